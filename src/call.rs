@@ -0,0 +1,632 @@
+//! Low-frequency variant calling from the pileup.
+//!
+//! Each candidate allele is tested against a binomial error model: for bases
+//! the error rate comes from the mean base quality at the site, for indels it
+//! is a fixed rate. At high depth the errors vary more between reads than a
+//! binomial allows, so the model can be a beta-binomial with the same mean
+//! and an overdispersion instead. Alleles significantly above the error rate,
+//! and above the minimum allele fraction, are reported down to heteroplasmy of
+//! about 1%.
+
+use crate::pileup::{Allele, Indel, Pileup};
+use bstr::BStr;
+use std::io::{self, Write};
+
+// The error rate assumed for indels, which have no base quality.
+const INDEL_ERROR_RATE: f64 = 1e-4;
+
+// The error rate of a base is bounded, so that a missing or very high quality
+// doesn't make every allele significant or none.
+const MIN_ERROR_RATE: f64 = 1e-6;
+const MAX_ERROR_RATE: f64 = 0.25;
+
+// The maximum reported QUAL.
+const MAX_QUAL: f64 = 9999.0;
+
+/// Thresholds for calling and filtering variants.
+#[derive(Clone, Copy, Debug)]
+pub struct CallOptions {
+    /// The minimum depth of a site.
+    pub min_depth: u32,
+    /// The minimum allele fraction of a variant.
+    pub min_allele_fraction: f64,
+    /// The maximum p-value of the allele count under the error model.
+    pub max_p_value: f64,
+    /// The overdispersion of the beta-binomial error model, the correlation
+    /// of the errors of reads at a site, or 0 for the binomial model.
+    pub overdispersion: f64,
+    /// Variants whose bases have a lower mean quality are filtered.
+    pub min_mean_base_quality: f64,
+    /// Variants whose strand bias has a lower Fisher's exact test p-value are
+    /// filtered.
+    pub strand_bias_p_value: f64,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            min_allele_fraction: 0.01,
+            max_p_value: 1e-6,
+            overdispersion: 0.0,
+            min_mean_base_quality: 20.0,
+            strand_bias_p_value: 1e-3,
+        }
+    }
+}
+
+/// A variant called from the pileup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    /// The 1-based position of the variant.
+    pub position: usize,
+    pub reference_allele: Vec<u8>,
+    pub alternate_allele: Vec<u8>,
+    /// The depth at the position.
+    pub depth: u32,
+    /// The forward and reverse strand counts of the reference allele.
+    pub reference_counts: (u32, u32),
+    /// The forward and reverse strand counts of the alternate allele.
+    pub alternate_counts: (u32, u32),
+    /// The phred scaled p-value of the allele count under the error model.
+    pub quality: f64,
+    /// The failed filters, empty if the variant passes.
+    pub filters: Vec<&'static str>,
+}
+
+impl Variant {
+    /// Returns the fraction of the depth supporting the alternate allele.
+    pub fn allele_fraction(&self) -> f64 {
+        let (forward, reverse) = self.alternate_counts;
+
+        if self.depth == 0 {
+            0.0
+        } else {
+            f64::from(forward + reverse) / f64::from(self.depth)
+        }
+    }
+
+    /// Returns true if the variant is an insertion or deletion.
+    pub fn is_indel(&self) -> bool {
+        self.reference_allele.len() != self.alternate_allele.len()
+    }
+}
+
+/// Calls the SNVs and short indels in a pileup against `reference_sequence`,
+/// the linear reference.
+pub fn call_variants(
+    pileup: &Pileup,
+    reference_sequence: &[u8],
+    options: &CallOptions,
+) -> Vec<Variant> {
+    let reflen = pileup.len().min(reference_sequence.len());
+    let mut variants = Vec::new();
+
+    for (i, &reference_base) in reference_sequence[..reflen].iter().enumerate() {
+        let Some(reference_allele) = Allele::from_base(reference_base) else {
+            continue;
+        };
+
+        let depth = site_depth(pileup, i);
+
+        if depth < options.min_depth {
+            continue;
+        }
+
+        let reference_counts = pileup.get(i, reference_allele);
+        let error_rate = (10f64.powf(-pileup.mean_site_quality(i) / 10.0) / 3.0)
+            .clamp(MIN_ERROR_RATE, MAX_ERROR_RATE);
+
+        for allele in [Allele::A, Allele::C, Allele::G, Allele::T] {
+            if allele == reference_allele {
+                continue;
+            }
+
+            let alternate_counts = pileup.get(i, allele);
+
+            let Some(quality) = test_allele(alternate_counts, depth, error_rate, options) else {
+                continue;
+            };
+
+            let mut filters = Vec::new();
+
+            if pileup.mean_quality(i, allele) < options.min_mean_base_quality {
+                filters.push("low_base_quality");
+            }
+
+            if is_strand_biased(reference_counts, alternate_counts, options) {
+                filters.push("strand_bias");
+            }
+
+            variants.push(Variant {
+                position: i + 1,
+                reference_allele: vec![reference_base.to_ascii_uppercase()],
+                alternate_allele: allele.as_str().as_bytes().to_vec(),
+                depth,
+                reference_counts,
+                alternate_counts,
+                quality,
+                filters,
+            });
+        }
+    }
+
+    for (anchor, indel, alternate_counts) in pileup.indels() {
+        if anchor >= reflen {
+            continue;
+        }
+
+        let depth = site_depth(pileup, anchor);
+
+        if depth < options.min_depth {
+            continue;
+        }
+
+        let Some(quality) = test_allele(alternate_counts, depth, INDEL_ERROR_RATE, options) else {
+            continue;
+        };
+
+        let Some((position, reference_allele, alternate_allele)) =
+            indel_alleles(reference_sequence, reflen, anchor, indel)
+        else {
+            log::warn!(
+                "Indel after position {} crosses the origin and can't be written to a VCF.",
+                anchor + 1
+            );
+            continue;
+        };
+
+        let reference_counts = Allele::from_base(reference_sequence[anchor])
+            .map(|allele| pileup.get(anchor, allele))
+            .unwrap_or_default();
+
+        let mut filters = Vec::new();
+
+        if is_strand_biased(reference_counts, alternate_counts, options) {
+            filters.push("strand_bias");
+        }
+
+        variants.push(Variant {
+            position,
+            reference_allele,
+            alternate_allele,
+            depth,
+            reference_counts,
+            alternate_counts,
+            quality,
+            filters,
+        });
+    }
+
+    variants.sort_by(|a, b| {
+        (a.position, &a.reference_allele, &a.alternate_allele).cmp(&(
+            b.position,
+            &b.reference_allele,
+            &b.alternate_allele,
+        ))
+    });
+
+    variants
+}
+
+/// Writes the variants as a VCF 4.3, with a `##contig` line for the circular
+/// reference `name` of length `reflen`.
+pub fn write_vcf<W>(
+    writer: &mut W,
+    variants: &[Variant],
+    name: &BStr,
+    reflen: usize,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(writer, "##fileformat=VCFv4.3")?;
+    writeln!(writer, "##source=mt_lintocirc")?;
+    writeln!(
+        writer,
+        "##contig=<ID={name},length={reflen},topology=\"circular\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth at the position\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele fraction\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=SB,Number=4,Type=Integer,Description=\"Reference forward, reference reverse, alternate forward and alternate reverse counts\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=INDEL,Number=0,Type=Flag,Description=\"Insertion or deletion\">"
+    )?;
+    writeln!(
+        writer,
+        "##FILTER=<ID=strand_bias,Description=\"The alternate allele is biased towards one strand\">"
+    )?;
+    writeln!(
+        writer,
+        "##FILTER=<ID=low_base_quality,Description=\"The alternate bases have a low mean quality\">"
+    )?;
+    writeln!(writer, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+
+    for variant in variants {
+        let filter = if variant.filters.is_empty() {
+            String::from("PASS")
+        } else {
+            variant.filters.join(";")
+        };
+
+        let (ref_forward, ref_reverse) = variant.reference_counts;
+        let (alt_forward, alt_reverse) = variant.alternate_counts;
+
+        write!(
+            writer,
+            "{name}\t{}\t.\t{}\t{}\t{:.1}\t{filter}\tDP={};AF={:.4};SB={ref_forward},{ref_reverse},{alt_forward},{alt_reverse}",
+            variant.position,
+            String::from_utf8_lossy(&variant.reference_allele),
+            String::from_utf8_lossy(&variant.alternate_allele),
+            variant.quality,
+            variant.depth,
+            variant.allele_fraction(),
+        )?;
+
+        if variant.is_indel() {
+            write!(writer, ";INDEL")?;
+        }
+
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+// The depth of a site counts its bases and deletions.
+pub(crate) fn site_depth(pileup: &Pileup, i: usize) -> u32 {
+    [Allele::A, Allele::C, Allele::G, Allele::T, Allele::Deletion]
+        .iter()
+        .map(|allele| {
+            let (forward, reverse) = pileup.get(i, *allele);
+            forward + reverse
+        })
+        .sum()
+}
+
+// Returns the QUAL of an allele if it is called: above the minimum allele
+// fraction and significant under the error model.
+fn test_allele(
+    (forward, reverse): (u32, u32),
+    depth: u32,
+    error_rate: f64,
+    options: &CallOptions,
+) -> Option<f64> {
+    let count = forward + reverse;
+
+    if count == 0 || f64::from(count) < options.min_allele_fraction * f64::from(depth) {
+        return None;
+    }
+
+    let (k, n) = (count.into(), depth.max(count).into());
+
+    let ln_p = if options.overdispersion > 0.0 {
+        ln_beta_binomial_upper_tail(k, n, error_rate, options.overdispersion)
+    } else {
+        ln_binomial_upper_tail(k, n, error_rate)
+    };
+
+    if ln_p > options.max_p_value.ln() {
+        return None;
+    }
+
+    Some((-10.0 * ln_p / std::f64::consts::LN_10).min(MAX_QUAL))
+}
+
+// Returns the VCF position, and reference and alternate alleles, of an indel
+// after the 0-based position `anchor`. The anchor base is the one before, as
+// usual, except at the start of the reference where it is the one after. An
+// indel can't cross the origin in a VCF, so those return None.
+pub(crate) fn indel_alleles(
+    reference_sequence: &[u8],
+    reflen: usize,
+    anchor: usize,
+    indel: &Indel,
+) -> Option<(usize, Vec<u8>, Vec<u8>)> {
+    let upper = |bases: &[u8]| bases.to_ascii_uppercase();
+
+    match indel {
+        Indel::Insertion(bases) => {
+            let anchor_base = reference_sequence[anchor].to_ascii_uppercase();
+            let mut alternate_allele = vec![anchor_base];
+            alternate_allele.extend_from_slice(bases);
+            Some((anchor + 1, vec![anchor_base], alternate_allele))
+        }
+        Indel::Deletion(len) if anchor + 1 == reflen => {
+            // The deletion starts at position 1, so anchor it on the base after.
+            let reference_allele = upper(reference_sequence.get(..=*len)?);
+            let alternate_allele = vec![reference_allele[*len]];
+            Some((1, reference_allele, alternate_allele))
+        }
+        Indel::Deletion(len) => {
+            if anchor + len >= reflen {
+                return None;
+            }
+
+            let reference_allele = upper(&reference_sequence[anchor..=anchor + len]);
+            let alternate_allele = vec![reference_allele[0]];
+            Some((anchor + 1, reference_allele, alternate_allele))
+        }
+    }
+}
+
+// Returns the natural log of P(X >= k) for X ~ Binomial(n, p).
+fn ln_binomial_upper_tail(k: u64, n: u64, p: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+
+    let (ln_p, ln_q) = (p.ln(), (1.0 - p).ln());
+
+    // ln C(n, k)
+    let ln_choose: f64 = (0..k)
+        .map(|i| ((n - i) as f64).ln() - ((i + 1) as f64).ln())
+        .sum();
+
+    let mut ln_term = ln_choose + k as f64 * ln_p + (n - k) as f64 * ln_q;
+    let mut ln_sum = ln_term;
+
+    for i in k..n {
+        ln_term += ((n - i) as f64).ln() - ((i + 1) as f64).ln() + ln_p - ln_q;
+
+        // The terms only shrink once past the mean, so stop when negligible.
+        if ln_term < ln_sum - 40.0 && i as f64 > n as f64 * p {
+            break;
+        }
+
+        ln_sum = ln_add_exp(ln_sum, ln_term);
+    }
+
+    ln_sum.min(0.0)
+}
+
+// Returns the natural log of P(X >= k) for X ~ BetaBinomial(n, a, b), with
+// mean p and overdispersion rho: a = p (1 - rho) / rho, b = (1 - p) (1 - rho)
+// / rho.
+fn ln_beta_binomial_upper_tail(k: u64, n: u64, p: f64, rho: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+
+    let rho = rho.min(1.0 - 1e-9);
+    let a = p * (1.0 - rho) / rho;
+    let b = (1.0 - p) * (1.0 - rho) / rho;
+    let (k, n) = (k as f64, n as f64);
+
+    let ln_beta = |x: f64, y: f64| ln_gamma(x) + ln_gamma(y) - ln_gamma(x + y);
+    let ln_choose = ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0);
+
+    let mut ln_term = ln_choose + ln_beta(k + a, n - k + b) - ln_beta(a, b);
+    let mut ln_sum = ln_term;
+
+    let mut i = k;
+    while i < n {
+        ln_term += ((n - i) / (i + 1.0)).ln() + ((i + a) / (n - i - 1.0 + b)).ln();
+
+        // The terms only shrink once past the mean, so stop when negligible.
+        if ln_term < ln_sum - 40.0 && i > n * p {
+            break;
+        }
+
+        ln_sum = ln_add_exp(ln_sum, ln_term);
+        i += 1.0;
+    }
+
+    ln_sum.min(0.0)
+}
+
+// The natural log of the gamma function for x > 0, by the Lanczos
+// approximation (g = 7, n = 9).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // The reflection formula.
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+fn ln_add_exp(a: f64, b: f64) -> f64 {
+    let (max, min) = if a > b { (a, b) } else { (b, a) };
+    max + (min - max).exp().ln_1p()
+}
+
+// Tests whether the alternate allele is on one strand more than the reference
+// allele, with a two-sided Fisher's exact test.
+fn is_strand_biased(
+    (ref_forward, ref_reverse): (u32, u32),
+    (alt_forward, alt_reverse): (u32, u32),
+    options: &CallOptions,
+) -> bool {
+    fisher_exact_test(
+        ref_forward.into(),
+        ref_reverse.into(),
+        alt_forward.into(),
+        alt_reverse.into(),
+    ) < options.strand_bias_p_value
+}
+
+// Returns the two-sided p-value of Fisher's exact test of the table
+// [[a, b], [c, d]].
+fn fisher_exact_test(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    let n = (a + b + c + d) as usize;
+
+    let mut ln_factorials = vec![0.0; n + 1];
+    for i in 1..=n {
+        ln_factorials[i] = ln_factorials[i - 1] + (i as f64).ln();
+    }
+
+    let (row1, row2, col1) = (a + b, c + d, a + c);
+
+    // The probability of the table with `x` in the top left cell.
+    let ln_probability = |x: u64| {
+        let (b, c) = (row1 - x, col1 - x);
+        let d = row2 - c;
+        ln_factorials[row1 as usize]
+            + ln_factorials[row2 as usize]
+            + ln_factorials[col1 as usize]
+            + ln_factorials[(b + d) as usize]
+            - ln_factorials[n]
+            - ln_factorials[x as usize]
+            - ln_factorials[b as usize]
+            - ln_factorials[c as usize]
+            - ln_factorials[d as usize]
+    };
+
+    let observed = ln_probability(a);
+    let min_x = col1.saturating_sub(row2);
+    let max_x = row1.min(col1);
+
+    // Allow for rounding when comparing equally likely tables.
+    (min_x..=max_x)
+        .map(ln_probability)
+        .filter(|&p| p <= observed + 1e-7)
+        .map(f64::exp)
+        .sum::<f64>()
+        .min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DepthOptions;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::{
+                cigar::{op::Kind, Op},
+                Flags,
+            },
+            RecordBuf,
+        },
+    };
+
+    #[test]
+    fn test_call_variants() -> io::Result<()> {
+        let reference_sequence = b"ACGTACGTAC";
+        let mut pileup = Pileup::new(10);
+
+        // 2% C at position 3 on both strands, and 5% T at position 5 on the
+        // forward strand only.
+        for i in 0..400 {
+            let mut sequence = b"GTACG".to_vec();
+            let flags = if i % 2 == 0 {
+                Flags::empty()
+            } else {
+                Flags::REVERSE_COMPLEMENTED
+            };
+
+            if i < 8 {
+                sequence[0] = b'C';
+            }
+
+            if i % 20 == 0 {
+                sequence[2] = b'T';
+            }
+
+            let record = RecordBuf::builder()
+                .set_flags(flags)
+                .set_alignment_start(Position::new(3).unwrap())
+                .set_cigar([Op::new(Kind::Match, 5)].into_iter().collect())
+                .set_sequence(sequence.into())
+                .set_quality_scores(vec![40; 5].into())
+                .build();
+            pileup.add(&record, &DepthOptions::default())?;
+        }
+
+        let variants = call_variants(&pileup, reference_sequence, &CallOptions::default());
+        assert_eq!(variants.len(), 2);
+
+        assert_eq!(variants[0].position, 3);
+        assert_eq!(variants[0].reference_allele, b"G");
+        assert_eq!(variants[0].alternate_allele, b"C");
+        assert!(variants[0].filters.is_empty());
+
+        assert_eq!(variants[1].position, 5);
+        assert_eq!(variants[1].reference_allele, b"A");
+        assert_eq!(variants[1].alternate_allele, b"T");
+        assert_eq!(variants[1].filters, ["strand_bias"]);
+
+        assert!((variants[0].allele_fraction() - 0.02).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_statistics() {
+        // P(X >= 1) for Binomial(2, 0.5) is 0.75.
+        assert!((ln_binomial_upper_tail(1, 2, 0.5).exp() - 0.75).abs() < 1e-9);
+        assert!((ln_binomial_upper_tail(10, 10, 0.5).exp() - 1.0 / 1024.0).abs() < 1e-12);
+
+        // Gamma(5) = 24 and Gamma(0.5) = sqrt(pi).
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-10);
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-10);
+
+        // BetaBinomial(2, 1, 1) is uniform on 0, 1 and 2, so P(X >= 1) is 2/3.
+        assert!((ln_beta_binomial_upper_tail(1, 2, 0.5, 1.0 / 3.0).exp() - 2.0 / 3.0).abs() < 1e-9);
+
+        // With little overdispersion it is close to the binomial, and with
+        // more the tail is heavier.
+        let binomial = ln_binomial_upper_tail(20, 1000, 0.01);
+        assert!((ln_beta_binomial_upper_tail(20, 1000, 0.01, 1e-9) - binomial).abs() < 1e-3);
+        assert!(ln_beta_binomial_upper_tail(20, 1000, 0.01, 0.01) > binomial + 1.0);
+
+        // The tea tasting example.
+        assert!((fisher_exact_test(3, 1, 1, 3) - 0.4857).abs() < 1e-4);
+        assert!(fisher_exact_test(50, 50, 20, 0) < 1e-3);
+    }
+
+    #[test]
+    fn test_indel_alleles() {
+        let reference_sequence = b"ACGTACGTAC";
+
+        assert_eq!(
+            indel_alleles(reference_sequence, 10, 2, &Indel::Deletion(2)),
+            Some((3, b"GTA".to_vec(), b"G".to_vec()))
+        );
+        assert_eq!(
+            indel_alleles(reference_sequence, 10, 9, &Indel::Insertion(b"TT".to_vec())),
+            Some((10, b"C".to_vec(), b"CTT".to_vec()))
+        );
+        assert_eq!(
+            indel_alleles(reference_sequence, 10, 9, &Indel::Deletion(2)),
+            Some((1, b"ACG".to_vec(), b"G".to_vec()))
+        );
+        assert_eq!(
+            indel_alleles(reference_sequence, 10, 8, &Indel::Deletion(2)),
+            None
+        );
+    }
+}
@@ -0,0 +1,491 @@
+//! Per-cell allele counts for single-cell mtDNA lineage tracing.
+//!
+//! Reads are grouped by their cell barcode and their bases counted on the
+//! circular reference as in the pileup, for mgatk-style sparse matrices of
+//! every cell. PCR duplicates are removed by the barcode, UMI, strand and
+//! folded start of each read. The two pieces of a split read are one
+//! molecule: the `_right` piece is kept or dropped along with the piece before
+//! the origin, whichever comes first in the file.
+
+use crate::{
+    depth::{aligned_bases, is_counted, mean, DepthOptions},
+    fold_position,
+    pileup::Allele,
+    RIGHT_SUFFIX,
+};
+use bstr::{BStr, BString};
+use noodles::sam::alignment::{
+    record::{data::field::Tag, Flags},
+    record_buf::data::field::Value,
+    RecordBuf,
+};
+use noodles_util::alignment::io::Reader;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, BufRead, Write},
+};
+
+/// The UMI tag written by Cell Ranger.
+pub const UMI_TAG: Tag = Tag::new(b'U', b'B');
+
+/// Options for counting the alleles of each cell.
+#[derive(Clone, Debug)]
+pub struct CellOptions {
+    /// Filters on the reads and bases counted.
+    pub depth: DepthOptions,
+    /// The tag with the cell barcode. Reads without it aren't counted.
+    pub barcode_tag: Tag,
+    /// The tag with the UMI. Reads without it, as in scATAC, are deduplicated
+    /// by position alone.
+    pub umi_tag: Tag,
+    /// If set, only the reads of these barcodes are counted.
+    pub whitelist: Option<HashSet<BString>>,
+}
+
+impl Default for CellOptions {
+    fn default() -> Self {
+        Self {
+            depth: DepthOptions::default(),
+            barcode_tag: Tag::CELL_BARCODE_ID,
+            umi_tag: UMI_TAG,
+            whitelist: None,
+        }
+    }
+}
+
+/// The number of reads counted and left out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CellStats {
+    /// The reads counted, not including the right pieces of split reads.
+    pub reads: u64,
+    /// The reads left out as duplicates of a counted one.
+    pub duplicates: u64,
+    /// The reads without a cell barcode.
+    pub no_barcode: u64,
+    /// The reads with a barcode not in the whitelist.
+    pub not_whitelisted: u64,
+}
+
+// The counts of the bases of one strand at a position.
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+    bases: [u32; 4],
+    quality_sums: [u64; 4],
+}
+
+// A read's barcode, UMI, folded 0-based start and strand.
+type MoleculeKey = (BString, BString, usize, bool);
+
+// A read's name without the `_right` suffix and the flags telling apart its
+// alignments.
+type PieceKey = (BString, u16);
+
+/// The forward and reverse strand base counts of each cell, by position.
+#[derive(Clone, Debug)]
+pub struct CellCounts {
+    reflen: usize,
+    cells: BTreeMap<BString, BTreeMap<usize, [Counts; 2]>>,
+    seen: HashSet<MoleculeKey>,
+    // Whether the reads ending at the origin were counted, until their right
+    // pieces turn up.
+    decided: HashMap<PieceKey, bool>,
+    // The right pieces that came before their reads.
+    pending: HashMap<PieceKey, Vec<(BString, RecordBuf)>>,
+    stats: CellStats,
+}
+
+impl CellCounts {
+    /// Creates empty counts for a reference of length `reflen`.
+    pub fn new(reflen: usize) -> Self {
+        Self {
+            reflen,
+            cells: BTreeMap::new(),
+            seen: HashSet::new(),
+            decided: HashMap::new(),
+            pending: HashMap::new(),
+            stats: CellStats::default(),
+        }
+    }
+
+    /// Returns the number of cells with any bases counted.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Returns true if no cell has any bases counted.
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Returns the number of reads counted and left out.
+    pub fn stats(&self) -> CellStats {
+        self.stats
+    }
+
+    /// Returns the forward and reverse strand counts of a base in a cell at
+    /// the 0-based position `i`.
+    pub fn get(&self, barcode: &BStr, i: usize, allele: Allele) -> (u32, u32) {
+        self.cells
+            .get(barcode)
+            .and_then(|positions| positions.get(&i))
+            .map_or((0, 0), |[forward, reverse]| {
+                (forward.bases[allele.index()], reverse.bases[allele.index()])
+            })
+    }
+
+    /// Adds a read to the counts of its cell, unless it is a duplicate.
+    ///
+    /// Reads are filtered as for the depth, and their bases as for the
+    /// pileup. The right piece of a split read is held back until the piece
+    /// before the origin is seen; call [`Self::finish`] after the last read.
+    pub fn add(&mut self, record: &RecordBuf, options: &CellOptions) -> io::Result<()> {
+        if self.reflen == 0 || !is_counted(record, &options.depth)? {
+            return Ok(());
+        }
+
+        let Some(barcode) = get_string(record, options.barcode_tag) else {
+            self.stats.no_barcode += 1;
+            return Ok(());
+        };
+
+        if let Some(whitelist) = &options.whitelist {
+            if !whitelist.contains(&barcode) {
+                self.stats.not_whitelisted += 1;
+                return Ok(());
+            }
+        }
+
+        let name: &[u8] = record.name().map(|name| name.as_ref()).unwrap_or_default();
+        let is_right_piece = name.ends_with(RIGHT_SUFFIX);
+        let name = if is_right_piece {
+            &name[..name.len() - RIGHT_SUFFIX.len()]
+        } else {
+            name
+        };
+
+        let segment_flags = Flags::FIRST_SEGMENT | Flags::LAST_SEGMENT | Flags::SUPPLEMENTARY;
+        let key = (BString::from(name), (record.flags() & segment_flags).bits());
+
+        if is_right_piece {
+            match self.decided.remove(&key) {
+                Some(true) => self.count(barcode, record, options),
+                Some(false) => {}
+                None => self
+                    .pending
+                    .entry(key)
+                    .or_default()
+                    .push((barcode, record.clone())),
+            }
+
+            return Ok(());
+        }
+
+        let is_kept = self.is_new_molecule(&barcode, record, options);
+
+        if is_kept {
+            self.count(barcode, record, options);
+        }
+
+        if let Some(right_pieces) = self.pending.remove(&key) {
+            if is_kept {
+                for (barcode, right_piece) in right_pieces {
+                    self.count(barcode, &right_piece, options);
+                }
+            }
+        } else if record
+            .alignment_end()
+            .is_some_and(|end| end.get() >= self.reflen)
+        {
+            // Only a read ending at the origin can have a right piece.
+            self.decided.insert(key, is_kept);
+        }
+
+        Ok(())
+    }
+
+    /// Counts the right pieces whose reads never turned up, each as a
+    /// molecule of its own.
+    pub fn finish(&mut self, options: &CellOptions) {
+        let mut pending: Vec<_> = self
+            .pending
+            .drain()
+            .flat_map(|(_, pieces)| pieces)
+            .collect();
+        pending.sort_by(|(a, a_piece), (b, b_piece)| {
+            a.cmp(b).then_with(|| a_piece.name().cmp(&b_piece.name()))
+        });
+
+        for (barcode, right_piece) in pending {
+            if self.is_new_molecule(&barcode, &right_piece, options) {
+                self.count(barcode, &right_piece, options);
+            }
+        }
+
+        self.decided.clear();
+    }
+
+    // Returns whether the read is the first of its molecule, counting it as
+    // either a read or a duplicate.
+    fn is_new_molecule(
+        &mut self,
+        barcode: &BString,
+        record: &RecordBuf,
+        options: &CellOptions,
+    ) -> bool {
+        let umi = get_string(record, options.umi_tag).unwrap_or_default();
+        let start = record
+            .alignment_start()
+            .map_or(0, |start| fold_position(start.get() - 1, self.reflen));
+        let is_reverse_complemented = record.flags().is_reverse_complemented();
+
+        if self
+            .seen
+            .insert((barcode.clone(), umi, start, is_reverse_complemented))
+        {
+            self.stats.reads += 1;
+            true
+        } else {
+            self.stats.duplicates += 1;
+            false
+        }
+    }
+
+    // Adds the aligned bases of the read to its cell.
+    fn count(&mut self, barcode: BString, record: &RecordBuf, options: &CellOptions) {
+        // A `RecordBuf` has already been parsed, so this can't fail.
+        let Ok(bases) = aligned_bases(record, self.reflen, options.depth.min_base_quality) else {
+            return;
+        };
+
+        let strand = usize::from(record.flags().is_reverse_complemented());
+        let positions = self.cells.entry(barcode).or_default();
+
+        for base in bases {
+            let Some(allele) = base.base.and_then(Allele::from_base) else {
+                continue;
+            };
+
+            let counts = &mut positions.entry(base.position).or_default()[strand];
+            counts.bases[allele.index()] += 1;
+            counts.quality_sums[allele.index()] += u64::from(base.quality_score.unwrap_or(0));
+        }
+    }
+
+    /// Writes an mgatk-style sparse matrix of a base: the position, barcode,
+    /// and forward and reverse strand counts and mean qualities, for the
+    /// positions of each cell where the base was seen.
+    pub fn write_sparse_base<W>(&self, writer: &mut W, allele: Allele) -> io::Result<()>
+    where
+        W: Write,
+    {
+        assert!(Allele::BASES.contains(&allele), "{allele:?} is not a base");
+
+        let b = allele.index();
+
+        for (barcode, positions) in &self.cells {
+            for (i, [forward, reverse]) in positions {
+                if forward.bases[b] == 0 && reverse.bases[b] == 0 {
+                    continue;
+                }
+
+                writeln!(
+                    writer,
+                    "{},{},{},{:.1},{},{:.1}",
+                    i + 1,
+                    barcode,
+                    forward.bases[b],
+                    mean(forward.quality_sums[b], forward.bases[b] as usize),
+                    reverse.bases[b],
+                    mean(reverse.quality_sums[b], reverse.bases[b] as usize)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes an mgatk-style sparse coverage matrix: the position, barcode and
+    /// the number of bases counted there.
+    pub fn write_sparse_coverage<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for (barcode, positions) in &self.cells {
+            for (i, coverage) in positions.iter().map(|(i, counts)| (i, coverage(counts))) {
+                if coverage > 0 {
+                    writeln!(writer, "{},{},{}", i + 1, barcode, coverage)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the mean depth of each cell over the whole reference, as
+    /// mgatk's depth table.
+    pub fn write_depth_table<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for (barcode, positions) in &self.cells {
+            let bases: u64 = positions
+                .values()
+                .map(|counts| u64::from(coverage(counts)))
+                .sum();
+            writeln!(writer, "{barcode}\t{:.2}", mean(bases, self.reflen))?;
+        }
+
+        Ok(())
+    }
+}
+
+// The number of bases counted on both strands.
+fn coverage(counts: &[Counts; 2]) -> u32 {
+    counts.iter().flat_map(|counts| counts.bases.iter()).sum()
+}
+
+fn get_string(record: &RecordBuf, tag: Tag) -> Option<BString> {
+    match record.data().get(&tag) {
+        Some(Value::String(s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+/// Computes the counts of each cell from the records on `refname`, folding
+/// their positions onto a circular reference of length `reflen`.
+pub fn compute_cell_counts<R>(
+    reader: &mut Reader<R>,
+    refname: &BStr,
+    reflen: usize,
+    options: &CellOptions,
+) -> io::Result<CellCounts>
+where
+    R: BufRead,
+{
+    let header = reader.read_header()?;
+
+    let Some(reference_sequence_id) = header.reference_sequences().get_index_of(refname) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {refname} is not in the header"),
+        ));
+    };
+
+    let mut counts = CellCounts::new(reflen);
+
+    for result in reader.records(&header) {
+        let record = result?;
+
+        if record.reference_sequence_id(&header).transpose()? == Some(reference_sequence_id) {
+            let record = RecordBuf::try_from_alignment_record(&header, &record)?;
+            counts.add(&record, options)?;
+        }
+    }
+
+    counts.finish(options);
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::record::{
+            cigar::{op::Kind, Op},
+            MappingQuality,
+        },
+    };
+
+    fn build_read(
+        name: &str,
+        barcode: &str,
+        umi: &str,
+        start: usize,
+        sequence: &[u8],
+    ) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name(name)
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar([Op::new(Kind::Match, sequence.len())].into_iter().collect())
+            .set_sequence(sequence.to_vec().into())
+            .set_quality_scores(vec![30; sequence.len()].into())
+            .set_data(
+                [
+                    (Tag::CELL_BARCODE_ID, Value::from(barcode)),
+                    (UMI_TAG, Value::from(umi)),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_cell_counts() -> io::Result<()> {
+        let options = CellOptions {
+            whitelist: Some(["AAA-1", "CCC-1"].into_iter().map(BString::from).collect()),
+            ..Default::default()
+        };
+
+        let mut counts = CellCounts::new(10);
+
+        // A split read, its right piece first as in a sorted file, and a
+        // duplicate of it whose right piece comes after it.
+        counts.add(&build_read("r1_right", "AAA-1", "U1", 1, b"GT"), &options)?;
+        counts.add(&build_read("r1", "AAA-1", "U1", 8, b"ACG"), &options)?;
+        counts.add(&build_read("r2", "AAA-1", "U1", 8, b"ACG"), &options)?;
+        counts.add(&build_read("r2_right", "AAA-1", "U1", 1, b"GT"), &options)?;
+
+        // A different UMI at the same position, and a read of another cell.
+        counts.add(&build_read("r3", "AAA-1", "U2", 8, b"TCG"), &options)?;
+        counts.add(&build_read("r4", "CCC-1", "U1", 2, b"T"), &options)?;
+
+        // Left out: not in the whitelist, and without a barcode.
+        counts.add(&build_read("r5", "GGG-1", "U1", 2, b"T"), &options)?;
+        let mut read = build_read("r6", "AAA-1", "U1", 2, b"T");
+        read.data_mut().remove(&Tag::CELL_BARCODE_ID);
+        counts.add(&read, &options)?;
+
+        // A right piece without its read.
+        counts.add(&build_read("r7_right", "CCC-1", "U3", 1, b"G"), &options)?;
+        counts.finish(&options);
+
+        let stats = counts.stats();
+        assert_eq!(stats.reads, 4);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.not_whitelisted, 1);
+        assert_eq!(stats.no_barcode, 1);
+
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get(b"AAA-1".into(), 0, Allele::G), (1, 0));
+        assert_eq!(counts.get(b"AAA-1".into(), 7, Allele::A), (1, 0));
+        assert_eq!(counts.get(b"AAA-1".into(), 7, Allele::T), (1, 0));
+
+        let mut sparse = Vec::new();
+        counts.write_sparse_base(&mut sparse, Allele::G)?;
+        assert_eq!(
+            String::from_utf8(sparse).unwrap(),
+            "1,AAA-1,1,30.0,0,0.0\n10,AAA-1,2,30.0,0,0.0\n1,CCC-1,1,30.0,0,0.0\n"
+        );
+
+        let mut coverage = Vec::new();
+        counts.write_sparse_coverage(&mut coverage)?;
+        assert_eq!(
+            String::from_utf8(coverage).unwrap(),
+            "1,AAA-1,1\n2,AAA-1,1\n8,AAA-1,2\n9,AAA-1,2\n10,AAA-1,2\n1,CCC-1,1\n2,CCC-1,1\n"
+        );
+
+        let mut depth_table = Vec::new();
+        counts.write_depth_table(&mut depth_table)?;
+        assert_eq!(
+            String::from_utf8(depth_table).unwrap(),
+            "AAA-1\t0.80\nCCC-1\t0.20\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,774 @@
+//! Joint heteroplasmy calls of many samples.
+//!
+//! Each sample's folded pileup is reduced to its counts: the depth and bases
+//! of every position, the indels as VCF alleles, and its own variant calls.
+//! The counts are small, so they are saved per sample and a cohort can be
+//! picked up where it stopped. The sites called in any sample are then written
+//! with the allele fraction and depth of every sample.
+
+use crate::{
+    call::{indel_alleles, site_depth},
+    call_variants,
+    pileup::{Allele, Pileup},
+    CallOptions, DepthOptions,
+};
+use bstr::BStr;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+// The start of the first line of a sample's counts, followed by their source.
+const COUNTS_HEADER: &str = "##mt_lintocirc cohort counts";
+
+/// What a sample's counts are computed from: the alignment file as it was
+/// then, the reference and the options. Saved counts are only reused if all
+/// of it is the same.
+#[derive(Clone, Debug)]
+pub struct CountsSource {
+    /// The alignment file.
+    pub path: PathBuf,
+    /// The size of the alignment file in bytes.
+    pub size: u64,
+    /// The modification time of the alignment file, in seconds since the
+    /// Unix epoch.
+    pub modified: u64,
+    /// The reference FASTA.
+    pub reference: String,
+    /// The name of the reference in the alignment file.
+    pub refname: String,
+    /// The name of the linear target reference.
+    pub target_refname: String,
+    pub depth: DepthOptions,
+    pub call: CallOptions,
+}
+
+impl CountsSource {
+    /// Returns the source of counts of the alignment file at `path`, looking
+    /// up its size and modification time.
+    pub fn new(
+        path: &Path,
+        reference: &str,
+        refname: &str,
+        target_refname: &str,
+        depth: DepthOptions,
+        call: CallOptions,
+    ) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified,
+            reference: reference.to_string(),
+            refname: refname.to_string(),
+            target_refname: target_refname.to_string(),
+            depth,
+            call,
+        })
+    }
+}
+
+impl fmt::Display for CountsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input={}\tsize={}\tmodified={}\treference={}\tref={}\ttargetref={}\t\
+            min_mapq={}\tmin_baseq={}\tmin_depth={}\tmin_af={}\tmax_pvalue={}\t\
+            overdispersion={}\tmin_mean_baseq={}\tstrand_bias_pvalue={}",
+            self.path.display(),
+            self.size,
+            self.modified,
+            self.reference,
+            self.refname,
+            self.target_refname,
+            self.depth.min_mapping_quality,
+            self.depth.min_base_quality,
+            self.call.min_depth,
+            self.call.min_allele_fraction,
+            self.call.max_p_value,
+            self.call.overdispersion,
+            self.call.min_mean_base_quality,
+            self.call.strand_bias_p_value,
+        )
+    }
+}
+
+/// A sample of the cohort and its alignment file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CohortSample {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+/// Reads a manifest of tab-separated sample IDs and alignment files. Lines
+/// starting with `#` are comments.
+///
+/// Sample IDs have to be unique, and are used as file names, so they can't
+/// contain `/` or whitespace.
+pub fn read_manifest<R>(reader: R) -> io::Result<Vec<CohortSample>>
+where
+    R: BufRead,
+{
+    let mut samples = Vec::new();
+    let mut ids = HashSet::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("manifest line {}: {message}", i + 1),
+            )
+        };
+
+        let Some((id, path)) = line.split_once('\t') else {
+            return Err(invalid(String::from("expected a sample ID and a file")));
+        };
+
+        if id.is_empty() || id.contains(|c: char| c == '/' || c.is_whitespace()) {
+            return Err(invalid(format!("invalid sample ID: {id}")));
+        }
+
+        if !ids.insert(id.to_string()) {
+            return Err(invalid(format!("duplicate sample ID: {id}")));
+        }
+
+        samples.push(CohortSample {
+            id: id.to_string(),
+            path: PathBuf::from(path.trim_end()),
+        });
+    }
+
+    Ok(samples)
+}
+
+/// A 1-based position and its reference and alternate alleles, as in a VCF.
+pub type Site = (usize, Vec<u8>, Vec<u8>);
+
+// A variant called in a sample.
+#[derive(Clone, Debug, PartialEq)]
+struct Call {
+    site: Site,
+    quality: f64,
+    filters: Vec<String>,
+}
+
+/// The counts of one sample that the joint matrix and VCF are made from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleCounts {
+    // The depth, counting deletions, and the A, C, G and T counts of each
+    // covered 1-based position.
+    bases: BTreeMap<usize, (u32, [u32; 4])>,
+    // The depth and count of each indel.
+    indels: BTreeMap<Site, (u32, u32)>,
+    calls: Vec<Call>,
+}
+
+impl SampleCounts {
+    /// Reduces a pileup to its counts, calling its variants against the
+    /// linear `reference_sequence`.
+    pub fn new(pileup: &Pileup, reference_sequence: &[u8], options: &CallOptions) -> Self {
+        let reflen = pileup.len().min(reference_sequence.len());
+        let mut counts = Self::default();
+
+        for i in 0..reflen {
+            let depth = site_depth(pileup, i);
+
+            if depth > 0 {
+                let bases = Allele::BASES.map(|allele| {
+                    let (forward, reverse) = pileup.get(i, allele);
+                    forward + reverse
+                });
+                counts.bases.insert(i + 1, (depth, bases));
+            }
+        }
+
+        for (anchor, indel, (forward, reverse)) in pileup.indels() {
+            if anchor >= reflen {
+                continue;
+            }
+
+            if let Some(site) = indel_alleles(reference_sequence, reflen, anchor, indel) {
+                let (depth, count) = counts.indels.entry(site).or_default();
+                *depth = site_depth(pileup, anchor);
+                *count += forward + reverse;
+            }
+        }
+
+        counts.calls = call_variants(pileup, reference_sequence, options)
+            .into_iter()
+            .map(|variant| Call {
+                site: (
+                    variant.position,
+                    variant.reference_allele,
+                    variant.alternate_allele,
+                ),
+                quality: variant.quality,
+                filters: variant.filters.iter().map(|s| s.to_string()).collect(),
+            })
+            .collect();
+
+        counts
+    }
+
+    /// Reads the counts written by [`Self::write`], or returns `None` if
+    /// they weren't computed from `source`.
+    pub fn read<R>(reader: R, source: &CountsSource) -> io::Result<Option<Self>>
+    where
+        R: BufRead,
+    {
+        let mut counts = Self::default();
+        let mut lines = reader.lines();
+
+        let Some(header) = lines.next().transpose()? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a cohort counts file",
+            ));
+        };
+
+        if !header.starts_with(COUNTS_HEADER) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a cohort counts file",
+            ));
+        }
+
+        if header != format!("{COUNTS_HEADER}\t{source}") {
+            return Ok(None);
+        }
+
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: invalid counts", i + 2),
+                )
+            };
+
+            let number = |s: &str| s.parse::<u32>().map_err(|_| invalid());
+            let site = |position: &str, reference: &str, alternate: &str| -> io::Result<Site> {
+                Ok((
+                    position.parse().map_err(|_| invalid())?,
+                    reference.as_bytes().to_vec(),
+                    alternate.as_bytes().to_vec(),
+                ))
+            };
+
+            match fields[..] {
+                ["B", position, depth, a, c, g, t] => {
+                    let position = position.parse().map_err(|_| invalid())?;
+                    let bases = [number(a)?, number(c)?, number(g)?, number(t)?];
+                    counts.bases.insert(position, (number(depth)?, bases));
+                }
+                ["I", position, reference, alternate, depth, count] => {
+                    counts.indels.insert(
+                        site(position, reference, alternate)?,
+                        (number(depth)?, number(count)?),
+                    );
+                }
+                ["V", position, reference, alternate, quality, filters] => {
+                    counts.calls.push(Call {
+                        site: site(position, reference, alternate)?,
+                        quality: quality.parse().map_err(|_| invalid())?,
+                        filters: match filters {
+                            "PASS" => Vec::new(),
+                            filters => filters.split(';').map(String::from).collect(),
+                        },
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Some(counts))
+    }
+
+    /// Writes the counts, computed from `source`, as tab-separated lines of
+    /// base counts (`B`), indel counts (`I`) and variant calls (`V`).
+    pub fn write<W>(&self, writer: &mut W, source: &CountsSource) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "{COUNTS_HEADER}\t{source}")?;
+
+        for (position, (depth, [a, c, g, t])) in &self.bases {
+            writeln!(writer, "B\t{position}\t{depth}\t{a}\t{c}\t{g}\t{t}")?;
+        }
+
+        for ((position, reference, alternate), (depth, count)) in &self.indels {
+            writeln!(
+                writer,
+                "I\t{position}\t{}\t{}\t{depth}\t{count}",
+                BStr::new(reference),
+                BStr::new(alternate)
+            )?;
+        }
+
+        for call in &self.calls {
+            let (position, reference, alternate) = &call.site;
+            writeln!(
+                writer,
+                "V\t{position}\t{}\t{}\t{}\t{}",
+                BStr::new(reference),
+                BStr::new(alternate),
+                call.quality,
+                format_filters(&call.filters)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the depth at a site, and the counts of its reference and
+    /// alternate alleles. The reference count of an indel is that of its
+    /// first base.
+    pub fn get(&self, (position, reference, alternate): &Site) -> (u32, u32, u32) {
+        let (depth, bases) = self.bases.get(position).copied().unwrap_or_default();
+
+        let base_count = |allele: &[u8]| match allele {
+            [base] => Allele::from_base(*base).map_or(0, |allele| bases[allele.index()]),
+            _ => 0,
+        };
+
+        let reference_count = reference.first().map_or(0, |&base| base_count(&[base]));
+
+        if reference.len() == 1 && alternate.len() == 1 {
+            (depth, reference_count, base_count(alternate))
+        } else {
+            let key = (*position, reference.clone(), alternate.clone());
+            let (indel_depth, count) = self.indels.get(&key).copied().unwrap_or((depth, 0));
+            (indel_depth, reference_count, count)
+        }
+    }
+
+    fn call(&self, site: &Site) -> Option<&Call> {
+        self.calls.iter().find(|call| &call.site == site)
+    }
+}
+
+fn format_filters(filters: &[String]) -> String {
+    if filters.is_empty() {
+        String::from("PASS")
+    } else {
+        filters.join(";")
+    }
+}
+
+/// Returns the sites called in any of the samples, in order.
+pub fn cohort_sites(samples: &[(String, SampleCounts)]) -> Vec<Site> {
+    samples
+        .iter()
+        .flat_map(|(_, counts)| counts.calls.iter().map(|call| call.site.clone()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Writes the allele fraction and depth of every sample at each site as a
+/// TSV, with an `_AF` and a `_DP` column for each sample. The allele fraction
+/// is `NA` where a sample has no depth.
+pub fn write_cohort_matrix<W>(
+    writer: &mut W,
+    name: &BStr,
+    samples: &[(String, SampleCounts)],
+    sites: &[Site],
+) -> io::Result<()>
+where
+    W: Write,
+{
+    write!(writer, "chrom\tpos\tref\talt")?;
+
+    for (id, _) in samples {
+        write!(writer, "\t{id}_AF\t{id}_DP")?;
+    }
+
+    writeln!(writer)?;
+
+    for site in sites {
+        let (position, reference, alternate) = site;
+        write!(
+            writer,
+            "{name}\t{position}\t{}\t{}",
+            BStr::new(reference),
+            BStr::new(alternate)
+        )?;
+
+        for (_, counts) in samples {
+            let (depth, _, count) = counts.get(site);
+            write!(writer, "\t{}\t{depth}", format_fraction(count, depth))?;
+        }
+
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+/// The matrix and VCF of a cohort, `PREFIX.tsv` and `PREFIX.vcf`.
+///
+/// Neither is ever overwritten, and both are checked before any sample is
+/// counted, so a rerun that would fail to write them fails straight away.
+/// Each is written under another name first, so an interrupted run leaves
+/// neither behind.
+#[derive(Clone, Debug)]
+pub struct CohortOutputs {
+    /// The path of the matrix.
+    pub matrix: PathBuf,
+    /// The path of the VCF.
+    pub vcf: PathBuf,
+}
+
+impl CohortOutputs {
+    /// Returns the outputs with `prefix`, or an `AlreadyExists` error if
+    /// either of them exists.
+    pub fn new(prefix: &str) -> io::Result<Self> {
+        let outputs = Self {
+            matrix: PathBuf::from(format!("{prefix}.tsv")),
+            vcf: PathBuf::from(format!("{prefix}.vcf")),
+        };
+
+        for path in [&outputs.matrix, &outputs.vcf] {
+            if path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    /// Writes the matrix and the VCF of the `samples` at the `sites`, on the
+    /// circular reference `name` of length `reflen`.
+    pub fn write(
+        &self,
+        name: &BStr,
+        reflen: usize,
+        samples: &[(String, SampleCounts)],
+        sites: &[Site],
+    ) -> io::Result<()> {
+        let matrix = write_partial(&self.matrix, |writer| {
+            write_cohort_matrix(writer, name, samples, sites)
+        })?;
+        let vcf = write_partial(&self.vcf, |writer| {
+            write_cohort_vcf(writer, name, reflen, samples, sites)
+        })?;
+
+        for (partial_path, path) in [(matrix, &self.matrix), (vcf, &self.vcf)] {
+            if path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+
+            fs::rename(partial_path, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Writes `path` with a `.partial` suffix, returning the path written.
+fn write_partial<F>(path: &Path, write: F) -> io::Result<PathBuf>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
+
+    let mut writer = BufWriter::new(File::create(&partial_path)?);
+    write(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    Ok(partial_path)
+}
+
+fn format_fraction(count: u32, depth: u32) -> String {
+    if depth == 0 {
+        String::from("NA")
+    } else {
+        format!("{:.4}", f64::from(count) / f64::from(depth))
+    }
+}
+
+/// Writes a multi-sample VCF 4.3 of the sites, with a `##contig` line for the
+/// circular reference `name` of length `reflen`.
+///
+/// QUAL is the best of the samples' calls, and a site passes if any sample's
+/// call passes. Each sample has its depth, allele counts and fraction, and
+/// the filters of its own call, or `.` if the site wasn't called in it.
+pub fn write_cohort_vcf<W>(
+    writer: &mut W,
+    name: &BStr,
+    reflen: usize,
+    samples: &[(String, SampleCounts)],
+    sites: &[Site],
+) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(writer, "##fileformat=VCFv4.3")?;
+    writeln!(writer, "##source=mt_lintocirc")?;
+    writeln!(
+        writer,
+        "##contig=<ID={name},length={reflen},topology=\"circular\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=NS,Number=1,Type=Integer,Description=\"Number of samples the variant was called in\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=INDEL,Number=0,Type=Flag,Description=\"Insertion or deletion\">"
+    )?;
+    writeln!(
+        writer,
+        "##FILTER=<ID=strand_bias,Description=\"The alternate allele is biased towards one strand\">"
+    )?;
+    writeln!(
+        writer,
+        "##FILTER=<ID=low_base_quality,Description=\"The alternate bases have a low mean quality\">"
+    )?;
+    writeln!(
+        writer,
+        "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Depth at the position\">"
+    )?;
+    writeln!(
+        writer,
+        "##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Reference and alternate allele counts\">"
+    )?;
+    writeln!(
+        writer,
+        "##FORMAT=<ID=AF,Number=A,Type=Float,Description=\"Allele fraction\">"
+    )?;
+    writeln!(
+        writer,
+        "##FORMAT=<ID=FT,Number=1,Type=String,Description=\"Filters of the sample's call, . if it wasn't called in the sample\">"
+    )?;
+    write!(
+        writer,
+        "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT"
+    )?;
+
+    for (id, _) in samples {
+        write!(writer, "\t{id}")?;
+    }
+
+    writeln!(writer)?;
+
+    for site in sites {
+        let (position, reference, alternate) = site;
+        let calls: Vec<&Call> = samples
+            .iter()
+            .filter_map(|(_, counts)| counts.call(site))
+            .collect();
+
+        let quality = calls.iter().map(|call| call.quality).fold(0.0, f64::max);
+
+        let filter = if calls.iter().any(|call| call.filters.is_empty()) {
+            String::from("PASS")
+        } else {
+            let filters: BTreeSet<&String> =
+                calls.iter().flat_map(|call| call.filters.iter()).collect();
+            filters.into_iter().cloned().collect::<Vec<_>>().join(";")
+        };
+
+        write!(
+            writer,
+            "{name}\t{position}\t.\t{}\t{}\t{quality:.1}\t{filter}\tNS={}",
+            BStr::new(reference),
+            BStr::new(alternate),
+            calls.len()
+        )?;
+
+        if reference.len() != alternate.len() {
+            write!(writer, ";INDEL")?;
+        }
+
+        write!(writer, "\tDP:AD:AF:FT")?;
+
+        for (_, counts) in samples {
+            let (depth, reference_count, count) = counts.get(site);
+            let fraction = if depth == 0 {
+                String::from(".")
+            } else {
+                format_fraction(count, depth)
+            };
+            let sample_filter = counts
+                .call(site)
+                .map_or_else(|| String::from("."), |call| format_filters(&call.filters));
+
+            write!(
+                writer,
+                "\t{depth}:{reference_count},{count}:{fraction}:{sample_filter}"
+            )?;
+        }
+
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::depth::DepthOptions;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::{
+                cigar::{op::Kind, Op},
+                Flags,
+            },
+            RecordBuf,
+        },
+    };
+
+    fn build_pileup(reads: &[(usize, &[u8], Flags)]) -> io::Result<Pileup> {
+        let mut pileup = Pileup::new(8);
+
+        for &(start, sequence, flags) in reads {
+            let record = RecordBuf::builder()
+                .set_flags(flags)
+                .set_alignment_start(Position::new(start).unwrap())
+                .set_cigar([Op::new(Kind::Match, sequence.len())].into_iter().collect())
+                .set_sequence(sequence.to_vec().into())
+                .set_quality_scores(vec![30; sequence.len()].into())
+                .build();
+
+            for _ in 0..10 {
+                pileup.add(&record, &DepthOptions::default())?;
+            }
+        }
+
+        Ok(pileup)
+    }
+
+    #[test]
+    fn test_cohort() -> io::Result<()> {
+        let manifest = read_manifest(&b"# id\tfile\ns1\ta.bam\ns2\tdir/b.bam\n"[..])?;
+        assert_eq!(manifest[1].id, "s2");
+        assert_eq!(manifest[1].path, PathBuf::from("dir/b.bam"));
+        assert!(read_manifest(&b"s1\ta.bam\ns1\tb.bam\n"[..]).is_err());
+
+        let reference_sequence = b"ACGTACGT";
+        let options = CallOptions {
+            min_depth: 5,
+            min_allele_fraction: 0.1,
+            ..Default::default()
+        };
+
+        // s1 is heteroplasmic for 3T on both strands, s2 has a few 3T reads
+        // that aren't called.
+        let s1 = build_pileup(&[
+            (7, b"GTACGT", Flags::empty()),
+            (7, b"GTACGT", Flags::REVERSE_COMPLEMENTED),
+            (7, b"GTACTT", Flags::empty()),
+            (7, b"GTACTT", Flags::REVERSE_COMPLEMENTED),
+        ])?;
+        let s2 = build_pileup(&[(1, b"ACGT", Flags::empty()), (1, b"ACTT", Flags::empty())])?;
+
+        let s1 = SampleCounts::new(&s1, reference_sequence, &options);
+        let s2 = SampleCounts::new(
+            &s2,
+            reference_sequence,
+            &CallOptions {
+                min_depth: 100,
+                ..options
+            },
+        );
+
+        // Restarting reads back the same counts, unless the input or the
+        // options changed.
+        let mut source = CountsSource {
+            path: PathBuf::from("a.bam"),
+            size: 1000,
+            modified: 1_700_000_000,
+            reference: String::from("rCRS.fa"),
+            refname: String::from("chrM"),
+            target_refname: String::from("chrM"),
+            depth: DepthOptions::default(),
+            call: options,
+        };
+
+        let mut saved = Vec::new();
+        s1.write(&mut saved, &source)?;
+        assert_eq!(SampleCounts::read(&saved[..], &source)?, Some(s1.clone()));
+
+        source.modified += 1;
+        assert_eq!(SampleCounts::read(&saved[..], &source)?, None);
+
+        source.modified -= 1;
+        source.depth.min_base_quality = 20;
+        assert_eq!(SampleCounts::read(&saved[..], &source)?, None);
+
+        let samples = vec![(String::from("s1"), s1), (String::from("s2"), s2)];
+        let sites = cohort_sites(&samples);
+        assert_eq!(sites, [(3, b"G".to_vec(), b"T".to_vec())]);
+
+        let mut matrix = Vec::new();
+        write_cohort_matrix(&mut matrix, b"chrM".into(), &samples, &sites)?;
+        assert_eq!(
+            String::from_utf8(matrix).unwrap(),
+            "chrom\tpos\tref\talt\ts1_AF\ts1_DP\ts2_AF\ts2_DP\n\
+            chrM\t3\tG\tT\t0.5000\t40\t0.5000\t20\n"
+        );
+
+        let mut vcf = Vec::new();
+        write_cohort_vcf(&mut vcf, b"chrM".into(), 8, &samples, &sites)?;
+        let vcf = String::from_utf8(vcf).unwrap();
+        let record = vcf.lines().last().unwrap();
+        assert!(record.starts_with("chrM\t3\t.\tG\tT\t"));
+        assert!(
+            record.ends_with("\tPASS\tNS=1\tDP:AD:AF:FT\t40:20,20:0.5000:PASS\t20:10,10:0.5000:.")
+        );
+
+        // A run interrupted while writing the VCF leaves neither output, so
+        // the rerun writes both. Then the next rerun, say with more samples,
+        // fails before counting anything.
+        let prefix = std::env::temp_dir()
+            .join(format!("mt_lintocirc_{}_cohort", std::process::id()))
+            .display()
+            .to_string();
+        let outputs = CohortOutputs::new(&prefix)?;
+        fs::write(format!("{prefix}.vcf.partial"), "##fileformat=VCFv4.3\n")?;
+        assert!(!outputs.matrix.exists() && !outputs.vcf.exists());
+
+        outputs.write(b"chrM".into(), 8, &samples, &sites)?;
+        assert_eq!(fs::read_to_string(&outputs.vcf)?, vcf);
+        assert!(!PathBuf::from(format!("{prefix}.vcf.partial")).exists());
+
+        let error = CohortOutputs::new(&prefix).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        fs::remove_file(&outputs.matrix)?;
+        fs::remove_file(&outputs.vcf)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-sample consensus sequence from the folded allele counts.
+//!
+//! Every position of the circular reference is called from the pileup, so the
+//! bases either side of the origin come from the reads crossing it and the
+//! sequence runs on from `reflen` to 1 without a seam.
+
+use crate::pileup::{Allele, Indel, Pileup};
+use std::collections::BTreeMap;
+
+/// Thresholds for calling the consensus.
+#[derive(Clone, Copy, Debug)]
+pub struct ConsensusOptions {
+    /// Positions with fewer reads, counting deletions, are `N`.
+    pub min_depth: u32,
+    /// Bases with at least this fraction of the reads are part of the call;
+    /// more than one gives an IUPAC code.
+    pub min_allele_fraction: f64,
+}
+
+impl Default for ConsensusOptions {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            min_allele_fraction: 0.2,
+        }
+    }
+}
+
+/// A consensus sequence, with counts of how it differs from a plain call.
+#[derive(Clone, Debug, Default)]
+pub struct Consensus {
+    pub sequence: Vec<u8>,
+    /// The number of positions below the minimum depth.
+    pub low_coverage: usize,
+    /// The number of positions with an IUPAC code.
+    pub ambiguous: usize,
+    /// The number of inserted bases.
+    pub inserted: usize,
+    /// The number of deleted positions.
+    pub deleted: usize,
+}
+
+const BASES: [Allele; 4] = [Allele::A, Allele::C, Allele::G, Allele::T];
+
+// The IUPAC code of each set of bases, indexed by a bit mask of A, C, G and T.
+const IUPAC: [u8; 16] = *b"NACMGRSVTWYHKDBN";
+
+/// Calls the consensus sequence from a pileup.
+///
+/// At each position the majority of the reads decides between a base and a
+/// deletion, and a majority inserting the same bases after it adds them.
+/// A base is the IUPAC code of every base with at least the minimum allele
+/// fraction.
+pub fn build_consensus(pileup: &Pileup, options: &ConsensusOptions) -> Consensus {
+    let mut consensus = Consensus::default();
+
+    // The most common insertion after each position.
+    let mut insertions: BTreeMap<usize, (u32, &[u8])> = BTreeMap::new();
+
+    for (i, indel, (forward, reverse)) in pileup.indels() {
+        if let Indel::Insertion(bases) = indel {
+            let count = forward + reverse;
+            let best = insertions.entry(i).or_insert((0, &[]));
+
+            if count > best.0 {
+                *best = (count, bases);
+            }
+        }
+    }
+
+    let total = |i: usize, allele: Allele| {
+        let (forward, reverse) = pileup.get(i, allele);
+        forward + reverse
+    };
+
+    for i in 0..pileup.len() {
+        let counts = BASES.map(|allele| total(i, allele));
+        let deletions = total(i, Allele::Deletion);
+        let depth = counts.iter().sum::<u32>() + deletions;
+
+        if depth < options.min_depth || depth == 0 {
+            consensus.sequence.push(b'N');
+            consensus.low_coverage += 1;
+            continue;
+        }
+
+        let is_majority = |count: u32| 2 * count > depth;
+
+        if is_majority(deletions) {
+            consensus.deleted += 1;
+        } else {
+            let fraction = |count: u32| f64::from(count) / f64::from(depth);
+
+            let mut mask = counts
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0 && fraction(count) >= options.min_allele_fraction)
+                .fold(0usize, |mask, (b, _)| mask | (1 << b));
+
+            // Without any base over the threshold, the most common one.
+            if mask == 0 {
+                let (b, _) = counts
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, &count)| count)
+                    .unwrap();
+                mask = 1 << b;
+            }
+
+            if mask.count_ones() > 1 {
+                consensus.ambiguous += 1;
+            }
+
+            consensus.sequence.push(IUPAC[mask]);
+        }
+
+        if let Some(&(count, bases)) = insertions.get(&i) {
+            if is_majority(count) {
+                consensus.sequence.extend_from_slice(bases);
+                consensus.inserted += bases.len();
+            }
+        }
+    }
+
+    consensus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::depth::DepthOptions;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::{
+                cigar::{op::Kind, Op},
+                Flags,
+            },
+            RecordBuf,
+        },
+    };
+
+    fn build_read(start: usize, ops: Vec<Op>, sequence: &[u8]) -> RecordBuf {
+        RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_cigar(ops.into_iter().collect())
+            .set_sequence(sequence.to_vec().into())
+            .build()
+    }
+
+    #[test]
+    fn test_build_consensus() -> std::io::Result<()> {
+        let depth_options = DepthOptions {
+            min_mapping_quality: 0,
+            min_base_quality: 0,
+        };
+
+        let mut pileup = Pileup::new(10);
+
+        // Three reads cross the origin from 8 with an insertion after 10 and
+        // a deletion of 2; one of them has a T at 9.
+        for sequence in [b"ACGGTAC", b"ATGGTAC", b"ACGGTAC"] {
+            let ops = vec![
+                Op::new(Kind::Match, 3),
+                Op::new(Kind::Insertion, 1),
+                Op::new(Kind::Match, 1),
+                Op::new(Kind::Deletion, 1),
+                Op::new(Kind::Match, 2),
+            ];
+            pileup.add(&build_read(8, ops, sequence), &depth_options)?;
+        }
+
+        // A read without the indels.
+        let ops = vec![Op::new(Kind::Match, 6)];
+        pileup.add(&build_read(8, ops, b"ACGTCA"), &depth_options)?;
+
+        let options = ConsensusOptions {
+            min_depth: 2,
+            min_allele_fraction: 0.25,
+        };
+
+        let consensus = build_consensus(&pileup, &options);
+
+        // 2 is deleted and a G is inserted after 10 by the majority, 5 to 7
+        // aren't covered and 9 is C or T.
+        assert_eq!(consensus.sequence, b"TACNNNAYGG");
+        assert_eq!(consensus.low_coverage, 3);
+        assert_eq!(consensus.ambiguous, 1);
+        assert_eq!(consensus.inserted, 1);
+        assert_eq!(consensus.deleted, 1);
+
+        Ok(())
+    }
+}
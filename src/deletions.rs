@@ -0,0 +1,724 @@
+//! Large deletion detection on the circular reference.
+//!
+//! Deletions show up either as long `D` ops, or as a read split into a primary
+//! and supplementary alignments listed in its `SA:Z` tag. Breakpoints from
+//! both are folded modulo the reference length and clustered, so deletions
+//! near the origin are found whether the reads were converted or not. A `D` op
+//! cut by the conversion, ending one piece and starting its `_right` piece, is
+//! joined back together.
+
+use crate::{
+    depth::{is_counted, DepthOptions},
+    fold_position, RIGHT_SUFFIX,
+};
+use bstr::{BStr, BString, ByteSlice};
+use noodles::sam::{
+    self,
+    alignment::{
+        record::{
+            cigar::{op::Kind, Op},
+            data::field::Tag,
+        },
+        record_buf::data::field::Value,
+        RecordBuf,
+    },
+};
+use noodles_util::alignment::io::Reader;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, BufRead, Write},
+};
+
+/// Thresholds for detecting deletions.
+#[derive(Clone, Debug)]
+pub struct DeletionOptions {
+    /// The minimum length of a deletion.
+    pub min_len: usize,
+    /// The maximum length of a deletion between split alignments. On the
+    /// circle a duplication looks like a deletion of nearly the whole
+    /// reference, so longer splits are left out.
+    pub max_len: usize,
+    /// The maximum distance between breakpoints in the same cluster, and the
+    /// maximum gap or overlap on the read between split alignments.
+    pub max_distance: usize,
+    /// The minimum number of reads supporting a deletion.
+    pub min_reads: usize,
+    /// The minimum mapping quality of a read.
+    pub min_mapping_quality: u8,
+    /// Other names of the reference in `SA:Z` tags, e.g. the extended
+    /// reference in a converted file.
+    pub alias_refnames: Vec<BString>,
+}
+
+impl Default for DeletionOptions {
+    fn default() -> Self {
+        Self {
+            min_len: 50,
+            max_len: 15000,
+            max_distance: 10,
+            min_reads: 2,
+            min_mapping_quality: 0,
+            alias_refnames: Vec::new(),
+        }
+    }
+}
+
+/// The deletions found in the reads.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeletionCalls {
+    /// The deletions, by position.
+    pub deletions: Vec<Deletion>,
+    /// The number of split alignments left out for being longer than the
+    /// maximum deletion length.
+    pub rejected_splits: usize,
+}
+
+/// A deletion found in the reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deletion {
+    /// The 0-based position of the first deleted base.
+    pub start: usize,
+    /// The number of deleted bases.
+    pub len: usize,
+    /// The number of reads supporting the deletion.
+    pub supporting_reads: usize,
+    /// The number of reads spanning the deleted region without it.
+    pub spanning_reads: usize,
+    /// The direct repeat flanking the deletion, if the reference is known.
+    pub repeat: Option<Vec<u8>>,
+}
+
+impl Deletion {
+    /// Returns the fraction of the reads at the deletion that support it.
+    pub fn heteroplasmy(&self) -> f64 {
+        let total = self.supporting_reads + self.spanning_reads;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.supporting_reads as f64 / total as f64
+        }
+    }
+}
+
+// A deletion seen in a read.
+#[derive(Clone, Debug)]
+struct Observation {
+    name: BString,
+    start: usize,
+    len: usize,
+}
+
+// The reference span of an alignment, unfolded.
+struct Interval {
+    name: BString,
+    start: usize,
+    end: usize,
+}
+
+// An alignment of part of a read, from the record or its SA tag.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    ref_start: usize,
+    ref_end: usize,
+    query_start: usize,
+    query_end: usize,
+}
+
+/// Finds the deletions in the records on `refname`, folding their positions
+/// onto a circular reference of length `reflen`.
+///
+/// `reference_sequence` is the linear reference, used to find the repeats
+/// flanking each deletion.
+pub fn detect_deletions<R>(
+    reader: &mut Reader<R>,
+    refname: &BStr,
+    reflen: usize,
+    reference_sequence: Option<&[u8]>,
+    options: &DeletionOptions,
+) -> io::Result<DeletionCalls>
+where
+    R: BufRead,
+{
+    let header = reader.read_header()?;
+
+    let Some(reference_sequence_id) = header.reference_sequences().get_index_of(refname) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {refname} is not in the header"),
+        ));
+    };
+
+    let depth_options = DepthOptions {
+        min_mapping_quality: options.min_mapping_quality,
+        ..Default::default()
+    };
+
+    let mut observations = Vec::new();
+    let mut intervals = Vec::new();
+    let mut rejected_splits = 0;
+
+    // Deletions ending the left piece of a split read, waiting for its right
+    // piece.
+    let mut pending: HashMap<BString, Observation> = HashMap::new();
+
+    for result in reader.records(&header) {
+        let record = RecordBuf::try_from_alignment_record(&header, &result?)?;
+
+        if record.reference_sequence_id() != Some(reference_sequence_id)
+            || !is_counted(&record, &depth_options)?
+        {
+            continue;
+        }
+
+        let Some(start) = record.alignment_start() else {
+            continue;
+        };
+
+        let full_name = record
+            .name()
+            .map(|name| name.to_owned())
+            .unwrap_or_default();
+        let is_right_piece = full_name.ends_with(RIGHT_SUFFIX);
+        let name = if is_right_piece {
+            BString::from(&full_name[..full_name.len() - RIGHT_SUFFIX.len()])
+        } else {
+            full_name
+        };
+
+        let ops: &[Op] = record.cigar().as_ref();
+        let mut ref_pos = usize::from(start) - 1;
+
+        intervals.push(Interval {
+            name: name.clone(),
+            start: ref_pos,
+            end: ref_pos + record.cigar().alignment_span(),
+        });
+
+        for (i, op) in ops.iter().enumerate() {
+            if !op.kind().consumes_reference() {
+                continue;
+            }
+
+            if op.kind() == Kind::Deletion {
+                let observation = Observation {
+                    name: name.clone(),
+                    start: fold_position(ref_pos, reflen),
+                    len: op.len(),
+                };
+
+                if i == 0 && is_right_piece {
+                    // The rest of a deletion cut by the conversion.
+                    match pending.remove(&name) {
+                        Some(mut left) => {
+                            left.len += op.len();
+                            observations.push(left);
+                        }
+                        None => observations.push(observation),
+                    }
+                } else if i + 1 == ops.len() && !is_right_piece {
+                    pending.insert(name.clone(), observation);
+                } else {
+                    observations.push(observation);
+                }
+            }
+
+            ref_pos += op.len();
+        }
+
+        // Each read's split alignments are only looked at once, from its
+        // primary alignment.
+        if !is_right_piece && !record.flags().is_supplementary() {
+            let (split, rejected) = split_observations(&record, &name, refname, reflen, options);
+            observations.extend(split);
+            rejected_splits += rejected;
+        }
+    }
+
+    observations.extend(pending.into_values());
+    observations.retain(|observation| observation.len >= options.min_len);
+
+    let mut deletions: Vec<Deletion> = cluster(observations, reflen, options.max_distance)
+        .into_iter()
+        .filter(|cluster| count_names(cluster) >= options.min_reads)
+        .map(|cluster| {
+            let (start, len) = representative(&cluster);
+            let supporting_names: HashSet<&BString> = cluster.iter().map(|o| &o.name).collect();
+
+            Deletion {
+                start,
+                len,
+                supporting_reads: supporting_names.len(),
+                spanning_reads: count_spanning_reads(
+                    &intervals,
+                    &supporting_names,
+                    start,
+                    len,
+                    reflen,
+                ),
+                repeat: reference_sequence.map(|sequence| flanking_repeat(sequence, start, len)),
+            }
+        })
+        .collect();
+
+    deletions.sort_by_key(|deletion| (deletion.start, deletion.len));
+
+    if rejected_splits > 0 {
+        log::info!(
+            "{} split alignments longer than {} bases were left out.",
+            rejected_splits,
+            options.max_len
+        );
+    }
+
+    Ok(DeletionCalls {
+        deletions,
+        rejected_splits,
+    })
+}
+
+/// Writes the deletions as a BEDPE of the breakends either side, with the
+/// supporting reads, heteroplasmy and flanking repeat. The number of splits
+/// left out is in a `##rejected_splits` line before the header.
+pub fn write_bedpe<W>(
+    writer: &mut W,
+    calls: &DeletionCalls,
+    name: &BStr,
+    reflen: usize,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(writer, "##rejected_splits={}", calls.rejected_splits)?;
+    writeln!(
+        writer,
+        "#chrom1\tstart1\tend1\tchrom2\tstart2\tend2\tname\tscore\tstrand1\tstrand2\tlength\tsupporting_reads\tspanning_reads\theteroplasmy\trepeat"
+    )?;
+
+    for (i, deletion) in calls.deletions.iter().enumerate() {
+        // The last base kept before the deletion, and the first after it.
+        let before = fold_position(deletion.start + reflen - 1, reflen);
+        let after = fold_position(deletion.start + deletion.len, reflen);
+
+        writeln!(
+            writer,
+            "{name}\t{before}\t{}\t{name}\t{after}\t{}\tdel{}\t{}\t+\t+\t{}\t{}\t{}\t{:.4}\t{}",
+            before + 1,
+            after + 1,
+            i + 1,
+            deletion.supporting_reads,
+            deletion.len,
+            deletion.supporting_reads,
+            deletion.spanning_reads,
+            deletion.heteroplasmy(),
+            format_repeat(&deletion.repeat)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the deletions as a VCF 4.3 of symbolic `<DEL>` alleles. Deletions
+/// crossing the origin can't be written to a VCF, and are skipped. The number
+/// of splits left out is in a `##rejected_splits` line.
+pub fn write_deletions_vcf<W>(
+    writer: &mut W,
+    calls: &DeletionCalls,
+    name: &BStr,
+    reflen: usize,
+    reference_sequence: Option<&[u8]>,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(writer, "##fileformat=VCFv4.3")?;
+    writeln!(writer, "##source=mt_lintocirc")?;
+    writeln!(writer, "##rejected_splits={}", calls.rejected_splits)?;
+    writeln!(
+        writer,
+        "##contig=<ID={name},length={reflen},topology=\"circular\">"
+    )?;
+    writeln!(writer, "##ALT=<ID=DEL,Description=\"Deletion\">")?;
+    writeln!(
+        writer,
+        "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=SVLEN,Number=A,Type=Integer,Description=\"Length of the deletion\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=END,Number=1,Type=Integer,Description=\"Last deleted base\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=SR,Number=1,Type=Integer,Description=\"Reads supporting the deletion\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=WR,Number=1,Type=Integer,Description=\"Reads spanning the deleted region without it\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=HF,Number=A,Type=Float,Description=\"Heteroplasmy fraction\">"
+    )?;
+    writeln!(
+        writer,
+        "##INFO=<ID=REPEAT,Number=1,Type=String,Description=\"Direct repeat flanking the deletion\">"
+    )?;
+    writeln!(writer, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+
+    for (i, deletion) in calls.deletions.iter().enumerate() {
+        // VCF needs the base before the deletion, on the same side of the
+        // origin as its end.
+        if deletion.start == 0 || deletion.start + deletion.len > reflen {
+            log::warn!(
+                "Deletion of {} bases at {} crosses the origin and can't be written to a VCF.",
+                deletion.len,
+                deletion.start + 1
+            );
+            continue;
+        }
+
+        let reference_base = reference_sequence
+            .and_then(|sequence| sequence.get(deletion.start - 1))
+            .map_or('N', |base| char::from(base.to_ascii_uppercase()));
+
+        writeln!(
+            writer,
+            "{name}\t{}\tdel{}\t{reference_base}\t<DEL>\t.\tPASS\tSVTYPE=DEL;SVLEN=-{};END={};SR={};WR={};HF={:.4};REPEAT={}",
+            deletion.start,
+            i + 1,
+            deletion.len,
+            deletion.start + deletion.len,
+            deletion.supporting_reads,
+            deletion.spanning_reads,
+            deletion.heteroplasmy(),
+            format_repeat(&deletion.repeat)
+        )?;
+    }
+
+    Ok(())
+}
+
+fn format_repeat(repeat: &Option<Vec<u8>>) -> String {
+    match repeat {
+        Some(repeat) if !repeat.is_empty() => String::from_utf8_lossy(repeat).into_owned(),
+        _ => String::from("."),
+    }
+}
+
+// Returns the deletions between consecutive alignments of a read on the
+// reference, from the record and its SA tag, and the number of pairs left out
+// for being longer than the maximum length. Only alignments on the same
+// strand as the record, next to each other on the read, are paired.
+fn split_observations(
+    record: &RecordBuf,
+    name: &BString,
+    refname: &BStr,
+    reflen: usize,
+    options: &DeletionOptions,
+) -> (Vec<Observation>, usize) {
+    let Some(Value::String(other_alignments)) = record.data().get(&Tag::OTHER_ALIGNMENTS) else {
+        return (Vec::new(), 0);
+    };
+
+    let Some(start) = record.alignment_start() else {
+        return (Vec::new(), 0);
+    };
+
+    let is_reverse = record.flags().is_reverse_complemented();
+    let mut segments = vec![build_segment(
+        usize::from(start) - 1,
+        record.cigar().as_ref(),
+    )];
+
+    for entry in other_alignments
+        .split_str(";")
+        .filter(|entry| !entry.is_empty())
+    {
+        let fields: Vec<&[u8]> = entry.split_str(",").collect();
+
+        let [sa_refname, pos, strand, cigar, ..] = fields[..] else {
+            continue;
+        };
+
+        let is_alias = options
+            .alias_refnames
+            .iter()
+            .any(|alias| alias.as_bytes() == sa_refname);
+
+        if sa_refname != refname.as_bytes() && !is_alias {
+            continue;
+        }
+
+        let Some(pos) = pos.to_str().ok().and_then(|pos| pos.parse::<usize>().ok()) else {
+            continue;
+        };
+
+        let Ok(ops) = sam::record::Cigar::new(cigar)
+            .iter()
+            .collect::<Result<Vec<Op>, _>>()
+        else {
+            continue;
+        };
+
+        if pos == 0 || (strand == b"-") != is_reverse {
+            continue;
+        }
+
+        segments.push(build_segment(pos - 1, &ops));
+    }
+
+    segments.sort_by_key(|segment| segment.query_start);
+
+    let mut observations = Vec::new();
+    let mut rejected = 0;
+
+    for pair in segments.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+
+        if a.query_end.abs_diff(b.query_start) > options.max_distance {
+            continue;
+        }
+
+        let len = fold_position(
+            fold_position(b.ref_start, reflen) + reflen - fold_position(a.ref_end, reflen),
+            reflen,
+        );
+
+        if len == 0 {
+            continue;
+        }
+
+        if len > options.max_len {
+            rejected += 1;
+            continue;
+        }
+
+        observations.push(Observation {
+            name: name.clone(),
+            start: fold_position(a.ref_end, reflen),
+            len,
+        });
+    }
+
+    (observations, rejected)
+}
+
+fn build_segment(ref_start: usize, ops: &[Op]) -> Segment {
+    let leading_clip: usize = ops
+        .iter()
+        .take_while(|op| matches!(op.kind(), Kind::SoftClip | Kind::HardClip))
+        .map(|op| op.len())
+        .sum();
+
+    let (ref_len, query_len) = ops.iter().fold((0, 0), |(ref_len, query_len), op| {
+        let kind = op.kind();
+        (
+            ref_len
+                + if kind.consumes_reference() {
+                    op.len()
+                } else {
+                    0
+                },
+            query_len
+                + if kind.consumes_read() && kind != Kind::SoftClip {
+                    op.len()
+                } else {
+                    0
+                },
+        )
+    });
+
+    Segment {
+        ref_start,
+        ref_end: ref_start + ref_len,
+        query_start: leading_clip,
+        query_end: leading_clip + query_len,
+    }
+}
+
+// Groups observations whose starts and ends are within `max_distance` of the
+// first observation of a group, on the circle.
+fn cluster(
+    mut observations: Vec<Observation>,
+    reflen: usize,
+    max_distance: usize,
+) -> Vec<Vec<Observation>> {
+    observations.sort_by_key(|observation| (observation.start, observation.len));
+
+    let circular_distance = |a: usize, b: usize| {
+        let d = a.abs_diff(b) % reflen;
+        d.min(reflen - d)
+    };
+
+    let mut clusters: Vec<Vec<Observation>> = Vec::new();
+
+    for observation in observations {
+        let end = observation.start + observation.len;
+
+        let found = clusters.iter_mut().find(|cluster| {
+            let seed = &cluster[0];
+            circular_distance(seed.start, observation.start) <= max_distance
+                && circular_distance(seed.start + seed.len, end) <= max_distance
+        });
+
+        match found {
+            Some(cluster) => cluster.push(observation),
+            None => clusters.push(vec![observation]),
+        }
+    }
+
+    clusters
+}
+
+fn count_names(cluster: &[Observation]) -> usize {
+    cluster
+        .iter()
+        .map(|observation| &observation.name)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+// The most common breakpoints of a cluster, the first if tied.
+fn representative(cluster: &[Observation]) -> (usize, usize) {
+    let mut counts: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+
+    for observation in cluster {
+        *counts
+            .entry((observation.start, observation.len))
+            .or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .max_by(|(a, m), (b, n)| m.cmp(n).then(b.cmp(a)))
+        .map(|(breakpoints, _)| breakpoints)
+        .unwrap_or_default()
+}
+
+// Counts the reads, other than those supporting it, that span the deleted
+// region and a base either side of it.
+fn count_spanning_reads(
+    intervals: &[Interval],
+    supporting_names: &HashSet<&BString>,
+    start: usize,
+    len: usize,
+    reflen: usize,
+) -> usize {
+    intervals
+        .iter()
+        .filter(|interval| !supporting_names.contains(&interval.name))
+        .filter(|interval| {
+            // The region may be a lap around the circle from the interval.
+            [0, reflen, 2 * reflen].iter().any(|&offset| {
+                let region_start = start + offset;
+                region_start >= 1
+                    && interval.start < region_start
+                    && region_start + len < interval.end
+            })
+        })
+        .map(|interval| &interval.name)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+// Returns the direct repeat flanking a deletion: the bases the breakpoints can
+// be shifted by, to the left and right, and still give the same sequence.
+fn flanking_repeat(reference_sequence: &[u8], start: usize, len: usize) -> Vec<u8> {
+    let n = reference_sequence.len();
+
+    if n == 0 {
+        return Vec::new();
+    }
+
+    let base = |i: usize| reference_sequence[i % n].to_ascii_uppercase();
+
+    // Shift left while the base before the deletion equals its last base.
+    let mut left = 0;
+    while left < n && base(start + n - 1 - left) == base(start + len + n - 1 - left) {
+        left += 1;
+    }
+
+    // Shift right while its first base equals the base after it.
+    let mut right = 0;
+    while right < n && base(start + right) == base(start + len + right) {
+        right += 1;
+    }
+
+    (0..left + right)
+        .map(|i| base(start + n - left + i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flanking_repeat() {
+        // ACCT is repeated, and the second copy is deleted along with GG.
+        let reference_sequence = b"TTACCTGGACCTAA";
+        assert_eq!(flanking_repeat(reference_sequence, 6, 6), b"ACCT");
+        assert_eq!(flanking_repeat(reference_sequence, 2, 6), b"ACCT");
+        assert_eq!(flanking_repeat(b"ACGTTTTTGCA", 3, 2), b"TTT");
+        assert!(flanking_repeat(b"AACCGGTT", 2, 2).is_empty());
+    }
+
+    #[test]
+    fn test_detect_deletions() -> io::Result<()> {
+        let sam = b"@HD\tVN:1.6\n\
+@SQ\tSN:chrM\tLN:1000\n\
+@SQ\tSN:chrM_ext\tLN:1200\n\
+a\t0\tchrM\t101\t60\t50M300D50M\t*\t0\t0\t*\t*\n\
+b\t0\tchrM\t103\t60\t48M299D52M\t*\t0\t0\t*\t*\n\
+c\t0\tchrM\t101\t60\t50M50S\t*\t0\t0\t*\t*\tSA:Z:chrM_ext,451,+,50S50M,60,0;\n\
+c\t2048\tchrM\t451\t60\t50S50M\t*\t0\t0\t*\t*\tSA:Z:chrM,101,+,50M50S,60,0;\n\
+d\t0\tchrM\t91\t60\t400M\t*\t0\t0\t*\t*\n\
+e\t0\tchrM\t951\t60\t40M10D\t*\t0\t0\t*\t*\n\
+e_right\t0\tchrM\t1\t60\t50D40M\t*\t0\t0\t*\t*\n\
+f\t0\tchrM\t946\t60\t45M10D\t*\t0\t0\t*\t*\n\
+f_right\t0\tchrM\t1\t60\t50D50M\t*\t0\t0\t*\t*\n\
+g\t0\tchrM\t801\t60\t100M\t*\t0\t0\t*\t*\n\
+h\t0\tchrM\t101\t60\t50M50S\t*\t0\t0\t*\t*\tSA:Z:chrM,801,+,50S50M,60,0;\n";
+
+        let mut reader =
+            noodles_util::alignment::io::reader::Builder::default().build_from_reader(&sam[..])?;
+
+        let options = DeletionOptions {
+            max_len: 600,
+            alias_refnames: vec![BString::from("chrM_ext")],
+            ..Default::default()
+        };
+
+        let calls = detect_deletions(&mut reader, b"chrM".into(), 1000, None, &options)?;
+
+        // h's split is longer than the maximum.
+        assert_eq!(calls.rejected_splits, 1);
+
+        let deletions = &calls.deletions;
+
+        assert_eq!(deletions.len(), 2);
+
+        // a, b and the split alignment of c, spanned by d.
+        assert_eq!(deletions[0].start, 150);
+        assert_eq!(deletions[0].len, 300);
+        assert_eq!(deletions[0].supporting_reads, 3);
+        assert_eq!(deletions[0].spanning_reads, 1);
+
+        // Joined across the origin from e and f.
+        assert_eq!(deletions[1].start, 990);
+        assert_eq!(deletions[1].len, 60);
+        assert_eq!(deletions[1].supporting_reads, 2);
+
+        let mut bedpe = Vec::new();
+        write_bedpe(&mut bedpe, &calls, b"chrM".into(), 1000)?;
+        let bedpe = String::from_utf8(bedpe).unwrap();
+        assert!(bedpe.starts_with("##rejected_splits=1\n"));
+        assert!(bedpe.contains("chrM\t989\t990\tchrM\t50\t51\tdel2\t2\t"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! Per-base depth on the circular reference.
+//!
+//! Alignment positions are folded modulo the reference length, so any part of
+//! an alignment past `reflen`--in a converted file, or straight from the
+//! extended reference--is counted at the start of the reference, and there are
+//! no edge effects at positions 1 and `reflen`.
+
+use crate::fold_position;
+use bstr::BStr;
+use noodles::sam::alignment::{record::cigar::op::Kind, Record};
+use noodles_util::alignment::io::Reader;
+use std::io::{self, BufRead, Write};
+
+/// Filters on the records and bases counted towards the depth.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DepthOptions {
+    /// The minimum mapping quality of a record.
+    pub min_mapping_quality: u8,
+    /// The minimum quality of a base.
+    pub min_base_quality: u8,
+}
+
+/// The forward and reverse strand depth of each position of the reference.
+#[derive(Clone, Debug)]
+pub struct Depth {
+    forward: Vec<u32>,
+    reverse: Vec<u32>,
+}
+
+impl Depth {
+    /// Creates an empty depth for a reference of length `reflen`.
+    pub fn new(reflen: usize) -> Self {
+        Self {
+            forward: vec![0; reflen],
+            reverse: vec![0; reflen],
+        }
+    }
+
+    /// Returns the length of the reference.
+    pub fn len(&self) -> usize {
+        self.forward.len()
+    }
+
+    /// Returns true if the reference is empty.
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty()
+    }
+
+    /// Returns the total depth at the 0-based position `i`.
+    pub fn get(&self, i: usize) -> u32 {
+        self.forward[i] + self.reverse[i]
+    }
+
+    /// Returns the forward and reverse strand depth at the 0-based position
+    /// `i`.
+    pub fn get_stranded(&self, i: usize) -> (u32, u32) {
+        (self.forward[i], self.reverse[i])
+    }
+
+    /// Adds the aligned bases of a record to the depth.
+    ///
+    /// Unmapped, secondary, QC failed and duplicate records are skipped, as
+    /// are those below the minimum mapping quality. Deleted and skipped
+    /// reference bases are not counted.
+    pub fn add(&mut self, record: &impl Record, options: &DepthOptions) -> io::Result<()> {
+        if self.is_empty() || !is_counted(record, options)? {
+            return Ok(());
+        }
+
+        let counts = if record.flags()?.is_reverse_complemented() {
+            &mut self.reverse
+        } else {
+            &mut self.forward
+        };
+
+        for base in aligned_bases(record, counts.len(), options.min_base_quality)? {
+            counts[base.position] += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the total depth as a bedGraph, merging runs of equal depth.
+    pub fn write_bedgraph<W>(&self, writer: &mut W, name: &BStr) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut start = 0;
+
+        while start < self.len() {
+            let depth = self.get(start);
+            let mut end = start + 1;
+
+            while end < self.len() && self.get(end) == depth {
+                end += 1;
+            }
+
+            writeln!(writer, "{name}\t{start}\t{end}\t{depth}")?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the depth of every position, with the forward and reverse strand
+    /// counts.
+    pub fn write_per_base<W>(&self, writer: &mut W, name: &BStr) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "chrom\tpos\tdepth\tforward\treverse")?;
+
+        for i in 0..self.len() {
+            let (forward, reverse) = self.get_stranded(i);
+            writeln!(
+                writer,
+                "{name}\t{}\t{}\t{forward}\t{reverse}",
+                i + 1,
+                forward + reverse
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a summary like mosdepth's: the length, total bases, and the mean,
+    /// minimum and maximum depth.
+    pub fn write_summary<W>(&self, writer: &mut W, name: &BStr) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let depths: Vec<u32> = (0..self.len()).map(|i| self.get(i)).collect();
+        let bases: u64 = depths.iter().map(|&d| u64::from(d)).sum();
+        let min = depths.iter().min().copied().unwrap_or(0);
+        let max = depths.iter().max().copied().unwrap_or(0);
+
+        writeln!(writer, "chrom\tlength\tbases\tmean\tmin\tmax")?;
+        writeln!(
+            writer,
+            "{name}\t{}\t{bases}\t{:.2}\t{min}\t{max}",
+            self.len(),
+            mean(bases, self.len())
+        )
+    }
+
+    /// Writes the mean depth of consecutive windows of `window_len` bases as a
+    /// BED. The last window is cut short at the end of the reference.
+    pub fn write_windows<W>(&self, writer: &mut W, name: &BStr, window_len: usize) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for start in (0..self.len()).step_by(window_len.max(1)) {
+            let end = (start + window_len.max(1)).min(self.len());
+            let bases: u64 = (start..end).map(|i| u64::from(self.get(i))).sum();
+            writeln!(
+                writer,
+                "{name}\t{start}\t{end}\t{:.2}",
+                mean(bases, end - start)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether a record counts towards the depth: it is mapped, not
+/// secondary, QC failed or a duplicate, and passes the mapping quality filter.
+pub(crate) fn is_counted(record: &impl Record, options: &DepthOptions) -> io::Result<bool> {
+    let flags = record.flags()?;
+
+    if flags.is_unmapped() || flags.is_secondary() || flags.is_qc_fail() || flags.is_duplicate() {
+        return Ok(false);
+    }
+
+    // A missing mapping quality is unavailable rather than zero.
+    if let Some(mapping_quality) = record.mapping_quality().transpose()? {
+        if mapping_quality.get() < options.min_mapping_quality {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// A base of a read aligned to the reference.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct AlignedBase {
+    /// The 0-based position, folded onto the circular reference.
+    pub position: usize,
+    /// The index of the base in the read.
+    pub query_index: usize,
+    /// The base, or `None` if the record has no sequence.
+    pub base: Option<u8>,
+    /// The quality score, or `None` if the record has none.
+    pub quality_score: Option<u8>,
+}
+
+/// Returns the bases of `record` aligned to a circular reference of length
+/// `reflen`, in `M`, `=` and `X` ops, leaving out those below
+/// `min_base_quality`. Missing quality scores don't filter anything.
+pub(crate) fn aligned_bases(
+    record: &impl Record,
+    reflen: usize,
+    min_base_quality: u8,
+) -> io::Result<impl Iterator<Item = AlignedBase>> {
+    // The reference and read positions, and the length, of each aligned op.
+    let mut spans = Vec::new();
+
+    if let Some(start) = record.alignment_start().transpose()? {
+        let mut ref_pos = usize::from(start) - 1;
+        let mut query_pos = 0;
+
+        for result in record.cigar().iter() {
+            let op = result?;
+
+            match op.kind() {
+                Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                    spans.push((ref_pos, query_pos, op.len()));
+                    ref_pos += op.len();
+                    query_pos += op.len();
+                }
+                Kind::Insertion | Kind::SoftClip => query_pos += op.len(),
+                Kind::Deletion | Kind::Skip => ref_pos += op.len(),
+                Kind::HardClip | Kind::Pad => {}
+            }
+        }
+    }
+
+    let sequence: Vec<u8> = record.sequence().iter().collect();
+    let quality_scores: Vec<u8> = record.quality_scores().iter().collect();
+
+    Ok(spans
+        .into_iter()
+        .flat_map(|(ref_pos, query_pos, len)| (0..len).map(move |i| (ref_pos + i, query_pos + i)))
+        .filter_map(move |(ref_pos, query_index)| {
+            let quality_score = quality_scores.get(query_index).copied();
+
+            if quality_score.is_some_and(|q| q < min_base_quality) {
+                return None;
+            }
+
+            Some(AlignedBase {
+                position: fold_position(ref_pos, reflen),
+                query_index,
+                base: sequence.get(query_index).copied(),
+                quality_score,
+            })
+        }))
+}
+
+// Returns the mean of `len` values summing to `bases`, or 0 if there are none.
+pub(crate) fn mean(bases: u64, len: usize) -> f64 {
+    if len == 0 {
+        0.0
+    } else {
+        bases as f64 / len as f64
+    }
+}
+
+/// Computes the depth of the records on `refname`, folding their positions
+/// onto a circular reference of length `reflen`.
+pub fn compute_depth<R>(
+    reader: &mut Reader<R>,
+    refname: &BStr,
+    reflen: usize,
+    options: &DepthOptions,
+) -> io::Result<Depth>
+where
+    R: BufRead,
+{
+    let header = reader.read_header()?;
+
+    let Some(reference_sequence_id) = header.reference_sequences().get_index_of(refname) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {refname} is not in the header"),
+        ));
+    };
+
+    let mut depth = Depth::new(reflen);
+
+    for result in reader.records(&header) {
+        let record = result?;
+
+        if record.reference_sequence_id(&header).transpose()? == Some(reference_sequence_id) {
+            depth.add(&record, options)?;
+        }
+    }
+
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::{cigar::Op, Flags, MappingQuality},
+            RecordBuf,
+        },
+    };
+
+    fn build_record(
+        start: usize,
+        ops: Vec<Op>,
+        flags: Flags,
+        quality_scores: Vec<u8>,
+    ) -> RecordBuf {
+        RecordBuf::builder()
+            .set_flags(flags)
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar(ops.into_iter().collect())
+            .set_quality_scores(quality_scores.into())
+            .build()
+    }
+
+    #[test]
+    fn test_depth() -> io::Result<()> {
+        let options = DepthOptions {
+            min_mapping_quality: 20,
+            min_base_quality: 10,
+        };
+
+        let mut depth = Depth::new(10);
+
+        // Wraps around the origin, with a deletion and a low quality base.
+        depth.add(
+            &build_record(
+                8,
+                vec![
+                    Op::new(Kind::Match, 2),
+                    Op::new(Kind::Deletion, 1),
+                    Op::new(Kind::Match, 3),
+                ],
+                Flags::empty(),
+                vec![30, 30, 30, 5, 30],
+            ),
+            &options,
+        )?;
+
+        // A piece already folded, on the reverse strand.
+        depth.add(
+            &build_record(
+                1,
+                vec![Op::new(Kind::Match, 2)],
+                Flags::REVERSE_COMPLEMENTED,
+                vec![],
+            ),
+            &options,
+        )?;
+
+        // Secondary records are skipped.
+        depth.add(
+            &build_record(1, vec![Op::new(Kind::Match, 10)], Flags::SECONDARY, vec![]),
+            &options,
+        )?;
+
+        let depths: Vec<u32> = (0..depth.len()).map(|i| depth.get(i)).collect();
+        assert_eq!(depths, [2, 1, 1, 0, 0, 0, 0, 1, 1, 0]);
+        assert_eq!(depth.get_stranded(0), (1, 1));
+
+        let mut bedgraph = Vec::new();
+        depth.write_bedgraph(&mut bedgraph, b"chrM".into())?;
+        assert_eq!(
+            String::from_utf8(bedgraph).unwrap(),
+            "chrM\t0\t1\t2\nchrM\t1\t3\t1\nchrM\t3\t7\t0\nchrM\t7\t9\t1\nchrM\t9\t10\t0\n"
+        );
+
+        let mut windows = Vec::new();
+        depth.write_windows(&mut windows, b"chrM".into(), 4)?;
+        assert_eq!(
+            String::from_utf8(windows).unwrap(),
+            "chrM\t0\t4\t1.00\nchrM\t4\t8\t0.25\nchrM\t8\t10\t0.50\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aligned_bases() -> io::Result<()> {
+        // A soft clip and an insertion move along the read only, and the last
+        // base is below the minimum quality.
+        let record = build_record(
+            9,
+            vec![
+                Op::new(Kind::SoftClip, 1),
+                Op::new(Kind::Match, 2),
+                Op::new(Kind::Insertion, 1),
+                Op::new(Kind::Match, 2),
+            ],
+            Flags::empty(),
+            vec![30, 30, 30, 30, 30, 5],
+        );
+
+        let bases: Vec<(usize, usize, Option<u8>)> = aligned_bases(&record, 10, 10)?
+            .map(|base| (base.position, base.query_index, base.quality_score))
+            .collect();
+        assert_eq!(
+            bases,
+            [(8, 1, Some(30)), (9, 2, Some(30)), (0, 4, Some(30))]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Extraction of the reads around the origin to FASTQ.
+//!
+//! The reads that cross the origin, or are soft clipped near it, are the ones
+//! worth re-aligning with different parameters. They are written with their
+//! original sequence and qualities, as they came off the sequencer.
+
+use crate::modifications::reverse_complement;
+use noodles::sam::alignment::{
+    record::{
+        cigar::{op::Kind, Op},
+        data::field::Tag,
+    },
+    record_buf::data::field::{value::Array, Value},
+    RecordBuf,
+};
+use std::io::{self, Write};
+
+// The tags carried into the FASTQ comment.
+const CARRIED_TAGS: [Tag; 3] = [
+    Tag::READ_GROUP,
+    Tag::BASE_MODIFICATIONS,
+    Tag::BASE_MODIFICATION_PROBABILITIES,
+];
+
+// Written for reads without base qualities.
+const MISSING_QUALITY: u8 = b'!';
+
+/// Writes the reads around the origin as FASTQ.
+pub struct JunctionReads {
+    writer: Box<dyn Write>,
+    window: usize,
+}
+
+impl JunctionReads {
+    /// Creates a writer for the reads that are split, or soft clipped within
+    /// `window` bases of position 1 or of the end of the linear reference.
+    pub fn new(writer: Box<dyn Write>, window: usize) -> Self {
+        Self { writer, window }
+    }
+
+    /// Writes `read` if it was split, or is soft clipped near the origin.
+    ///
+    /// Secondary and supplementary records are skipped, as they may be hard
+    /// clipped and would repeat the primary record's read.
+    pub(crate) fn add(
+        &mut self,
+        read: &RecordBuf,
+        is_split: bool,
+        reflen: usize,
+    ) -> io::Result<()> {
+        let flags = read.flags();
+
+        if flags.is_secondary() || flags.is_supplementary() {
+            return Ok(());
+        }
+
+        if is_split || is_clipped_near_origin(read, reflen, self.window) {
+            write_fastq_record(&mut self.writer, read)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the FASTQ.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Whether either end of the alignment is soft clipped within `window` bases of
+// position 1 or of `reflen`.
+fn is_clipped_near_origin(read: &RecordBuf, reflen: usize, window: usize) -> bool {
+    let (Some(start), Some(end)) = (read.alignment_start(), read.alignment_end()) else {
+        return false;
+    };
+
+    let is_near = |position: usize| position <= window || position.abs_diff(reflen) <= window;
+    let is_soft_clip = |op: Option<&Op>| op.is_some_and(|op| op.kind() == Kind::SoftClip);
+
+    // Hard clips are outside the soft clips.
+    let ops = read.cigar().as_ref();
+    let first = ops.iter().find(|op| op.kind() != Kind::HardClip);
+    let last = ops.iter().rev().find(|op| op.kind() != Kind::HardClip);
+
+    (is_soft_clip(first) && is_near(start.get())) || (is_soft_clip(last) && is_near(end.get()))
+}
+
+// Writes the read as sequenced, with the carried tags in the comment.
+fn write_fastq_record<W>(writer: &mut W, read: &RecordBuf) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut sequence = read.sequence().as_ref().to_vec();
+    let mut quality_scores: Vec<u8> = if read.quality_scores().is_empty() {
+        vec![MISSING_QUALITY; sequence.len()]
+    } else {
+        read.quality_scores()
+            .as_ref()
+            .iter()
+            .map(|score| score.saturating_add(b'!'))
+            .collect()
+    };
+
+    if read.flags().is_reverse_complemented() {
+        sequence = reverse_complement(&sequence);
+        quality_scores.reverse();
+    }
+
+    writer.write_all(b"@")?;
+    writer.write_all(read.name().map(|name| name.as_ref()).unwrap_or(b"*"))?;
+
+    for tag in CARRIED_TAGS {
+        if let Some(value) = read.data().get(&tag).and_then(format_value) {
+            writer.write_all(b"\t")?;
+            writer.write_all(tag.as_ref())?;
+            write!(writer, ":{value}")?;
+        }
+    }
+
+    writer.write_all(b"\n")?;
+    writer.write_all(&sequence)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(&quality_scores)?;
+    writer.write_all(b"\n")
+}
+
+// Formats a tag value as in SAM, e.g. `Z:value`. Returns `None` for values
+// that can't be written on one line.
+fn format_value(value: &Value) -> Option<String> {
+    let s = match value {
+        Value::Character(c) => format!("A:{}", char::from(*c)),
+        Value::Int8(n) => format!("i:{n}"),
+        Value::UInt8(n) => format!("i:{n}"),
+        Value::Int16(n) => format!("i:{n}"),
+        Value::UInt16(n) => format!("i:{n}"),
+        Value::Int32(n) => format!("i:{n}"),
+        Value::UInt32(n) => format!("i:{n}"),
+        Value::Float(n) => format!("f:{n}"),
+        Value::String(s) => format!("Z:{s}"),
+        Value::Hex(s) => format!("H:{s}"),
+        Value::Array(array) => {
+            let (subtype, values) = match array {
+                Array::Int8(values) => ('c', join(values)),
+                Array::UInt8(values) => ('C', join(values)),
+                Array::Int16(values) => ('s', join(values)),
+                Array::UInt16(values) => ('S', join(values)),
+                Array::Int32(values) => ('i', join(values)),
+                Array::UInt32(values) => ('I', join(values)),
+                Array::Float(values) => ('f', join(values)),
+            };
+
+            if values.is_empty() {
+                format!("B:{subtype}")
+            } else {
+                format!("B:{subtype},{values}")
+            }
+        }
+    };
+
+    (!s.contains(['\t', '\n'])).then_some(s)
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::Flags,
+            record_buf::{QualityScores, Sequence},
+        },
+    };
+
+    fn build_read(start: usize, ops: Vec<Op>, flags: Flags) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name("r1")
+            .set_flags(flags)
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_cigar(ops.into_iter().collect())
+            .set_sequence(Sequence::from(b"AACCGT".to_vec()))
+            .set_quality_scores(QualityScores::from(vec![10, 20, 30, 40, 40, 40]))
+            .set_data(
+                [
+                    (Tag::READ_GROUP, Value::from("rg1")),
+                    (Tag::BASE_MODIFICATIONS, Value::from("C+m,0;")),
+                    (
+                        Tag::BASE_MODIFICATION_PROBABILITIES,
+                        Value::Array(Array::UInt8(vec![200])),
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_write_fastq_record() -> io::Result<()> {
+        // Written in the orientation the read was sequenced in.
+        let read = build_read(
+            998,
+            vec![Op::new(Kind::Match, 6)],
+            Flags::REVERSE_COMPLEMENTED,
+        );
+        let mut fastq = Vec::new();
+        write_fastq_record(&mut fastq, &read)?;
+
+        assert_eq!(
+            String::from_utf8(fastq).unwrap(),
+            "@r1\tRG:Z:rg1\tMM:Z:C+m,0;\tML:B:C,200\nACGGTT\n+\nIII?5+\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_clipped_near_origin() {
+        let matched = vec![Op::new(Kind::Match, 6)];
+        let clipped = vec![Op::new(Kind::Match, 4), Op::new(Kind::SoftClip, 2)];
+        let leading = vec![
+            Op::new(Kind::HardClip, 5),
+            Op::new(Kind::SoftClip, 2),
+            Op::new(Kind::Match, 4),
+        ];
+
+        let is_near = |start, ops: &Vec<Op>| {
+            is_clipped_near_origin(&build_read(start, ops.clone(), Flags::empty()), 1000, 10)
+        };
+
+        // Soft clipped at the end of the linear reference, or at position 1.
+        assert!(is_near(994, &clipped));
+        assert!(is_near(3, &leading));
+
+        // Soft clipped far from the origin, or not clipped at all.
+        assert!(!is_near(500, &clipped));
+        assert!(!is_near(500, &leading));
+        assert!(!is_near(994, &matched));
+    }
+}
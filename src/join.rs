@@ -0,0 +1,549 @@
+//! Joining of primary and supplementary alignments split at the origin.
+//!
+//! An aligner run against the linear reference may already align a read
+//! spanning the origin as a primary at one end of the chromosome and a
+//! supplementary at the other, linked through `SA:Z`, each clipped its own way.
+//! The pair is rewritten as one alignment to the extended reference, so that
+//! the conversion cuts it into the same two pieces as any other read.
+
+use crate::rescue::push_op;
+use bstr::{BStr, BString, ByteSlice};
+use noodles::{
+    core::Position,
+    sam::{
+        self,
+        alignment::{
+            record::{
+                cigar::{op::Kind, Op},
+                data::field::Tag,
+            },
+            record_buf::data::field::Value,
+            RecordBuf,
+        },
+    },
+};
+
+// An alignment of part of a read.
+#[derive(Clone, Debug)]
+struct Piece {
+    // The 1-based position of the first aligned reference base.
+    start: usize,
+    // The ops without the clips.
+    ops: Vec<Op>,
+    // The aligned part of the read, in reference orientation.
+    query_start: usize,
+    query_end: usize,
+}
+
+impl Piece {
+    fn new(start: usize, ops: &[Op]) -> Option<Self> {
+        let is_clip = |op: &&Op| matches!(op.kind(), Kind::SoftClip | Kind::HardClip);
+
+        let leading = ops.iter().take_while(is_clip).count();
+        let trailing = ops[leading..].iter().rev().take_while(is_clip).count();
+        let aligned = &ops[leading..ops.len() - trailing];
+
+        if aligned.is_empty() {
+            return None;
+        }
+
+        let query_start = ops[..leading].iter().map(|op| op.len()).sum();
+        let query_len: usize = aligned
+            .iter()
+            .filter(|op| op.kind().consumes_read())
+            .map(|op| op.len())
+            .sum();
+
+        Some(Self {
+            start,
+            ops: aligned.to_vec(),
+            query_start,
+            query_end: query_start + query_len,
+        })
+    }
+
+    // The 1-based position of the last aligned reference base.
+    fn end(&self) -> usize {
+        let span: usize = self
+            .ops
+            .iter()
+            .filter(|op| op.kind().consumes_reference())
+            .map(|op| op.len())
+            .sum();
+
+        self.start + span - 1
+    }
+
+    // Drops at least `n` read bases from the start of the alignment, along
+    // with any indels left at its start. Returns `None` if nothing is left.
+    fn trim_start(&self, n: usize) -> Option<Self> {
+        let mut remaining = n;
+        let mut trimmed = self.clone();
+        trimmed.ops.clear();
+
+        let mut ops = self.ops.iter();
+
+        for op in ops.by_ref() {
+            let kind = op.kind();
+
+            match (kind.consumes_read(), kind.consumes_reference()) {
+                (true, true) if remaining < op.len() => {
+                    trimmed.query_start += remaining;
+                    trimmed.start += remaining;
+                    trimmed.ops.push(Op::new(kind, op.len() - remaining));
+                    break;
+                }
+                (true, true) => {
+                    remaining -= op.len();
+                    trimmed.query_start += op.len();
+                    trimmed.start += op.len();
+                }
+                (true, false) => {
+                    remaining = remaining.saturating_sub(op.len());
+                    trimmed.query_start += op.len();
+                }
+                (false, true) => trimmed.start += op.len(),
+                (false, false) => {}
+            }
+        }
+
+        trimmed.ops.extend(ops);
+        (!trimmed.ops.is_empty()).then_some(trimmed)
+    }
+}
+
+// An alignment of the read listed in `SA:Z`: rname,pos,strand,CIGAR,mapQ,NM.
+#[derive(Clone, Debug)]
+struct OtherAlignment {
+    is_reverse: bool,
+    start: usize,
+    ops: Vec<Op>,
+}
+
+impl OtherAlignment {
+    fn parse(entry: &[u8], refname: &BStr) -> Option<Self> {
+        let fields: Vec<&[u8]> = entry.split_str(",").collect();
+
+        let [sa_refname, pos, strand, cigar, ..] = fields[..] else {
+            return None;
+        };
+
+        if sa_refname != refname.as_bytes() {
+            return None;
+        }
+
+        let start = pos
+            .to_str()
+            .ok()
+            .and_then(|pos| pos.parse::<usize>().ok())
+            .filter(|&pos| pos > 0)?;
+
+        let ops = sam::record::Cigar::new(cigar)
+            .iter()
+            .collect::<Result<Vec<Op>, _>>()
+            .ok()?;
+
+        Some(Self {
+            is_reverse: strand == b"-",
+            start,
+            ops,
+        })
+    }
+}
+
+// Returns the alignments in `SA:Z`, in order, with `None` for those to other
+// references or that don't parse.
+fn other_alignments(read: &RecordBuf, refname: &BStr) -> Vec<Option<OtherAlignment>> {
+    let Some(Value::String(other_alignments)) = read.data().get(&Tag::OTHER_ALIGNMENTS) else {
+        return Vec::new();
+    };
+
+    other_alignments
+        .split_str(";")
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| OtherAlignment::parse(entry, refname))
+        .collect()
+}
+
+/// Returns true if `read` is a supplementary alignment whose primary is at
+/// the other end of the chromosome, and so is joined into the primary's
+/// pieces.
+///
+/// The first `SA:Z` entry of a supplementary is its primary, and the rest are
+/// the primary's other supplementaries, so the primary's join is worked out
+/// here in full and the two records always agree.
+pub(crate) fn is_joined_supplementary(
+    read: &RecordBuf,
+    refname: &BStr,
+    reflen: usize,
+    max_distance: usize,
+) -> bool {
+    let flags = read.flags();
+
+    if !flags.is_supplementary() || flags.is_unmapped() {
+        return false;
+    }
+
+    let mut alignments = other_alignments(read, refname);
+
+    let Some(Some(primary)) = alignments.first().cloned() else {
+        return false;
+    };
+
+    let Some(start) = read.alignment_start() else {
+        return false;
+    };
+
+    // The primary's own `SA:Z`, with this supplementary in place of the
+    // primary.
+    alignments[0] = Some(OtherAlignment {
+        is_reverse: flags.is_reverse_complemented(),
+        start: start.get(),
+        ops: read.cigar().as_ref().to_vec(),
+    });
+
+    plan_join(&primary, &alignments, reflen, max_distance).is_some_and(|(index, ..)| index == 0)
+}
+
+/// Returns the primary alignment `read` joined with its supplementary at the
+/// other end of the chromosome, as aligned to the extended reference.
+///
+/// The supplementary's clipping is ignored: where the two alignments overlap
+/// on the read the primary keeps the bases, and any bases neither aligns are
+/// an insertion at the join. The joined `SA:Z` entry is removed. A read with
+/// more than one supplementary that could be joined is left alone.
+pub(crate) fn join_at_origin(
+    read: &RecordBuf,
+    refname: &BStr,
+    reflen: usize,
+    max_distance: usize,
+) -> Option<RecordBuf> {
+    let flags = read.flags();
+
+    if flags.is_supplementary() || flags.is_secondary() || flags.is_unmapped() {
+        return None;
+    }
+
+    let own = OtherAlignment {
+        is_reverse: flags.is_reverse_complemented(),
+        start: read.alignment_start()?.get(),
+        ops: read.cigar().as_ref().to_vec(),
+    };
+
+    let alignments = other_alignments(read, refname);
+    let (index, start, cigar) = plan_join(&own, &alignments, reflen, max_distance)?;
+
+    let mut joined = read.clone();
+    *joined.alignment_start_mut() = Position::new(start);
+    *joined.cigar_mut() = cigar.into_iter().collect();
+    remove_other_alignment(&mut joined, index);
+
+    Some(joined)
+}
+
+// Works out the join of a primary with the one entry of its `SA:Z` that,
+// with the primary, makes up a read spanning the origin: one ends near the
+// end of the chromosome, the other starts near position 1, on the same
+// strand, and the one at the end has the earlier part of the read. Returns
+// the index of the entry, and the start and CIGAR of the joined alignment.
+fn plan_join(
+    primary: &OtherAlignment,
+    alignments: &[Option<OtherAlignment>],
+    reflen: usize,
+    max_distance: usize,
+) -> Option<(usize, usize, Vec<Op>)> {
+    // The primary has to hold the whole read.
+    if primary.ops.iter().any(|op| op.kind() == Kind::HardClip) {
+        return None;
+    }
+
+    let own = Piece::new(primary.start, &primary.ops)?;
+
+    let mut partners = alignments
+        .iter()
+        .enumerate()
+        .filter_map(|(index, alignment)| {
+            let alignment = alignment.as_ref()?;
+
+            if alignment.is_reverse != primary.is_reverse {
+                return None;
+            }
+
+            let piece = Piece::new(alignment.start, &alignment.ops)?;
+
+            let (end_piece, start_piece) = if is_at_end(&own, reflen, max_distance) {
+                (&own, &piece)
+            } else {
+                (&piece, &own)
+            };
+
+            (is_at_end(end_piece, reflen, max_distance)
+                && is_at_start(start_piece, max_distance)
+                && end_piece.query_start < start_piece.query_start
+                && end_piece.query_end < start_piece.query_end)
+                .then_some((index, piece))
+        });
+
+    let (index, piece) = partners.next()?;
+
+    if partners.next().is_some() {
+        return None;
+    }
+
+    let (end_piece, start_piece) = if is_at_end(&own, reflen, max_distance) {
+        (own, piece)
+    } else {
+        (piece, own)
+    };
+
+    let read_len: usize = primary
+        .ops
+        .iter()
+        .filter(|op| op.kind().consumes_read())
+        .map(|op| op.len())
+        .sum();
+    let overlap = end_piece.query_end.saturating_sub(start_piece.query_start);
+    let start_piece = start_piece.trim_start(overlap)?;
+
+    if start_piece.query_end > read_len {
+        return None;
+    }
+
+    let mut cigar = Vec::new();
+
+    if end_piece.query_start > 0 {
+        cigar.push(Op::new(Kind::SoftClip, end_piece.query_start));
+    }
+
+    let unaligned = start_piece.query_start - end_piece.query_end;
+    let skipped = (reflen - end_piece.end()) + (start_piece.start - 1);
+
+    let join = [
+        Op::new(Kind::Insertion, unaligned),
+        Op::new(Kind::Deletion, skipped),
+    ];
+
+    let start = end_piece.start;
+
+    for op in end_piece
+        .ops
+        .into_iter()
+        .chain(join.into_iter().filter(|op| !op.is_empty()))
+        .chain(start_piece.ops)
+    {
+        push_op(&mut cigar, op);
+    }
+
+    if start_piece.query_end < read_len {
+        cigar.push(Op::new(Kind::SoftClip, read_len - start_piece.query_end));
+    }
+
+    Some((index, start, cigar))
+}
+
+/// Moves what isn't aligned at the cut of a piece of a joined read off its
+/// ends: the reference the join skipped, a deletion at the cut, is dropped,
+/// and the bases neither alignment had, an insertion at the cut, are soft
+/// clipped, so that neither piece starts or ends with a deletion.
+pub(crate) fn clip_join(piece: &mut RecordBuf) {
+    let ops: Vec<Op> = piece
+        .cigar()
+        .as_ref()
+        .iter()
+        .copied()
+        .filter(|op| !op.is_empty())
+        .collect();
+
+    let (ops, skipped) = clip_front(&ops);
+    let reversed: Vec<Op> = ops.into_iter().rev().collect();
+    let (reversed, _) = clip_front(&reversed);
+
+    if let Some(start) = piece.alignment_start() {
+        *piece.alignment_start_mut() = Position::new(start.get() + skipped);
+    }
+
+    *piece.cigar_mut() = reversed.into_iter().rev().collect();
+}
+
+// Soft clips the insertions at the start of `ops` and drops the deletions.
+// Returns the ops and the length of reference dropped.
+fn clip_front(ops: &[Op]) -> (Vec<Op>, usize) {
+    let hard_clips = ops
+        .iter()
+        .take_while(|op| op.kind() == Kind::HardClip)
+        .count();
+
+    let mut clipped = 0;
+    let mut skipped = 0;
+    let mut rest = &ops[hard_clips..];
+
+    while let Some((op, tail)) = rest.split_first() {
+        match op.kind() {
+            Kind::SoftClip | Kind::Insertion => clipped += op.len(),
+            Kind::Deletion | Kind::Skip => skipped += op.len(),
+            _ => break,
+        }
+
+        rest = tail;
+    }
+
+    // Nothing is aligned.
+    if rest.is_empty() {
+        return (ops.to_vec(), 0);
+    }
+
+    let mut clipped_ops = ops[..hard_clips].to_vec();
+
+    if clipped > 0 {
+        clipped_ops.push(Op::new(Kind::SoftClip, clipped));
+    }
+
+    clipped_ops.extend_from_slice(rest);
+    (clipped_ops, skipped)
+}
+
+fn is_at_end(piece: &Piece, reflen: usize, max_distance: usize) -> bool {
+    let end = piece.end();
+    end <= reflen && reflen - end <= max_distance
+}
+
+fn is_at_start(piece: &Piece, max_distance: usize) -> bool {
+    piece.start - 1 <= max_distance
+}
+
+// Removes the `index`th entry of `SA:Z`, and the tag if it was the only one.
+fn remove_other_alignment(read: &mut RecordBuf, index: usize) {
+    let Some(Value::String(other_alignments)) = read.data().get(&Tag::OTHER_ALIGNMENTS) else {
+        return;
+    };
+
+    let rest: Vec<&[u8]> = other_alignments
+        .split_str(";")
+        .filter(|entry| !entry.is_empty())
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .map(|(_, entry)| entry)
+        .collect();
+
+    if rest.is_empty() {
+        read.data_mut().remove(&Tag::OTHER_ALIGNMENTS);
+    } else {
+        let mut value = BString::from(rest.join(&b";"[..]));
+        value.push(b';');
+        read.data_mut()
+            .insert(Tag::OTHER_ALIGNMENTS, Value::String(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::sam::alignment::{record::Flags, record_buf::Sequence};
+
+    const REFLEN: usize = 1000;
+
+    fn build_read(flags: Flags, start: usize, ops: Vec<Op>, sa: &str) -> RecordBuf {
+        let len = ops
+            .iter()
+            .filter(|op| op.kind().consumes_read())
+            .map(|op| op.len())
+            .sum();
+
+        RecordBuf::builder()
+            .set_flags(flags)
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_cigar(ops.into_iter().collect())
+            .set_sequence(Sequence::from(vec![b'A'; len]))
+            .set_data(
+                [(Tag::OTHER_ALIGNMENTS, Value::from(sa))]
+                    .into_iter()
+                    .collect(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_join_at_origin() {
+        let refname: &BStr = b"chrM".into();
+
+        // A primary at the end with 40 bases clipped, and a supplementary at
+        // the start overlapping it by 5 bases on the read.
+        let primary = build_read(
+            Flags::empty(),
+            REFLEN - 59,
+            vec![Op::new(Kind::Match, 60), Op::new(Kind::SoftClip, 40)],
+            "chrM,1,+,55H45M,60,0;chr1,100,+,30M70S,10,2;",
+        );
+
+        let joined = join_at_origin(&primary, refname, REFLEN, 5).unwrap();
+        assert_eq!(joined.alignment_start(), Position::new(REFLEN - 59));
+        assert_eq!(
+            joined.cigar().as_ref(),
+            [
+                Op::new(Kind::Match, 60),
+                Op::new(Kind::Deletion, 5),
+                Op::new(Kind::Match, 40)
+            ]
+        );
+        assert_eq!(
+            joined.data().get(&Tag::OTHER_ALIGNMENTS),
+            Some(&Value::from("chr1,100,+,30M70S,10,2;"))
+        );
+
+        let supplementary = build_read(
+            Flags::SUPPLEMENTARY,
+            1,
+            vec![Op::new(Kind::HardClip, 55), Op::new(Kind::Match, 45)],
+            "chrM,941,+,60M40S,60,0;",
+        );
+        assert!(is_joined_supplementary(&supplementary, refname, REFLEN, 5));
+        assert!(join_at_origin(&supplementary, refname, REFLEN, 5).is_none());
+
+        // The pieces are in the wrong order on the read.
+        let primary = build_read(
+            Flags::empty(),
+            REFLEN - 59,
+            vec![Op::new(Kind::SoftClip, 40), Op::new(Kind::Match, 60)],
+            "chrM,1,+,60H40M,60,0;",
+        );
+        assert!(join_at_origin(&primary, refname, REFLEN, 5).is_none());
+
+        // A gap between the pieces on the read becomes an insertion.
+        let primary = build_read(
+            Flags::empty(),
+            1,
+            vec![Op::new(Kind::SoftClip, 50), Op::new(Kind::Match, 50)],
+            "chrM,961,+,40M60S,60,0;",
+        );
+
+        let joined = join_at_origin(&primary, refname, REFLEN, 5).unwrap();
+        assert_eq!(joined.alignment_start(), Position::new(961));
+        assert_eq!(
+            joined.cigar().as_ref(),
+            [
+                Op::new(Kind::Match, 40),
+                Op::new(Kind::Insertion, 10),
+                Op::new(Kind::Match, 50)
+            ]
+        );
+        assert!(joined.data().get(&Tag::OTHER_ALIGNMENTS).is_none());
+
+        // Two supplementaries could be joined, so neither is, and neither is
+        // dropped.
+        let primary = build_read(
+            Flags::empty(),
+            REFLEN - 59,
+            vec![Op::new(Kind::Match, 60), Op::new(Kind::SoftClip, 40)],
+            "chrM,1,+,60H40M,60,0;chrM,3,+,70H30M,60,0;",
+        );
+        assert!(join_at_origin(&primary, refname, REFLEN, 5).is_none());
+
+        let supplementary = build_read(
+            Flags::SUPPLEMENTARY,
+            1,
+            vec![Op::new(Kind::HardClip, 60), Op::new(Kind::Match, 40)],
+            "chrM,941,+,60M40S,60,0;chrM,3,+,70H30M,60,0;",
+        );
+        assert!(!is_joined_supplementary(&supplementary, refname, REFLEN, 5));
+    }
+}
@@ -0,0 +1,258 @@
+//! Per-read report of the reads crossing the origin.
+//!
+//! Every read that is split or shifted gets a row describing where it was cut,
+//! so the alignment quality around the origin can be checked without diffing
+//! the input and output.
+
+use noodles::sam::alignment::{
+    record::cigar::{op::Kind, Op},
+    Record, RecordBuf,
+};
+use std::io::{self, Write};
+
+// The number of query bases reported on each side of the cut.
+const FLANK_LEN: usize = 10;
+
+// Mismatches are counted within this many reference bases of the origin.
+const WINDOW_LEN: usize = 50;
+
+const HEADER: &str = "read_name\tstrand\tpos\tcigar\tleft_pos\tleft_cigar\tleft_query_span\t\
+right_pos\tright_cigar\tright_query_span\tleft_flank\tright_flank\tboundary_mismatches";
+
+/// Writes a TSV row for each read that is split or shifted by the conversion.
+pub struct JunctionReport {
+    writer: Box<dyn Write>,
+    reference_sequence: Option<Vec<u8>>,
+}
+
+impl JunctionReport {
+    /// Creates a report and writes its header.
+    ///
+    /// `reference_sequence` is the extended reference, used to count the
+    /// mismatches near the origin. Without it the mismatches are reported as
+    /// `.`.
+    pub fn new(
+        mut writer: Box<dyn Write>,
+        reference_sequence: Option<Vec<u8>>,
+    ) -> io::Result<Self> {
+        writeln!(writer, "{HEADER}")?;
+
+        Ok(Self {
+            writer,
+            reference_sequence,
+        })
+    }
+
+    /// Adds a row for `record`, which was converted into the `left` piece and,
+    /// if it was split, the `right` piece.
+    pub(crate) fn add(
+        &mut self,
+        record: &impl Record,
+        left: &RecordBuf,
+        right: Option<&RecordBuf>,
+        reflen: usize,
+    ) -> io::Result<()> {
+        let read_name = record
+            .name()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .unwrap_or_else(|| String::from("*"));
+
+        let strand = if record.flags()?.is_reverse_complemented() {
+            '-'
+        } else {
+            '+'
+        };
+
+        let ops: Vec<Op> = record.cigar().iter().collect::<io::Result<_>>()?;
+        let start = record.alignment_start().transpose()?;
+
+        let left_len = left.cigar().read_length();
+
+        let (right_pos, right_cigar, right_query_span, left_flank, right_flank) = match right {
+            Some(right) => {
+                let right_len = right.cigar().read_length();
+
+                (
+                    format_position(right.alignment_start().map(usize::from)),
+                    format_cigar(right.cigar().as_ref()),
+                    format!("{}-{}", left_len, left_len + right_len),
+                    flank(left.sequence().as_ref(), true),
+                    flank(right.sequence().as_ref(), false),
+                )
+            }
+            None => (
+                String::from("."),
+                String::from("."),
+                String::from("."),
+                String::from("."),
+                String::from("."),
+            ),
+        };
+
+        let mismatches = match (&self.reference_sequence, start) {
+            (Some(reference_sequence), Some(start)) => {
+                let sequence: Vec<u8> = record.sequence().iter().collect();
+                count_boundary_mismatches(reference_sequence, &sequence, start.get(), &ops, reflen)
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| String::from("."))
+            }
+            _ => String::from("."),
+        };
+
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t0-{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            read_name,
+            strand,
+            format_position(start.map(usize::from)),
+            format_cigar(&ops),
+            format_position(left.alignment_start().map(usize::from)),
+            format_cigar(left.cigar().as_ref()),
+            left_len,
+            right_pos,
+            right_cigar,
+            right_query_span,
+            left_flank,
+            right_flank,
+            mismatches
+        )
+    }
+
+    /// Flushes the report.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn format_position(position: Option<usize>) -> String {
+    position.map_or_else(|| String::from("0"), |p| p.to_string())
+}
+
+fn format_cigar(ops: &[Op]) -> String {
+    if ops.is_empty() {
+        return String::from("*");
+    }
+
+    ops.iter()
+        .map(|op| {
+            let c = match op.kind() {
+                Kind::Match => 'M',
+                Kind::Insertion => 'I',
+                Kind::Deletion => 'D',
+                Kind::Skip => 'N',
+                Kind::SoftClip => 'S',
+                Kind::HardClip => 'H',
+                Kind::Pad => 'P',
+                Kind::SequenceMatch => '=',
+                Kind::SequenceMismatch => 'X',
+            };
+            format!("{}{}", op.len(), c)
+        })
+        .collect()
+}
+
+// Returns the query bases next to the cut: the end of the left piece, or the
+// start of the right piece.
+fn flank(sequence: &[u8], is_left: bool) -> String {
+    if sequence.is_empty() {
+        return String::from(".");
+    }
+
+    let n = FLANK_LEN.min(sequence.len());
+    let bases = if is_left {
+        &sequence[sequence.len() - n..]
+    } else {
+        &sequence[..n]
+    };
+
+    String::from_utf8_lossy(bases).into_owned()
+}
+
+// Counts the aligned bases that differ from the reference within WINDOW_LEN
+// bases either side of the origin, i.e. in the 1-based reference positions
+// (reflen - WINDOW_LEN, reflen + WINDOW_LEN]. Returns None if the read has no
+// sequence or runs off the reference.
+fn count_boundary_mismatches(
+    reference_sequence: &[u8],
+    sequence: &[u8],
+    start: usize,
+    ops: &[Op],
+    reflen: usize,
+) -> Option<usize> {
+    if sequence.is_empty() {
+        return None;
+    }
+
+    let window = (reflen.saturating_sub(WINDOW_LEN) + 1)..=(reflen + WINDOW_LEN);
+
+    let mut ref_pos = start;
+    let mut query_pos = 0;
+    let mut mismatches = 0;
+
+    for op in ops {
+        match op.kind() {
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                for i in 0..op.len() {
+                    if window.contains(&(ref_pos + i)) {
+                        let reference_base = reference_sequence.get(ref_pos + i - 1)?;
+                        let base = sequence.get(query_pos + i)?;
+
+                        if !reference_base.eq_ignore_ascii_case(base) {
+                            mismatches += 1;
+                        }
+                    }
+                }
+
+                ref_pos += op.len();
+                query_pos += op.len();
+            }
+            Kind::Insertion | Kind::SoftClip => query_pos += op.len(),
+            Kind::Deletion | Kind::Skip => ref_pos += op.len(),
+            Kind::HardClip | Kind::Pad => {}
+        }
+    }
+
+    Some(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_boundary_mismatches() {
+        let reference_sequence = b"ACGTACGTAC";
+        let ops = [
+            Op::new(Kind::Match, 3),
+            Op::new(Kind::Deletion, 1),
+            Op::new(Kind::Match, 3),
+        ];
+
+        // Aligned at 3: GTA-GTT, with the last base a mismatch.
+        assert_eq!(
+            count_boundary_mismatches(reference_sequence, b"GTAGTT", 3, &ops, 6),
+            Some(1)
+        );
+
+        // The same read, but with the window too far away to include any of it.
+        assert_eq!(
+            count_boundary_mismatches(reference_sequence, b"GTAGTT", 3, &ops, 100),
+            Some(0)
+        );
+
+        // Past the end of the reference.
+        assert_eq!(
+            count_boundary_mismatches(reference_sequence, b"GTAGTT", 8, &ops, 6),
+            None
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let ops = [Op::new(Kind::SoftClip, 5), Op::new(Kind::Match, 45)];
+        assert_eq!(format_cigar(&ops), "5S45M");
+        assert_eq!(format_cigar(&[]), "*");
+        assert_eq!(flank(b"ACGTACGTACGT", true), "GTACGTACGT");
+        assert_eq!(flank(b"ACG", false), "ACG");
+    }
+}
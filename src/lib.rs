@@ -1,5 +1,60 @@
 //! Library (helper) modules for mt_lintocirc.
 
+mod call;
+mod cells;
+mod cohort;
+mod consensus;
+mod deletions;
+mod depth;
+mod extract;
+mod join;
+mod json;
+mod junction;
+mod lift;
+mod modifications;
+mod mtcn;
+mod normalize;
+mod numt;
+mod paf;
+mod per_base;
+mod phase;
+mod pileup;
+mod reference;
+mod region;
+mod rescue;
+mod stats;
+mod validate;
+
+pub use call::{call_variants, write_vcf, CallOptions, Variant};
+pub use cells::{compute_cell_counts, CellCounts, CellOptions, CellStats, UMI_TAG};
+pub use cohort::{
+    cohort_sites, read_manifest, write_cohort_matrix, write_cohort_vcf, CohortOutputs,
+    CohortSample, CountsSource, SampleCounts, Site,
+};
+pub use consensus::{build_consensus, Consensus, ConsensusOptions};
+pub use deletions::{
+    detect_deletions, write_bedpe, write_deletions_vcf, Deletion, DeletionCalls, DeletionOptions,
+};
+pub use depth::{compute_depth, Depth, DepthOptions};
+pub use extract::JunctionReads;
+pub use junction::JunctionReport;
+pub use lift::{
+    lift_bed, lift_vcf, DepthPolicy, Lift, LiftBedOptions, LiftDirection, LiftVcfOptions,
+};
+pub use mtcn::{estimate_copy_number, read_bed, CopyNumber, CopyNumberOptions};
+pub use numt::{NumtOptions, NUMT_TAG};
+pub use paf::{convert_paf, PafStats};
+pub use per_base::{parse_per_base_tag, parse_tag, PerBaseLayout, PerBaseTags};
+pub use phase::{
+    phase_sam, read_phase_sites, Haplotype, PhaseOptions, PhaseSite, Phasing, HAPLOTYPE_TAG,
+};
+pub use pileup::{compute_pileup, Allele, Indel, Pileup};
+pub use reference::build_repository;
+pub use region::{convert_bam_passthrough, convert_region};
+pub use rescue::{rescue_sam, RescueOptions};
+pub use stats::{ConversionStats, FlagClass, Histogram, Outcome};
+pub use validate::{validate, ValidationReport, Violation, ViolationKind};
+
 use bstr::BString;
 use noodles::{
     core::Position,
@@ -20,78 +75,304 @@ use noodles::{
 };
 use noodles_util::alignment::io::Reader;
 use std::{
+    ffi::OsString,
     io::{self, BufRead, Write as StdWrite},
     num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
 // For writing we are going to use the simpler, but less efficient std::io
 // writer, as opposed to the faster, but more complex tokio::async::writer.
 
+/// The suffix added to the name of the right piece of a split read.
+pub(crate) const RIGHT_SUFFIX: &[u8] = b"_right";
+
+/// Folds a 0-based position on the extended reference onto the circular
+/// reference of length `reflen`, the way `convert_read` cuts split reads.
+pub(crate) fn fold_position(position: usize, reflen: usize) -> usize {
+    position % reflen
+}
+
 // The return type from the convert read.
-enum SplitType {
+pub(crate) enum SplitType {
     Unchanged,           // No change to the input read
     Modified(RecordBuf), // Read modified, but not split
     Split(RecordBuf, RecordBuf),
 }
 
+/// Options controlling how alignments to the extended reference are converted.
+pub struct ConvertOptions {
+    /// The length of the linear reference.
+    pub reflen: usize,
+    /// The name of the extended reference the reads were aligned to.
+    pub refname: BString,
+    /// The name of the linear reference in the output.
+    pub target_refname: BString,
+    /// The per-base array tags to split alongside the sequence.
+    pub per_base_tags: PerBaseTags,
+    /// If set, reads likely from NUMTs are tagged, or left out.
+    pub numt: Option<NumtOptions>,
+    /// If set, the linear reference sequence to left-align the indels
+    /// against before the reads are split.
+    pub left_align: Option<Vec<u8>>,
+}
+
+/// Optional per-read reports written alongside the converted alignments.
+#[derive(Default)]
+pub struct SideOutputs {
+    /// A row for each read that is split or shifted.
+    pub junctions: Option<JunctionReport>,
+    /// The original reads around the origin, as FASTQ.
+    pub junction_reads: Option<JunctionReads>,
+}
+
+impl SideOutputs {
+    /// Flushes the reports.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(junctions) = self.junctions.as_mut() {
+            junctions.finish()?;
+        }
+
+        if let Some(junction_reads) = self.junction_reads.as_mut() {
+            junction_reads.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Converts SAM records in an alignment file that were aligned to a
 /// doubled circular reference genome--a reference in which the linear reference
 /// genome is doubled--back to a single copy linear reference genome.
 ///
+/// Returns the statistics of the conversion. The reads around the origin are
+/// also written to the reports in `outputs`.
 pub fn convert_sam<T>(
     reader: &mut Reader<Box<dyn BufRead>>,
-    reflen: usize,
     bufwriter: &mut Box<dyn StdWrite>,
-    refname: &BString,
-    target_refname: BString,
-) -> io::Result<()> {
-    let mut header = reader.read_header()?;
+    options: &ConvertOptions,
+    outputs: &mut SideOutputs,
+) -> io::Result<ConversionStats> {
+    let header = reader.read_header()?;
+    let converted_header = convert_header(&header, options);
+
+    // Open a writer to stdout. We want to lock stdout to explicitly control stdout buffering.
+    let mut writer = Writer::new(bufwriter);
+
+    // Write the header for the SAM
+    writer.write_header(&converted_header)?;
+
+    let mut stats = ConversionStats::default();
+
+    write_converted_records(
+        &mut writer,
+        &header,
+        &converted_header,
+        reader.records(&header),
+        options,
+        &mut stats,
+        outputs,
+    )?;
+
+    // Close the writer
+    writer.finish(&converted_header)?;
+    stats.finish();
 
+    Ok(stats)
+}
+
+/// Returns the header for the converted alignments, with the extended
+/// reference replaced by the linear reference.
+///
+/// The linear reference takes the place of the extended reference, so the
+/// reference sequence IDs of all the other reference sequences are unchanged.
+pub(crate) fn convert_header(header: &Header, options: &ConvertOptions) -> Header {
+    let mut header = header.clone();
     let reference_sequences = header.reference_sequences_mut();
 
+    if !reference_sequences.contains_key(&options.refname) {
+        log::warn!(
+            "Reference: {} is not in the header, no reads will be converted.",
+            options.refname
+        );
+    }
+
     // Create an entry for chrM and add it to the end, but only if refname and target_refname are different.
     // Note that if the names don't change, we do not chagne or check the reflen.
-    let mt_ref_len = NonZeroUsize::new(reflen).unwrap();
+    let mt_ref_len = NonZeroUsize::new(options.reflen).unwrap();
     let mt_refseq = Map::<ReferenceSequence>::new(mt_ref_len);
 
     // If the target_refname is the same as refname, the value is simply updated. If not,
     // a new entry is inserted and None is returned.
     if reference_sequences
-        .insert(target_refname, mt_refseq)
+        .insert(options.target_refname.clone(), mt_refseq)
         .is_none()
     {
-        reference_sequences.swap_remove(refname);
+        reference_sequences.swap_remove(&options.refname);
     }
 
-    // Open a writer to stdout. We want to lock stdout to explicitly control stdout buffering.
-    let mut writer = Writer::new(bufwriter);
+    header
+}
 
-    // Write the header for the SAM
-    writer.write_header(&header)?;
+/// Converts the records aligned to the extended reference and writes them
+/// out. Records on other reference sequences, and unmapped records, are
+/// written unchanged.
+///
+/// `header` is the header of the input, used to read the records, and
+/// `converted_header` the header of the output. Every record is counted in
+/// `stats`, and the ones around the origin are added to `outputs`.
+pub(crate) fn write_converted_records<R, I>(
+    writer: &mut dyn Write,
+    header: &Header,
+    converted_header: &Header,
+    records: I,
+    options: &ConvertOptions,
+    stats: &mut ConversionStats,
+    outputs: &mut SideOutputs,
+) -> io::Result<()>
+where
+    R: Record,
+    I: IntoIterator<Item = io::Result<R>>,
+{
+    let mt_reference_sequence_id = header.reference_sequences().get_index_of(&options.refname);
 
     // Loop through the SAM records.
-    for result in reader.records(&header) {
+    for result in records {
         let record = result?;
 
         // Check if this reference is one we're interested in.
+        let reference_sequence_id = record.reference_sequence_id(header).transpose()?;
+
+        let flags = record.flags()?;
+
+        if reference_sequence_id.is_none() || reference_sequence_id != mt_reference_sequence_id {
+            let outcome = if reference_sequence_id.is_none() {
+                Outcome::Unplaced
+            } else {
+                Outcome::OtherReference
+            };
+            stats.add(outcome, flags);
+            writer.write_alignment_record(converted_header, &record)?;
+            continue;
+        }
+
+        let is_numt = match &options.numt {
+            Some(numt_options) => {
+                let read = RecordBuf::try_from_alignment_record(header, &record)?;
+                let mito_refnames = [options.refname.as_ref(), options.target_refname.as_ref()];
+                numt::is_numt(&read, &mito_refnames, numt_options)
+            }
+            None => false,
+        };
+
+        if is_numt {
+            stats.numt_flagged += 1;
+
+            if options.numt.is_some_and(|numt_options| numt_options.filter) {
+                stats.add(Outcome::Filtered, flags);
+                continue;
+            }
+        }
+
+        let aligned = match &options.left_align {
+            Some(reference_sequence) => {
+                let read = RecordBuf::try_from_alignment_record(header, &record)?;
+                normalize::left_align_indels(&read, reference_sequence, options.reflen)
+            }
+            None => None,
+        };
+
+        if aligned.is_some() {
+            stats.left_aligned += 1;
+        }
+
+        let read_type = match &aligned {
+            Some(read) => convert_read(read, header, options.reflen, &options.per_base_tags),
+            None => convert_read(&record, header, options.reflen, &options.per_base_tags),
+        };
+
+        if !matches!(read_type, SplitType::Unchanged) {
+            if let Some(Ok(end)) = record.alignment_end() {
+                stats
+                    .overhangs
+                    .add(end.get().saturating_sub(options.reflen));
+            }
+        }
+
+        if let Some(junction_reads) = outputs.junction_reads.as_mut() {
+            let read = RecordBuf::try_from_alignment_record(header, &record)?;
+            let is_split = matches!(read_type, SplitType::Split(..));
+            junction_reads.add(&read, is_split, options.reflen)?;
+        }
 
-        let read_type = convert_read(&record, &header, reflen);
         match read_type {
-            SplitType::Unchanged => writer.write_alignment_record(&header, &record)?,
-            SplitType::Modified(read) => writer.write_alignment_record(&header, &read)?,
-            SplitType::Split(left_read, right_read) => {
+            SplitType::Unchanged if is_numt || aligned.is_some() => {
+                stats.add(Outcome::Unchanged, flags);
+
+                let mut read = match aligned {
+                    Some(read) => read,
+                    None => RecordBuf::try_from_alignment_record(header, &record)?,
+                };
+
+                if is_numt {
+                    numt::flag(&mut read);
+                }
+
+                writer.write_alignment_record(converted_header, &read)?
+            }
+            SplitType::Unchanged => {
+                stats.add(Outcome::Unchanged, flags);
+                writer.write_alignment_record(converted_header, &record)?
+            }
+            SplitType::Modified(mut read) => {
+                stats.add(Outcome::Modified, flags);
+
+                if is_numt {
+                    numt::flag(&mut read);
+                }
+
+                if let Some(junctions) = outputs.junctions.as_mut() {
+                    junctions.add(&record, &read, None, options.reflen)?;
+                }
+
+                writer.write_alignment_record(converted_header, &read)?
+            }
+            SplitType::Split(mut left_read, mut right_read) => {
+                stats.add(Outcome::Split, flags);
+
+                if is_numt {
+                    numt::flag(&mut left_read);
+                    numt::flag(&mut right_read);
+                }
+
+                stats
+                    .left_piece_lengths
+                    .add(left_read.cigar().read_length());
+                stats
+                    .right_piece_lengths
+                    .add(right_read.cigar().read_length());
+
+                if let Some(junctions) = outputs.junctions.as_mut() {
+                    junctions.add(&record, &left_read, Some(&right_read), options.reflen)?;
+                }
+
                 // If we get a left and right read then write them separately.
-                writer.write_alignment_record(&header, &left_read)?;
-                writer.write_alignment_record(&header, &right_read)?;
+                writer.write_alignment_record(converted_header, &left_read)?;
+                writer.write_alignment_record(converted_header, &right_read)?;
             }
         }
     }
 
-    // Close the writer
-    writer.finish(&header)
+    Ok(())
 }
 
-fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitType {
+pub(crate) fn convert_read(
+    record: &impl Record,
+    header: &Header,
+    reflen: usize,
+    per_base_tags: &PerBaseTags,
+) -> SplitType {
     let read_name = record.name().expect("UNKNOWN read name!");
 
     // We are only looking for reads that are longer than `reflen`
@@ -102,7 +383,9 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
     };
 
     // Parse the cigar vector to check if we need to split this read.
-    let mut left_ref_len: usize = record_start.get();
+    // `left_ref_len` is 0-based, so the left piece ends at position `reflen`
+    // and position `reflen` + 1 is the start of the right piece.
+    let mut left_ref_len: usize = record_start.get() - 1;
 
     let name = record.name().unwrap();
     let name_str = std::str::from_utf8(name).unwrap();
@@ -118,12 +401,12 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
         log::warn!(
             "Read: {} has a start alignment: {} beyond reference.",
             name_str,
-            left_ref_len
+            record_start
         );
 
         // Subtract the reflen to reset the proper alignment start.
         let mut read = RecordBuf::try_from_alignment_record(header, record).unwrap();
-        let record_start = left_ref_len - reflen;
+        let record_start = left_ref_len - reflen + 1;
         *read.alignment_start_mut() = Position::new(record_start);
 
         return SplitType::Modified(read);
@@ -137,7 +420,7 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
     let cigar_vec: Vec<Op> = record.cigar().iter().map(|x| x.ok().unwrap()).collect();
     let mut opiter = cigar_vec.iter();
 
-    while let Some(oper) = opiter.next() {
+    for oper in opiter.by_ref() {
         // Do we advance the reference count?
         if oper.kind().consumes_reference() {
             // If we go beyond the reference length, then we need to split this op
@@ -171,14 +454,14 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
         }
 
         // Add the operator
-        left_cigar.push(oper.clone());
+        left_cigar.push(*oper);
     }
 
     // Update the sequence and the quality scores for the left read.
     // Trim the sequence. In minimap2 if the alignment is a secondary alignment,
     // then there is no sequence in the secondary alignment, so nothing to split.
     let (left_sequence, right_sequence, left_quality_scores, right_quality_scores) =
-        if record.sequence().len() == 0 {
+        if record.sequence().is_empty() {
             assert_eq!(
                 record.quality_scores().len(),
                 0,
@@ -217,8 +500,8 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
     }
 
     // Then simply add the rest of the cigar
-    while let Some(oper) = opiter.next() {
-        right_cigar.push(oper.clone());
+    for oper in opiter {
+        right_cigar.push(*oper);
     }
 
     // Now create the left and right reads.
@@ -232,7 +515,7 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
     *left_read.sequence_mut() = RecordBufSequence::from(left_sequence);
 
     // If there's nothing in the right cigar, then there's no split.
-    if right_cigar.len() == 0 {
+    if right_cigar.is_empty() {
         return SplitType::Unchanged;
     }
 
@@ -246,12 +529,61 @@ fn convert_read(record: &impl Record, header: &Header, reflen: usize) -> SplitTy
     *right_read.sequence_mut() = RecordBufSequence::from(right_sequence);
 
     // Also change the read name
-    let right_name = String::from(name_str) + "_right";
+    let right_name = [name, RIGHT_SUFFIX].concat();
     *right_read.name_mut() = Some(right_name.into());
 
+    // Per-base tags describing the full read have to be cut at the same place
+    // as the sequence.
+    if !record.sequence().is_empty() {
+        let sequence: Vec<u8> = record.sequence().iter().collect();
+        let is_reverse_complemented = record.flags().unwrap().is_reverse_complemented();
+
+        slice_per_base_data(
+            &mut left_read,
+            &sequence,
+            is_reverse_complemented,
+            0..sequence_idx,
+            per_base_tags,
+        );
+        slice_per_base_data(
+            &mut right_read,
+            &sequence,
+            is_reverse_complemented,
+            sequence_idx..sequence.len(),
+            per_base_tags,
+        );
+    }
+
     SplitType::Split(left_read, right_read)
 }
 
+// Appends an extension to a path, e.g. to find the index of a file.
+pub(crate) fn push_ext(src: &Path, ext: &str) -> PathBuf {
+    let mut s = OsString::from(src);
+    s.push(".");
+    s.push(ext);
+    PathBuf::from(s)
+}
+
+// Cuts the per-base data fields of a split piece--base modifications and
+// per-base arrays--down to the part of `sequence` in `range`.
+fn slice_per_base_data(
+    read: &mut RecordBuf,
+    sequence: &[u8],
+    is_reverse_complemented: bool,
+    range: Range<usize>,
+    per_base_tags: &PerBaseTags,
+) {
+    modifications::slice_base_modifications(read, sequence, is_reverse_complemented, range.clone());
+    per_base::slice_per_base_tags(
+        read,
+        per_base_tags,
+        sequence.len(),
+        is_reverse_complemented,
+        range,
+    );
+}
+
 // TESTING
 
 #[cfg(test)]
@@ -303,8 +635,9 @@ mod tests {
          * | S10       |        150 |             130 |
          * --------------------------------------------
          *
-         * Position 100 (starting at 1) is where we would like to split. At position 100
-         * in the read, the reference is 10bp behind, i.e. 90bp along the reference.
+         * Position 101 (starting at 1) is the last base of the left piece. At position
+         * 101 in the read, the reference is 10bp behind, i.e. 91bp along the reference,
+         * which is position 1000, the end of the reference.
          */
         let cigar: RecordBufCigar = [
             Op::new(Kind::SoftClip, 20),
@@ -322,7 +655,7 @@ mod tests {
         let quality_scores = b"0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDE!FGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@AB".to_vec();
 
         // Generate the sequence string. In this case our string is just ACGT repeated.
-        // "N" marks position 100--where we are going to cut the read.
+        // "N" marks position 101, at the end of the reference--where we are going to cut the read.
         let sequence = b"ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTNACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTA";
 
         // where in the reference that the read starts. See note about cigar string above.
@@ -348,7 +681,7 @@ mod tests {
             .set_sequence(RecordBufSequence::from(sequence))
             .build();
 
-        let read_type = convert_read(&sam_record, &header, REF_LEN);
+        let read_type = convert_read(&sam_record, &header, REF_LEN, &PerBaseTags::default());
         let result = match read_type {
             SplitType::Unchanged => false,
             SplitType::Modified(_) => false,
@@ -356,24 +689,24 @@ mod tests {
                 // Check the parameters of the left and right reads.
                 assert!(
                     left_read.sequence().as_ref()
-                        == b"ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT",
+                        == b"ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTN",
                         "left_read sequence mismatch, sequence={:?}, len={}", left_read.sequence().as_ref(), left_read.sequence().len()
                 );
                 assert!(
                     right_read.sequence().as_ref()
-                        == b"NACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTA",
+                        == b"ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTA",
                     "right_read sequence mismatch, sequence={:?}, len={}",
                     right_read.sequence().as_ref(),
                     right_read.sequence().len()
                 );
                 assert!(
-                    left_read.quality_scores().as_ref() == b"0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDE",
+                    left_read.quality_scores().as_ref() == b"0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@ABCDE!",
                     "left_read quality mismatch, quality scores={:?}", 
                     left_read.quality_scores().as_ref()
                 );
                 assert!(
                     right_read.quality_scores().as_ref()
-                        == b"!FGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@AB",
+                        == b"FGHI0123456789:;<=>?@ABCDEFGHI0123456789:;<=>?@AB",
                     "right_read quality mismatch, quality scores={:?}",
                     right_read.quality_scores().as_ref()
                 );
@@ -389,6 +722,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_split_at_reflen() -> io::Result<()> {
+        const SQ0_LN: NonZeroUsize = match NonZeroUsize::new(131072) {
+            Some(n) => n,
+            None => unreachable!(),
+        };
+
+        let header = noodles::sam::Header::builder()
+            .set_header(Default::default())
+            .add_reference_sequence("sq0", Map::<ReferenceSequence>::new(SQ0_LN))
+            .build();
+
+        let record = |start: usize| {
+            RecordBuf::builder()
+                .set_alignment_start(Position::new(start).unwrap())
+                .set_reference_sequence_id(0)
+                .set_mapping_quality(MappingQuality::MIN)
+                .set_name(b"Read1".as_bstr())
+                .set_quality_scores(RecordBufQS::from(b"ABCD".to_vec()))
+                .set_cigar([Op::new(Kind::Match, 4)].into_iter().collect())
+                .set_sequence(RecordBufSequence::from(b"ACGT".to_vec()))
+                .build()
+        };
+
+        // A read starting at the last position of the reference keeps its
+        // first base there, and the rest starts at 1.
+        match convert_read(&record(REF_LEN), &header, REF_LEN, &PerBaseTags::default()) {
+            SplitType::Split(left_read, right_read) => {
+                assert_eq!(left_read.alignment_start(), Position::new(REF_LEN));
+                assert_eq!(left_read.sequence().as_ref(), b"A");
+                assert_eq!(left_read.quality_scores().as_ref(), b"A");
+                assert_eq!(right_read.alignment_start(), Position::new(1));
+                assert_eq!(right_read.sequence().as_ref(), b"CGT");
+                assert_eq!(right_read.quality_scores().as_ref(), b"BCD");
+            }
+            _ => panic!("Read not split!"),
+        }
+
+        // A read starting just past the reference is moved to 1.
+        match convert_read(
+            &record(REF_LEN + 1),
+            &header,
+            REF_LEN,
+            &PerBaseTags::default(),
+        ) {
+            SplitType::Modified(read) => {
+                assert_eq!(read.alignment_start(), Position::new(1));
+            }
+            _ => panic!("Read not moved!"),
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_nosplit_read() -> io::Result<()> {
         const SQ0_LN: NonZeroUsize = match NonZeroUsize::new(131072) {
@@ -461,7 +848,7 @@ mod tests {
             .set_sequence(RecordBufSequence::from(sequence))
             .build();
 
-        let result = convert_read(&sam_record, &header, REF_LEN);
+        let result = convert_read(&sam_record, &header, REF_LEN, &PerBaseTags::default());
         assert!(
             matches!(result, SplitType::Unchanged),
             "Result is not none."
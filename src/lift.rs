@@ -0,0 +1,673 @@
+//! Lifting of VCFs and BEDs between the extended, or a rotated, reference and
+//! the linear reference.
+//!
+//! Variants called on the extended reference past the end of the linear
+//! reference are the same positions as the ones at its start, so they are
+//! folded back and merged with them. A rotated reference starts part way
+//! along the linear reference, and its positions are shifted back the same
+//! way. Intervals are folded the same way, and cut where they go around the
+//! origin, or unfolded onto every copy of the linear reference.
+
+use crate::fold_position;
+use bstr::BString;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+/// How positions on the reference the input is on map to the linear
+/// reference.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lift {
+    /// The length of the linear reference.
+    pub reflen: usize,
+    /// The number of bases of the linear reference before the start of the
+    /// rotated reference, 0 for the extended reference.
+    pub offset: usize,
+}
+
+impl Lift {
+    /// Returns the 1-based position on the linear reference of the 1-based
+    /// `position`.
+    pub fn fold(&self, position: usize) -> usize {
+        self.fold_start(position - 1) + 1
+    }
+
+    /// Returns the 0-based, half-open intervals on the linear reference
+    /// covered by `interval`. An interval going around the origin is cut in
+    /// two there.
+    pub fn fold_interval(&self, interval: Range<usize>) -> Vec<Range<usize>> {
+        // An interval as long as the reference covers all of it.
+        let (start, end) = if interval.len() >= self.reflen {
+            (0, self.reflen)
+        } else {
+            let start = self.fold_start(interval.start);
+            (start, start + interval.len())
+        };
+
+        let mut intervals = Vec::with_capacity(2);
+        intervals.push(start..end.min(self.reflen));
+
+        if end > self.reflen {
+            intervals.push(0..end - self.reflen);
+        }
+
+        intervals
+    }
+
+    /// Returns every 0-based, half-open interval on a reference of length
+    /// `extended_len` that folds onto `interval` on the linear reference.
+    ///
+    /// On the extended reference an interval at the start of the linear
+    /// reference is also in the extension, and the copies are cut at the
+    /// ends of the extended reference.
+    pub fn unfold_interval(
+        &self,
+        interval: Range<usize>,
+        extended_len: usize,
+    ) -> Vec<Range<usize>> {
+        let len = interval.len().min(self.reflen);
+        let start = fold_position(
+            fold_position(interval.start, self.reflen) + self.reflen - self.offset,
+            self.reflen,
+        );
+
+        // Begin a copy before the start, for the part of an interval going
+        // around the origin that lands at the start of the reference.
+        (0..)
+            .map(|k| (start + k * self.reflen).checked_sub(self.reflen))
+            .map(|copy_start| match copy_start {
+                Some(copy_start) => copy_start..copy_start + len,
+                None => 0..(start + len).saturating_sub(self.reflen),
+            })
+            .take_while(|copy| copy.start < extended_len)
+            .map(|copy| copy.start..copy.end.min(extended_len))
+            .filter(|copy| !copy.is_empty())
+            .collect()
+    }
+
+    // Returns the 0-based position on the linear reference of the 0-based
+    // `start`.
+    fn fold_start(&self, start: usize) -> usize {
+        fold_position(start + self.offset, self.reflen)
+    }
+}
+
+/// How the depths of the records for the same variant on both copies of the
+/// extended reference are combined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DepthPolicy {
+    /// The copies saw different reads, so their depths add up.
+    Sum,
+    /// The copies saw the same reads, so the larger depth is kept.
+    Max,
+}
+
+/// Options for lifting a VCF.
+#[derive(Clone, Debug)]
+pub struct LiftVcfOptions {
+    /// The name of the reference the variants were called on.
+    pub refname: BString,
+    /// The name of the linear reference in the output.
+    pub target_refname: BString,
+    pub lift: Lift,
+    pub policy: DepthPolicy,
+}
+
+// The INFO and FORMAT fields combined by the `DepthPolicy` when records are
+// merged. Every other field is taken from the record with the higher QUAL.
+const DEPTH_FIELDS: [&str; 2] = ["DP", "AD"];
+
+// The columns of a VCF record.
+const CHROM: usize = 0;
+const POS: usize = 1;
+const REF: usize = 3;
+const ALT: usize = 4;
+const QUAL: usize = 5;
+const INFO: usize = 7;
+const FORMAT: usize = 8;
+
+// Records are sorted by contig, in the order of the header, then position,
+// alleles, and the order they were read in. The records of the lifted
+// reference all have order 0, so the records of the same variant are merged.
+type SortKey = (usize, usize, String, String, usize);
+
+/// Lifts the records of `refname` in a VCF to `target_refname`, merging the
+/// records of the same variant and sorting the output.
+///
+/// The `##contig` line of `refname` is replaced with one for the linear
+/// reference. Records of the other contigs are kept, sorted by position.
+/// All the records are held in memory.
+pub fn lift_vcf<R, W>(reader: R, writer: &mut W, options: &LiftVcfOptions) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let refname = options.refname.to_string();
+    let target_refname = options.target_refname.to_string();
+    let reflen = options.lift.reflen;
+
+    let mut contigs: Vec<String> = Vec::new();
+    let mut records: BTreeMap<SortKey, Vec<String>> = BTreeMap::new();
+    let mut merged = 0;
+    let mut spanning = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if let Some(id) = line.strip_prefix("##contig=<").and_then(contig_id) {
+            let id = if id == refname { &target_refname } else { id };
+
+            // With both references in the header, the linear one is kept once.
+            if !contigs.iter().any(|contig| contig == id) {
+                contigs.push(id.to_string());
+
+                if id == target_refname {
+                    writeln!(
+                        writer,
+                        "##contig=<ID={target_refname},length={reflen},topology=\"circular\">"
+                    )?;
+                } else {
+                    writeln!(writer, "{line}")?;
+                }
+            }
+
+            continue;
+        }
+
+        if line.starts_with('#') {
+            writeln!(writer, "{line}")?;
+            continue;
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields: Vec<String> = line.split('\t').map(String::from).collect();
+
+        if fields.len() < 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("VCF line {}: expected at least 8 fields", i + 1),
+            ));
+        }
+
+        let position: usize = fields[POS]
+            .parse()
+            .ok()
+            .filter(|&position| position > 0)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("VCF line {}: invalid position {}", i + 1, fields[POS]),
+                )
+            })?;
+
+        let is_lifted = fields[CHROM] == refname;
+
+        let (position, order) = if is_lifted {
+            let position = options.lift.fold(position);
+
+            if position + fields[REF].len() - 1 > reflen {
+                spanning += 1;
+            }
+
+            fields[CHROM] = target_refname.clone();
+            fields[POS] = position.to_string();
+
+            (position, 0)
+        } else {
+            (position, i + 1)
+        };
+
+        let rank = match contigs.iter().position(|contig| *contig == fields[CHROM]) {
+            Some(rank) => rank,
+            None => {
+                contigs.push(fields[CHROM].clone());
+                contigs.len() - 1
+            }
+        };
+
+        let key = (
+            rank,
+            position,
+            fields[REF].clone(),
+            fields[ALT].clone(),
+            order,
+        );
+
+        match records.get_mut(&key) {
+            Some(record) => {
+                merge_records(record, fields, options.policy);
+                merged += 1;
+            }
+            None => {
+                records.insert(key, fields);
+            }
+        }
+    }
+
+    for fields in records.values() {
+        writeln!(writer, "{}", fields.join("\t"))?;
+    }
+
+    if merged > 0 {
+        log::info!("Merged {merged} records of the same variant on both copies.");
+    }
+
+    if spanning > 0 {
+        log::warn!("{spanning} records have a reference allele going around the origin.");
+    }
+
+    Ok(())
+}
+
+/// Which way intervals are lifted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LiftDirection {
+    /// From the extended, or rotated, reference to the linear reference.
+    Fold,
+    /// From the linear reference to an extended, or rotated, reference of
+    /// this length.
+    Unfold { extended_len: usize },
+}
+
+/// Options for lifting a BED.
+#[derive(Clone, Debug)]
+pub struct LiftBedOptions {
+    /// The name of the extended, or rotated, reference.
+    pub refname: BString,
+    /// The name of the linear reference.
+    pub target_refname: BString,
+    pub lift: Lift,
+    pub direction: LiftDirection,
+}
+
+/// Lifts the intervals of a BED between `refname` and `target_refname`, in
+/// the order they are read.
+///
+/// Only the reference name, start and end are changed. A row cut into more
+/// than one interval is written once for each, with the rest of its columns
+/// repeated, so the thick and block columns of BED12 aren't lifted. Rows on
+/// other references, and header lines, are kept.
+pub fn lift_bed<R, W>(reader: R, writer: &mut W, options: &LiftBedOptions) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let (from, to) = match options.direction {
+        LiftDirection::Fold => (&options.refname, &options.target_refname),
+        LiftDirection::Unfold { .. } => (&options.target_refname, &options.refname),
+    };
+
+    let from = from.to_string();
+    let to = to.to_string();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+
+        if line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+            || fields[0] != from
+        {
+            writeln!(writer, "{line}")?;
+            continue;
+        }
+
+        let interval = match fields[..] {
+            [_, start, end, ..] => start
+                .parse::<usize>()
+                .ok()
+                .zip(end.parse::<usize>().ok())
+                .map(|(start, end)| start..end)
+                .filter(|interval| interval.start <= interval.end),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("BED line {}: expected a start and an end", i + 1),
+            )
+        })?;
+
+        let intervals = match options.direction {
+            LiftDirection::Fold => options.lift.fold_interval(interval),
+            LiftDirection::Unfold { extended_len } => {
+                options.lift.unfold_interval(interval, extended_len)
+            }
+        };
+
+        for interval in intervals {
+            write!(writer, "{to}\t{}\t{}", interval.start, interval.end)?;
+
+            for field in &fields[3..] {
+                write!(writer, "\t{field}")?;
+            }
+
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the ID of a `##contig` header line, without the leading `##contig=<`.
+fn contig_id(s: &str) -> Option<&str> {
+    s.trim_end_matches('>')
+        .split(',')
+        .find_map(|field| field.strip_prefix("ID="))
+}
+
+// Merges `other` into `record`, two records of the same variant. The columns
+// come from the one with the higher QUAL, and the depths of both are combined.
+fn merge_records(record: &mut Vec<String>, mut other: Vec<String>, policy: DepthPolicy) {
+    let quality = |fields: &[String]| fields[QUAL].parse().unwrap_or(f64::NEG_INFINITY);
+
+    if quality(&other) > quality(record) {
+        std::mem::swap(record, &mut other);
+    }
+
+    record[INFO] = merge_info(&record[INFO], &other[INFO], policy);
+
+    let (Some(format), Some(other_format)) = (record.get(FORMAT), other.get(FORMAT)) else {
+        return;
+    };
+
+    let keys: Vec<&str> = format.split(':').collect();
+    let other_keys: Vec<&str> = other_format.split(':').collect();
+
+    let samples: Vec<String> = record
+        .iter()
+        .enumerate()
+        .skip(FORMAT + 1)
+        .map(|(j, sample)| {
+            let other_values: Vec<&str> = match other.get(j) {
+                Some(other_sample) => other_sample.split(':').collect(),
+                None => Vec::new(),
+            };
+
+            sample
+                .split(':')
+                .zip(&keys)
+                .map(|(value, key)| {
+                    let other_value = other_keys
+                        .iter()
+                        .position(|other_key| other_key == key)
+                        .and_then(|k| other_values.get(k));
+
+                    match other_value {
+                        Some(other_value) if DEPTH_FIELDS.contains(key) => {
+                            combine(value, other_value, policy)
+                        }
+                        _ => value.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+        .collect();
+
+    record.truncate(FORMAT + 1);
+    record.extend(samples);
+}
+
+// Combines the depth fields of two INFO columns.
+fn merge_info(info: &str, other_info: &str, policy: DepthPolicy) -> String {
+    info.split(';')
+        .map(|field| {
+            let Some((key, value)) = field.split_once('=') else {
+                return field.to_string();
+            };
+
+            let other_value = other_info
+                .split(';')
+                .find_map(|other_field| other_field.strip_prefix(key)?.strip_prefix('='));
+
+            match other_value {
+                Some(other_value) if DEPTH_FIELDS.contains(&key) => {
+                    format!("{key}={}", combine(value, other_value, policy))
+                }
+                _ => field.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+// Combines two lists of counts, element by element. A missing value takes
+// the other, and values that aren't counts are kept as in `value`.
+fn combine(value: &str, other_value: &str, policy: DepthPolicy) -> String {
+    if value == "." {
+        return other_value.to_string();
+    }
+
+    let parse = |s: &str| -> Option<Vec<u64>> { s.split(',').map(|n| n.parse().ok()).collect() };
+
+    match (parse(value), parse(other_value)) {
+        (Some(counts), Some(other_counts)) if counts.len() == other_counts.len() => counts
+            .iter()
+            .zip(&other_counts)
+            .map(|(&a, &b)| match policy {
+                DepthPolicy::Sum => a + b,
+                DepthPolicy::Max => a.max(b),
+            })
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VCF: &str = "##fileformat=VCFv4.2
+##contig=<ID=chr1,length=1000>
+##contig=<ID=chrM_ext,length=30>
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1
+chrM_ext\t23\t.\tA\tG\t30\tPASS\tDP=10;AF=0.5\tGT:AD:DP\t0/1:5,5:10
+chr1\t50\t.\tC\tT\t50\tPASS\tDP=8\tGT:AD:DP\t0/1:4,4:8
+chrM_ext\t3\t.\tA\tG\t40\tPASS\tDP=12;AF=0.25\tGT:AD:DP\t0/1:9,3:12
+chrM_ext\t10\t.\tC\tCT\t20\tPASS\tDP=7\tGT:AD:DP\t0/1:6,1:7
+";
+
+    fn lift(offset: usize, policy: DepthPolicy) -> io::Result<String> {
+        let options = LiftVcfOptions {
+            refname: "chrM_ext".into(),
+            target_refname: "chrM".into(),
+            lift: Lift { reflen: 20, offset },
+            policy,
+        };
+
+        let mut output = Vec::new();
+        lift_vcf(VCF.as_bytes(), &mut output, &options)?;
+
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_lift_vcf() -> io::Result<()> {
+        let rotated = Lift {
+            reflen: 20,
+            offset: 5,
+        };
+        assert_eq!(rotated.fold(1), 6);
+        assert_eq!(rotated.fold(15), 20);
+        assert_eq!(rotated.fold(16), 1);
+
+        // 23 on the extended reference is 3, so the two records are merged,
+        // keeping the columns of the one with the higher QUAL.
+        let output = lift(0, DepthPolicy::Sum)?;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[2],
+            "##contig=<ID=chrM,length=20,topology=\"circular\">"
+        );
+        assert_eq!(
+            &lines[4..],
+            [
+                "chr1\t50\t.\tC\tT\t50\tPASS\tDP=8\tGT:AD:DP\t0/1:4,4:8",
+                "chrM\t3\t.\tA\tG\t40\tPASS\tDP=22;AF=0.25\tGT:AD:DP\t0/1:14,8:22",
+                "chrM\t10\t.\tC\tCT\t20\tPASS\tDP=7\tGT:AD:DP\t0/1:6,1:7",
+            ]
+        );
+
+        let output = lift(0, DepthPolicy::Max)?;
+        assert!(
+            output.contains("chrM\t3\t.\tA\tG\t40\tPASS\tDP=12;AF=0.25\tGT:AD:DP\t0/1:9,5:12\n")
+        );
+
+        // On a reference rotated by 15, 10 is 5, and 3 and 23 are both 18.
+        let output = lift(15, DepthPolicy::Sum)?;
+        let lines: Vec<&str> = output.lines().skip(5).collect();
+        assert_eq!(
+            lines,
+            [
+                "chrM\t5\t.\tC\tCT\t20\tPASS\tDP=7\tGT:AD:DP\t0/1:6,1:7",
+                "chrM\t18\t.\tA\tG\t40\tPASS\tDP=22;AF=0.25\tGT:AD:DP\t0/1:14,8:22",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lift_bed() -> io::Result<()> {
+        const BED: &str = "track name=masks
+chrM_ext\t18\t23\tacross\t0\t+
+chrM_ext\t22\t25\tcopy
+chrM\t1\t3\tstart
+chr1\t5\t10\tother
+";
+
+        let rotated = Lift {
+            reflen: 20,
+            offset: 15,
+        };
+        let spans = |intervals: Vec<Range<usize>>| -> Vec<(usize, usize)> {
+            intervals
+                .into_iter()
+                .map(|interval| (interval.start, interval.end))
+                .collect()
+        };
+        assert_eq!(spans(rotated.fold_interval(2..4)), [(17, 19)]);
+        assert_eq!(spans(rotated.fold_interval(3..7)), [(18, 20), (0, 2)]);
+        assert_eq!(spans(rotated.fold_interval(0..25)), [(0, 20)]);
+        assert_eq!(
+            spans(rotated.unfold_interval(13..17, 20)),
+            [(0, 2), (18, 20)]
+        );
+        assert_eq!(spans(rotated.unfold_interval(0..2, 20)), [(5, 7)]);
+
+        let mut options = LiftBedOptions {
+            refname: "chrM_ext".into(),
+            target_refname: "chrM".into(),
+            lift: Lift {
+                reflen: 20,
+                offset: 0,
+            },
+            direction: LiftDirection::Fold,
+        };
+
+        let mut output = Vec::new();
+        lift_bed(BED.as_bytes(), &mut output, &options)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "track name=masks
+chrM\t18\t20\tacross\t0\t+
+chrM\t0\t3\tacross\t0\t+
+chrM\t2\t5\tcopy
+chrM\t1\t3\tstart
+chr1\t5\t10\tother
+"
+        );
+
+        // The start of the linear reference is also at 20-30 on the extended
+        // reference.
+        options.direction = LiftDirection::Unfold { extended_len: 30 };
+
+        let mut output = Vec::new();
+        lift_bed(BED.as_bytes(), &mut output, &options)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "track name=masks
+chrM_ext\t18\t23\tacross\t0\t+
+chrM_ext\t22\t25\tcopy
+chrM_ext\t1\t3\tstart
+chrM_ext\t21\t23\tstart
+chr1\t5\t10\tother
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lift_bed_agrees_with_conversion() -> io::Result<()> {
+        use crate::{convert_read, PerBaseTags, SplitType};
+        use noodles::{
+            core::Position,
+            sam::{
+                self,
+                alignment::{
+                    record::{
+                        cigar::{op::Kind, Op},
+                        Flags,
+                    },
+                    record_buf::{QualityScores, Sequence},
+                    RecordBuf,
+                },
+            },
+        };
+
+        // A read over 15-25 on the extended reference, and the same interval
+        // as a BED.
+        let read = RecordBuf::builder()
+            .set_name("r1")
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(16).unwrap())
+            .set_cigar([Op::new(Kind::Match, 10)].into_iter().collect())
+            .set_sequence(Sequence::from(b"ACGTACGTAC".to_vec()))
+            .set_quality_scores(QualityScores::from(vec![30; 10]))
+            .build();
+
+        let SplitType::Split(left_read, right_read) =
+            convert_read(&read, &sam::Header::default(), 20, &PerBaseTags::default())
+        else {
+            panic!("the read isn't split");
+        };
+
+        let piece = |read: &RecordBuf| {
+            let start = read.alignment_start().unwrap().get() - 1;
+            format!(
+                "chrM\t{}\t{}\n",
+                start,
+                start + read.cigar().alignment_span()
+            )
+        };
+
+        let options = LiftBedOptions {
+            refname: "chrM_ext".into(),
+            target_refname: "chrM".into(),
+            lift: Lift {
+                reflen: 20,
+                offset: 0,
+            },
+            direction: LiftDirection::Fold,
+        };
+
+        let mut output = Vec::new();
+        lift_bed(&b"chrM_ext\t15\t25\n"[..], &mut output, &options)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            piece(&left_read) + &piece(&right_read)
+        );
+
+        Ok(())
+    }
+}
@@ -5,13 +5,30 @@
 //! aligned reads back to an alignment of a circular mtDNA reference.
 
 use bstr::BString;
-use clap::{value_parser, Arg, Command};
-use mt_lintocirc::convert_sam;
-use noodles::sam::alignment::Record;
-use noodles_util::alignment::io::reader::Builder;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use mt_lintocirc::{
+    build_consensus, build_repository, call_variants, cohort_sites, compute_cell_counts,
+    compute_depth, compute_pileup, convert_bam_passthrough, convert_paf, convert_region,
+    convert_sam, detect_deletions, estimate_copy_number, lift_bed, lift_vcf, parse_per_base_tag,
+    parse_tag, phase_sam, read_bed, read_manifest, read_phase_sites, rescue_sam, validate,
+    write_bedpe, write_deletions_vcf, write_vcf, Allele, CallOptions, CellOptions, CohortOutputs,
+    ConsensusOptions, ConversionStats, ConvertOptions, CopyNumberOptions, CountsSource,
+    DeletionOptions, DepthOptions, DepthPolicy, JunctionReads, JunctionReport, Lift,
+    LiftBedOptions, LiftDirection, LiftVcfOptions, NumtOptions, PerBaseLayout, PerBaseTags,
+    PhaseOptions, RescueOptions, SampleCounts, SideOutputs,
+};
+use noodles::{
+    bgzf,
+    core::Region,
+    fasta,
+    sam::alignment::{record::data::field::Tag, Record},
+};
+use noodles_util::alignment::io::{indexed_reader, reader::Builder, Reader};
 use std::{
+    collections::HashSet,
     fs::File,
-    io::{self, BufWriter, Write},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 fn main() -> io::Result<()> {
@@ -32,7 +49,7 @@ fn main() -> io::Result<()> {
             )
             .arg(
                 Arg::new("alignmentfile")
-                    .help("The input file to process")
+                    .help("The input file to process, or - to read from stdin")
                     .required(true) // Set to false to manually handle missing arguments
                     .index(1),
             ).arg(
@@ -56,23 +73,1082 @@ fn main() -> io::Result<()> {
                 .required(false)
                 .default_value("chrM")
                 .help("target reference sequence name")
+            ).arg(
+                Arg::new("reference")
+                .long("reference")
+                .required(false)
+                .help("reference FASTA, needed to decode CRAM input")
+            ).arg(
+                Arg::new("per-base-tag")
+                .long("per-base-tag")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(parse_per_base_tag)
+                .help("additional per-base B array tag to split with the sequence, as TAG[:forward|reverse]")
+            ).arg(
+                Arg::new("region")
+                .long("region")
+                .required(false)
+                .value_parser(|s: &str| s.parse::<Region>().map_err(|e| e.to_string()))
+                .conflicts_with("mito-only")
+                .help("only convert the records in this region, using the index of the input")
+            ).arg(
+                Arg::new("mito-only")
+                .long("mito-only")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("only convert the records on the extended reference, using the index of the input")
+            ).arg(
+                Arg::new("include-unmapped")
+                .long("include-unmapped")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("with --region or --mito-only, also output the unplaced unmapped reads")
+            ).arg(
+                Arg::new("passthrough-rest")
+                .long("passthrough-rest")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["region", "mito-only", "include-unmapped"])
+                .help("copy the records on other references through without decoding them; needs an indexed BAM and writes BAM")
+            ).arg(
+                Arg::new("stats")
+                .long("stats")
+                .required(false)
+                .help("write conversion statistics to this file, as TSV if it ends in .tsv, otherwise JSON")
+            ).arg(
+                Arg::new("junctions")
+                .long("junctions")
+                .required(false)
+                .help("write a TSV row for each split or shifted read to this file; mismatches need --reference")
+            ).arg(
+                Arg::new("extract-junction")
+                .long("extract-junction")
+                .required(false)
+                .help("write the original reads that are split or soft clipped near the origin to this FASTQ, gzipped if it ends in .gz")
+            ).arg(
+                Arg::new("extract-junction-window")
+                .long("extract-junction-window")
+                .required(false)
+                .default_value("10")
+                .value_parser(value_parser!(usize))
+                .help("with --extract-junction, also write reads soft clipped within this many bases of position 1 or the reference length")
+            ).arg(
+                Arg::new("numt")
+                .long("numt")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("tag reads likely from nuclear mitochondrial segments (NUMTs) with NU:i:1")
+            ).arg(
+                Arg::new("numt-filter")
+                .long("numt-filter")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("leave reads likely from NUMTs out of the output, implies --numt")
+            ).arg(
+                Arg::new("numt-max-nm-diff")
+                .long("numt-max-nm-diff")
+                .required(false)
+                .default_value("1")
+                .value_parser(value_parser!(i64))
+                .help("flag a read with an SA or XA alignment to a nuclear contig with at most this many more edits, scaled to the aligned length of the read")
+            ).arg(
+                Arg::new("numt-max-mismatch-density")
+                .long("numt-max-mismatch-density")
+                .required(false)
+                .default_value("0.02")
+                .value_parser(value_parser!(f64))
+                .help("flag a read with more edits (NM) per aligned reference base")
+            ).arg(
+                Arg::new("left-align")
+                .long("left-align")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .requires("reference")
+                .help("left-align indels against the reference FASTA, keeping them clear of the origin")
+            )
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+            .subcommand(
+                Command::new("validate")
+                .about("Checks that a converted file is self-consistent, and optionally matches the original reads.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted file to check, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("original")
+                    .long("original")
+                    .required(false)
+                    .help("the unconverted input, to check the pieces of each read add up to the original sequence")
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output JSON report, default is stdout")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("max-reported")
+                    .long("max-reported")
+                    .required(false)
+                    .default_value("100")
+                    .value_parser(value_parser!(usize))
+                    .help("maximum number of violations to list in the report, all are counted")
+                )
+            )
+            .subcommand(
+                Command::new("depth")
+                .about("Computes the per-base depth on the circular reference, folding the alignments at the origin.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted (or extended reference) file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output bedGraph, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to compute the depth of, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 0")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 0")
+                ).arg(
+                    Arg::new("per-base")
+                    .long("per-base")
+                    .required(false)
+                    .help("write the depth of every position, with forward and reverse strand counts, to this TSV")
+                ).arg(
+                    Arg::new("summary")
+                    .long("summary")
+                    .required(false)
+                    .help("write a mosdepth-style summary to this file")
+                ).arg(
+                    Arg::new("windows")
+                    .long("windows")
+                    .required(false)
+                    .help("write the mean depth of windows to this BED")
+                ).arg(
+                    Arg::new("window-size")
+                    .long("window-size")
+                    .required(false)
+                    .default_value("500")
+                    .value_parser(value_parser!(usize))
+                    .help("size of the windows, default is 500")
+                )
+            )
+            .subcommand(
+                Command::new("pileup")
+                .about("Counts the alleles at each position of the circular reference, folding the alignments at the origin.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted (or extended reference) file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output TSV, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to count the alleles of, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 0")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 0")
+                ).arg(
+                    Arg::new("sparse")
+                    .long("sparse")
+                    .required(false)
+                    .help("also write mgatk-style sparse matrices to PREFIX.{A,C,G,T}.txt and PREFIX.coverage.txt")
+                ).arg(
+                    Arg::new("sample")
+                    .long("sample")
+                    .required(false)
+                    .help("sample name in the sparse matrices, default is the input file name")
+                )
+            )
+            .subcommand(
+                Command::new("cells")
+                .about("Counts the bases of each cell barcode on the circular reference as mgatk-style sparse matrices.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted (or extended reference) file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(true)
+                    .help("write PREFIX.{A,C,G,T}.txt, PREFIX.coverage.txt and PREFIX.depthTable.txt")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to count the bases of, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 0")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 0")
+                ).arg(
+                    Arg::new("barcode-tag")
+                    .long("barcode-tag")
+                    .required(false)
+                    .default_value("CB")
+                    .value_parser(parse_tag)
+                    .help("tag with the cell barcode, default is CB")
+                ).arg(
+                    Arg::new("umi-tag")
+                    .long("umi-tag")
+                    .required(false)
+                    .default_value("UB")
+                    .value_parser(parse_tag)
+                    .help("tag with the UMI, default is UB; reads without it are deduplicated by position")
+                ).arg(
+                    Arg::new("whitelist")
+                    .long("whitelist")
+                    .required(false)
+                    .help("file of the barcodes to count, one per line, default is all barcodes")
+                )
+            )
+            .subcommand(
+                Command::new("call")
+                .about("Calls low-frequency SNVs and short indels on the circular reference to a VCF.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output VCF, default is stdout")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(true)
+                    .help("reference FASTA with the linear target reference")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 20")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("13")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 13")
+                ).arg(
+                    Arg::new("min-depth")
+                    .long("min-depth")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(u32))
+                    .help("minimum depth of a site, default is 10")
+                ).arg(
+                    Arg::new("min-af")
+                    .long("min-af")
+                    .required(false)
+                    .default_value("0.01")
+                    .value_parser(value_parser!(f64))
+                    .help("minimum allele fraction, default is 0.01")
+                ).arg(
+                    Arg::new("max-pvalue")
+                    .long("max-pvalue")
+                    .required(false)
+                    .default_value("1e-6")
+                    .value_parser(value_parser!(f64))
+                    .help("maximum p-value of an allele under the error model, default is 1e-6")
+                ).arg(
+                    Arg::new("overdispersion")
+                    .long("overdispersion")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(f64))
+                    .help("overdispersion of a beta-binomial error model, 0 for the binomial model, default is 0")
+                )
+            )
+            .subcommand(
+                Command::new("cohort")
+                .about("Calls many samples jointly into a sites-by-samples matrix and a multi-sample VCF.")
+                .arg(
+                    Arg::new("manifest")
+                    .help("TSV of sample IDs and their alignment files")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(true)
+                    .help("write the matrix to PREFIX.tsv and the VCF to PREFIX.vcf")
+                ).arg(
+                    Arg::new("work-dir")
+                    .long("work-dir")
+                    .required(false)
+                    .help("directory of each sample's counts, which are reused on a rerun, default is PREFIX.work")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(true)
+                    .help("reference FASTA with the linear target reference")
+                ).arg(
+                    Arg::new("targetref")
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .help("name of the reference in the alignment files, e.g. the extended one, default is the target reference")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 20")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("13")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 13")
+                ).arg(
+                    Arg::new("min-depth")
+                    .long("min-depth")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(u32))
+                    .help("minimum depth of a site, default is 10")
+                ).arg(
+                    Arg::new("min-af")
+                    .long("min-af")
+                    .required(false)
+                    .default_value("0.01")
+                    .value_parser(value_parser!(f64))
+                    .help("minimum allele fraction, default is 0.01")
+                ).arg(
+                    Arg::new("max-pvalue")
+                    .long("max-pvalue")
+                    .required(false)
+                    .default_value("1e-6")
+                    .value_parser(value_parser!(f64))
+                    .help("maximum p-value of an allele under the error model, default is 1e-6")
+                ).arg(
+                    Arg::new("overdispersion")
+                    .long("overdispersion")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(f64))
+                    .help("overdispersion of a beta-binomial error model, 0 for the binomial model, default is 0")
+                )
+            )
+            .subcommand(
+                Command::new("deletions")
+                .about("Finds large deletions from long D ops and split alignments on the circular reference.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted (or extended reference) file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output BEDPE, or VCF if it ends in .vcf, default is BEDPE to stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to find deletions on, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("extended-ref")
+                    .long("extended-ref")
+                    .required(false)
+                    .help("name of the extended reference, which SA tags of a converted file still refer to")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, to find the repeats flanking the deletions and to decode CRAM input")
+                ).arg(
+                    Arg::new("min-len")
+                    .long("min-len")
+                    .required(false)
+                    .default_value("50")
+                    .value_parser(value_parser!(usize))
+                    .help("minimum length of a deletion, default is 50")
+                ).arg(
+                    Arg::new("min-reads")
+                    .long("min-reads")
+                    .required(false)
+                    .default_value("2")
+                    .value_parser(value_parser!(usize))
+                    .help("minimum number of supporting reads, default is 2")
+                ).arg(
+                    Arg::new("max-len")
+                    .long("max-len")
+                    .required(false)
+                    .default_value("15000")
+                    .value_parser(value_parser!(usize))
+                    .help("maximum length of a deletion between split alignments, longer ones are more likely a duplication, default is 15000")
+                ).arg(
+                    Arg::new("max-distance")
+                    .long("max-distance")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(usize))
+                    .help("maximum distance between breakpoints of the same deletion, default is 10")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 0")
+                )
+            )
+            .subcommand(
+                Command::new("consensus")
+                .about("Builds the sample's consensus sequence of the circular reference as FASTA.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output FASTA, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to build the consensus of, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("name")
+                    .long("name")
+                    .required(false)
+                    .help("name of the FASTA record, default is the input file name")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 20")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("13")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 13")
+                ).arg(
+                    Arg::new("min-depth")
+                    .long("min-depth")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(u32))
+                    .help("minimum depth of a position, below which it is N, default is 10")
+                ).arg(
+                    Arg::new("min-af")
+                    .long("min-af")
+                    .required(false)
+                    .default_value("0.2")
+                    .value_parser(value_parser!(f64))
+                    .help("minimum fraction of the reads for a base to be part of an IUPAC code, default is 0.2")
+                )
+            )
+            .subcommand(
+                Command::new("mtcn")
+                .about("Estimates the mtDNA copy number of an indexed whole genome alignment.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The indexed alignment file")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output TSV, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the mitochondrial reference, circular or extended, default is chrM")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the linear target reference, for --exclude regions on it, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of the circular reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("sample")
+                    .long("sample")
+                    .required(false)
+                    .help("sample name in the output, default is the input file name")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 20")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 0")
+                ).arg(
+                    Arg::new("autosomes")
+                    .long("autosomes")
+                    .required(false)
+                    .value_delimiter(',')
+                    .help("comma-separated autosomes to sample, default is 1-22 with or without a chr prefix")
+                ).arg(
+                    Arg::new("windows")
+                    .long("windows")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(usize))
+                    .help("number of windows sampled from each autosome, default is 20")
+                ).arg(
+                    Arg::new("window-len")
+                    .long("window-len")
+                    .required(false)
+                    .default_value("10000")
+                    .value_parser(value_parser!(usize))
+                    .help("length of each autosomal window, default is 10000")
+                ).arg(
+                    Arg::new("exclude")
+                    .long("exclude")
+                    .required(false)
+                    .help("BED of regions to leave out, e.g. NUMT-prone regions of chrM or NUMT insertions")
+                ).arg(
+                    Arg::new("numt")
+                    .long("numt")
+                    .required(false)
+                    .action(ArgAction::SetTrue)
+                    .help("don't count mitochondrial reads likely from NUMTs")
+                ).arg(
+                    Arg::new("numt-max-nm-diff")
+                    .long("numt-max-nm-diff")
+                    .required(false)
+                    .default_value("1")
+                    .value_parser(value_parser!(i64))
+                    .help("with --numt, flag a read with an SA or XA alignment to a nuclear contig with at most this many more edits, scaled to the aligned length of the read")
+                ).arg(
+                    Arg::new("numt-max-mismatch-density")
+                    .long("numt-max-mismatch-density")
+                    .required(false)
+                    .default_value("0.02")
+                    .value_parser(value_parser!(f64))
+                    .help("with --numt, flag a read with more edits (NM) per aligned reference base")
+                )
+            )
+            .subcommand(
+                Command::new("phase")
+                .about("Phases heteroplasmic variants into mtDNA haplotypes with long reads, tagging each read with HP:i.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The converted (or extended reference) file, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output SAM file, default is stdout")
+                ).arg(
+                    Arg::new("sites")
+                    .long("sites")
+                    .required(true)
+                    .help("VCF of the heteroplasmic SNVs to phase")
+                ).arg(
+                    Arg::new("haplotypes")
+                    .long("haplotypes")
+                    .required(true)
+                    .help("write the haplotypes and their frequencies to this TSV")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("name of the reference to phase, as in the VCF, default is chrM")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(false)
+                    .help("reference FASTA, needed to decode CRAM input")
+                ).arg(
+                    Arg::new("min-mapq")
+                    .short('Q')
+                    .long("min-mapq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum mapping quality of a read, default is 20")
+                ).arg(
+                    Arg::new("min-baseq")
+                    .short('q')
+                    .long("min-baseq")
+                    .required(false)
+                    .default_value("20")
+                    .value_parser(value_parser!(u8))
+                    .help("minimum base quality, default is 20")
+                ).arg(
+                    Arg::new("min-reads")
+                    .long("min-reads")
+                    .required(false)
+                    .default_value("3")
+                    .value_parser(value_parser!(usize))
+                    .help("minimum number of reads seeding a haplotype, default is 3")
+                ).arg(
+                    Arg::new("min-fraction")
+                    .long("min-fraction")
+                    .required(false)
+                    .default_value("0.01")
+                    .value_parser(value_parser!(f64))
+                    .help("minimum fraction of the seeding reads for a haplotype, default is 0.01")
+                ).arg(
+                    Arg::new("max-mismatch-fraction")
+                    .long("max-mismatch-fraction")
+                    .required(false)
+                    .default_value("0.2")
+                    .value_parser(value_parser!(f64))
+                    .help("maximum fraction of its sites a read may differ from its haplotype at, default is 0.2")
+                )
+            )
+            .subcommand(
+                Command::new("lift-vcf")
+                .about("Lifts a VCF called on the extended, or a rotated, reference back to the linear reference.")
+                .arg(
+                    Arg::new("vcf")
+                    .help("The VCF to lift, BGZF compressed if it ends in .gz, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output VCF, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(true)
+                    .help("name of the reference the variants were called on")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                ).arg(
+                    Arg::new("offset")
+                    .long("offset")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("number of bases of the linear reference before the start of a rotated reference, default is 0")
+                ).arg(
+                    Arg::new("depth-policy")
+                    .long("depth-policy")
+                    .required(false)
+                    .default_value("sum")
+                    .value_parser(["sum", "max"])
+                    .help("combine the DP and AD of the records of the same variant by sum or max, default is sum")
+                )
+            )
+            .subcommand(
+                Command::new("lift-bed")
+                .about("Lifts a BED between the extended, or a rotated, reference and the linear reference.")
+                .arg(
+                    Arg::new("bed")
+                    .help("The BED to lift, BGZF compressed if it ends in .gz, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output BED, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(true)
+                    .help("name of the extended, or rotated, reference")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                ).arg(
+                    Arg::new("offset")
+                    .long("offset")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("number of bases of the linear reference before the start of a rotated reference, default is 0")
+                ).arg(
+                    Arg::new("unfold")
+                    .long("unfold")
+                    .required(false)
+                    .action(ArgAction::SetTrue)
+                    .requires("extended-len")
+                    .help("lift intervals on the linear reference onto the extended reference, instead of folding them back")
+                ).arg(
+                    Arg::new("extended-len")
+                    .long("extended-len")
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .help("with --unfold, the length of the extended reference")
+                )
+            )
+            .subcommand(
+                Command::new("paf")
+                .about("Converts minimap2 PAF aligned to the doubled reference back to the single linear reference.")
+                .arg(
+                    Arg::new("paf")
+                    .help("The PAF to convert, BGZF compressed if it ends in .gz, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output PAF, default is stdout")
+                ).arg(
+                    Arg::new("ref")
+                    .short('r')
+                    .long("ref")
+                    .required(true)
+                    .help("name of doubled mitochondrial reference")
+                ).arg(
+                    Arg::new("reflen")
+                    .short('l')
+                    .long("reflen")
+                    .required(false)
+                    .default_value("16569")
+                    .value_parser(value_parser!(usize))
+                    .help("length of reference, default is 16569")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                )
+            )
+            .subcommand(
+                Command::new("rescue")
+                .about("Splits the reads spanning the origin of a file aligned to the plain linear reference, by aligning their soft clips to the other end.")
+                .arg(
+                    Arg::new("alignmentfile")
+                    .help("The file aligned to the linear reference, or - to read from stdin")
+                    .required(true)
+                    .index(1),
+                ).arg(
+                    Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(false)
+                    .help("output sam file")
+                ).arg(
+                    Arg::new("reference")
+                    .long("reference")
+                    .required(true)
+                    .help("reference FASTA with the linear target reference")
+                ).arg(
+                    Arg::new("targetref")
+                    .short('t')
+                    .long("targetref")
+                    .required(false)
+                    .default_value("chrM")
+                    .help("target reference sequence name, default is chrM")
+                ).arg(
+                    Arg::new("per-base-tag")
+                    .long("per-base-tag")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_delimiter(',')
+                    .value_parser(parse_per_base_tag)
+                    .help("additional per-base B array tag to split with the sequence, as TAG[:forward|reverse]")
+                ).arg(
+                    Arg::new("join-only")
+                    .long("join-only")
+                    .required(false)
+                    .action(ArgAction::SetTrue)
+                    .help("only join primary and supplementary alignments at the two ends, don't align soft clips")
+                ).arg(
+                    Arg::new("min-clip")
+                    .long("min-clip")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(usize))
+                    .help("minimum length of a soft clip to align, default is 10")
+                ).arg(
+                    Arg::new("max-distance")
+                    .long("max-distance")
+                    .required(false)
+                    .default_value("5")
+                    .value_parser(value_parser!(usize))
+                    .help("maximum distance of a clipped or supplementary end from the end of the reference, default is 5")
+                ).arg(
+                    Arg::new("band")
+                    .long("band")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(usize))
+                    .help("band width of the alignment, default is 10")
+                ).arg(
+                    Arg::new("min-score")
+                    .long("min-score")
+                    .required(false)
+                    .default_value("10")
+                    .value_parser(value_parser!(i32))
+                    .help("minimum alignment score of a clip, default is 10")
+                ).arg(
+                    Arg::new("stats")
+                    .long("stats")
+                    .required(false)
+                    .help("write statistics to this file, as TSV if it ends in .tsv, otherwise JSON")
+                )
             )
             .get_matches();
 
+    match matches.subcommand() {
+        Some(("validate", sub_matches)) => return run_validate(sub_matches),
+        Some(("depth", sub_matches)) => return run_depth(sub_matches),
+        Some(("pileup", sub_matches)) => return run_pileup(sub_matches),
+        Some(("cells", sub_matches)) => return run_cells(sub_matches),
+        Some(("call", sub_matches)) => return run_call(sub_matches),
+        Some(("cohort", sub_matches)) => return run_cohort(sub_matches),
+        Some(("deletions", sub_matches)) => return run_deletions(sub_matches),
+        Some(("consensus", sub_matches)) => return run_consensus(sub_matches),
+        Some(("mtcn", sub_matches)) => return run_mtcn(sub_matches),
+        Some(("phase", sub_matches)) => return run_phase(sub_matches),
+        Some(("lift-vcf", sub_matches)) => return run_lift_vcf(sub_matches),
+        Some(("lift-bed", sub_matches)) => return run_lift_bed(sub_matches),
+        Some(("paf", sub_matches)) => return run_paf(sub_matches),
+        Some(("rescue", sub_matches)) => return run_rescue(sub_matches),
+        _ => {}
+    }
+
     if let Some(filename) = matches.get_one::<String>("alignmentfile") {
         log::info!("Processing file: {}", filename);
 
-        let mut reader = Builder::default().build_from_path(filename)?;
-
         // Get the output file name
-        let mut bufwriter: Box<dyn Write> =
-            if let Some(output_filename) = matches.get_one::<String>("output") {
-                let output_file = File::create_new(output_filename)?;
-
-                Box::new(BufWriter::new(output_file))
-            } else {
-                Box::new(BufWriter::new(std::io::stdout().lock()))
-            };
+        let bufwriter = create_output(&matches)?;
 
         // Get the reference name
         let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
@@ -84,15 +1160,944 @@ fn main() -> io::Result<()> {
         // Get the reference name
         let reflen = matches.get_one::<usize>("reflen").unwrap();
 
-        // Process the bam file
-        convert_sam::<Box<dyn Record>>(
+        let per_base_tags = read_per_base_tags(&matches);
+
+        let numt =
+            (matches.get_flag("numt") || matches.get_flag("numt-filter")).then(|| NumtOptions {
+                max_edit_distance_difference: *matches.get_one::<i64>("numt-max-nm-diff").unwrap(),
+                max_mismatch_density: *matches.get_one::<f64>("numt-max-mismatch-density").unwrap(),
+                filter: matches.get_flag("numt-filter"),
+            });
+
+        let repository = read_repository(&matches)?;
+
+        let left_align = matches
+            .get_flag("left-align")
+            .then(|| read_linear_reference(&repository, [&target_refname, &refname], *reflen))
+            .transpose()?;
+
+        let options = ConvertOptions {
+            reflen: *reflen,
+            refname,
+            target_refname,
+            per_base_tags,
+            numt,
+            left_align,
+        };
+
+        let is_stdin = filename == "-";
+
+        // Querying or copying blocks through needs a seekable, indexed file.
+        if is_stdin
+            && (matches.get_flag("passthrough-rest")
+                || matches.get_flag("mito-only")
+                || matches.contains_id("region"))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--region, --mito-only and --passthrough-rest need an indexed file, not stdin",
+            ));
+        }
+
+        let mut outputs = SideOutputs {
+            junctions: matches
+                .get_one::<String>("junctions")
+                .map(|junctions_filename| {
+                    create_junction_report(junctions_filename, &repository, &options)
+                })
+                .transpose()?,
+            junction_reads: matches
+                .get_one::<String>("extract-junction")
+                .map(|fastq_filename| {
+                    let window = *matches.get_one::<usize>("extract-junction-window").unwrap();
+                    create_junction_reads(fastq_filename, window)
+                })
+                .transpose()?,
+        };
+
+        let stats = convert(
+            &matches,
+            filename,
+            bufwriter,
+            repository,
+            &options,
+            &mut outputs,
+        )?;
+
+        outputs.finish()?;
+
+        if let Some(stats_filename) = matches.get_one::<String>("stats") {
+            write_stats(&stats, stats_filename)?;
+        }
+
+        Ok(())
+    } else {
+        Ok(())
+    }
+}
+
+// Runs the conversion in the mode selected on the command line.
+fn convert(
+    matches: &ArgMatches,
+    filename: &str,
+    mut bufwriter: Box<dyn Write>,
+    repository: fasta::Repository,
+    options: &ConvertOptions,
+    outputs: &mut SideOutputs,
+) -> io::Result<ConversionStats> {
+    // Only the extended reference needs converting, copy the rest through.
+    if matches.get_flag("passthrough-rest") {
+        return convert_bam_passthrough(filename, bufwriter, options, outputs);
+    }
+
+    // Query the region from an indexed file rather than reading it all.
+    let region = if matches.get_flag("mito-only") {
+        Some(Region::new(options.refname.clone(), ..))
+    } else {
+        matches.get_one::<Region>("region").cloned()
+    };
+
+    if let Some(region) = region {
+        let mut reader = indexed_reader::Builder::default()
+            .set_reference_sequence_repository(repository)
+            .build_from_path(filename)?;
+
+        return convert_region(
             &mut reader,
-            *reflen,
+            &region,
+            matches.get_flag("include-unmapped"),
             &mut bufwriter,
-            &refname,
-            target_refname,
+            options,
+            outputs,
+        );
+    }
+
+    let mut reader = build_reader(filename, repository)?;
+
+    // Process the bam file
+    convert_sam::<Box<dyn Record>>(&mut reader, &mut bufwriter, options, outputs)
+}
+
+// Reads the linear reference to left-align the indels against. The extended
+// reference begins with the linear reference, so either of them will do.
+fn read_linear_reference(
+    repository: &fasta::Repository,
+    names: [&BString; 2],
+    reflen: usize,
+) -> io::Result<Vec<u8>> {
+    for name in names {
+        if let Some(Ok(sequence)) = repository.get(name) {
+            if sequence.len() >= reflen {
+                return Ok(sequence.as_ref()[..reflen].to_vec());
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "--left-align needs {} or {} of at least {} bases in the reference FASTA",
+            names[0], names[1], reflen
+        ),
+    ))
+}
+
+// Creates the junction report, with the extended reference from the FASTA, if
+// there is one, to count mismatches.
+fn create_junction_report(
+    filename: &str,
+    repository: &fasta::Repository,
+    options: &ConvertOptions,
+) -> io::Result<JunctionReport> {
+    let reference_sequence = match repository.get(&options.refname) {
+        Some(result) => Some(result?.as_ref().to_vec()),
+        None => {
+            log::warn!(
+                "Reference: {} is not in the reference FASTA, mismatches won't be counted.",
+                options.refname
+            );
+            None
+        }
+    };
+
+    let writer = BufWriter::new(File::create(filename)?);
+    JunctionReport::new(Box::new(writer), reference_sequence)
+}
+
+// Creates the FASTQ for the reads around the origin. BGZF is gzip compatible,
+// so a .gz file is written as BGZF.
+fn create_junction_reads(filename: &str, window: usize) -> io::Result<JunctionReads> {
+    let file = File::create(filename)?;
+
+    let writer: Box<dyn Write> = if filename.ends_with(".gz") {
+        Box::new(bgzf::Writer::new(file))
+    } else {
+        Box::new(BufWriter::new(file))
+    };
+
+    Ok(JunctionReads::new(writer, window))
+}
+
+// Writes the conversion statistics, as TSV for a .tsv file, otherwise JSON.
+fn write_stats(stats: &ConversionStats, filename: &str) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+
+    if Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tsv"))
+    {
+        stats.write_tsv(&mut writer)?;
+    } else {
+        stats.write_json(&mut writer)?;
+    }
+
+    writer.flush()
+}
+
+// Opens the output file, or stdout if there isn't one.
+fn create_output(matches: &ArgMatches) -> io::Result<Box<dyn Write>> {
+    if let Some(output_filename) = matches.get_one::<String>("output") {
+        let output_file = File::create_new(output_filename)?;
+
+        Ok(Box::new(BufWriter::new(output_file)))
+    } else {
+        Ok(Box::new(BufWriter::new(std::io::stdout().lock())))
+    }
+}
+
+// Adds any user supplied per-base tags to the known ones.
+fn read_per_base_tags(matches: &ArgMatches) -> PerBaseTags {
+    let mut per_base_tags = PerBaseTags::default();
+
+    for (tag, layout) in matches
+        .get_many::<(Tag, PerBaseLayout)>("per-base-tag")
+        .into_iter()
+        .flatten()
+    {
+        per_base_tags.insert(*tag, *layout);
+    }
+
+    per_base_tags
+}
+
+// Reads the reference, which is only needed to decode CRAM.
+fn read_repository(matches: &ArgMatches) -> io::Result<fasta::Repository> {
+    Ok(matches
+        .get_one::<String>("reference")
+        .map(build_repository)
+        .transpose()?
+        .unwrap_or_default())
+}
+
+// Opens an alignment file, or stdin for `-`. The format and compression of
+// stdin are detected from its magic bytes.
+fn build_reader(
+    filename: &str,
+    repository: fasta::Repository,
+) -> io::Result<Reader<Box<dyn BufRead>>> {
+    let builder = Builder::default().set_reference_sequence_repository(repository);
+
+    if filename == "-" {
+        builder.build_from_reader(io::stdin())
+    } else {
+        builder.build_from_path(filename)
+    }
+}
+
+// Runs the validate subcommand. Exits with a non-zero status if the file has
+// any violations.
+fn run_validate(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Validating file: {}", filename);
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository.clone())?;
+
+    let mut original = matches
+        .get_one::<String>("original")
+        .map(|original_filename| build_reader(original_filename, repository))
+        .transpose()?;
+
+    let max_reported = *matches.get_one::<usize>("max-reported").unwrap();
+    let report = validate(&mut reader, original.as_mut(), max_reported)?;
+
+    let mut bufwriter = create_output(matches)?;
+    report.write_json(&mut bufwriter)?;
+    bufwriter.flush()?;
+
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+// Runs the depth subcommand.
+fn run_depth(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Computing depth: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let options = DepthOptions {
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+    };
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository)?;
+    let depth = compute_depth(&mut reader, refname.as_ref(), reflen, &options)?;
+
+    let mut bufwriter = create_output(matches)?;
+    depth.write_bedgraph(&mut bufwriter, refname.as_ref())?;
+    bufwriter.flush()?;
+
+    if let Some(per_base_filename) = matches.get_one::<String>("per-base") {
+        let mut writer = BufWriter::new(File::create(per_base_filename)?);
+        depth.write_per_base(&mut writer, refname.as_ref())?;
+        writer.flush()?;
+    }
+
+    if let Some(summary_filename) = matches.get_one::<String>("summary") {
+        let mut writer = BufWriter::new(File::create(summary_filename)?);
+        depth.write_summary(&mut writer, refname.as_ref())?;
+        writer.flush()?;
+    }
+
+    if let Some(windows_filename) = matches.get_one::<String>("windows") {
+        let window_size = *matches.get_one::<usize>("window-size").unwrap();
+        let mut writer = BufWriter::new(File::create(windows_filename)?);
+        depth.write_windows(&mut writer, refname.as_ref(), window_size)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// Runs the pileup subcommand.
+fn run_pileup(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Computing pileup: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let options = DepthOptions {
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+    };
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository)?;
+    let pileup = compute_pileup(&mut reader, refname.as_ref(), reflen, &options)?;
+
+    let mut bufwriter = create_output(matches)?;
+    pileup.write_tsv(&mut bufwriter, refname.as_ref())?;
+    bufwriter.flush()?;
+
+    if let Some(prefix) = matches.get_one::<String>("sparse") {
+        let sample = match matches.get_one::<String>("sample") {
+            Some(sample) => sample.clone(),
+            None => Path::new(filename)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("sample")),
+        };
+
+        for allele in [Allele::A, Allele::C, Allele::G, Allele::T] {
+            let mut writer =
+                BufWriter::new(File::create(format!("{prefix}.{}.txt", allele.as_str()))?);
+            pileup.write_sparse_base(&mut writer, &sample, allele)?;
+            writer.flush()?;
+        }
+
+        let mut writer = BufWriter::new(File::create(format!("{prefix}.coverage.txt"))?);
+        pileup.write_sparse_coverage(&mut writer, &sample)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// Runs the cells subcommand.
+fn run_cells(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Counting cells: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let whitelist = matches
+        .get_one::<String>("whitelist")
+        .map(|whitelist_filename| -> io::Result<HashSet<BString>> {
+            let reader = BufReader::new(File::open(whitelist_filename)?);
+            let mut whitelist = HashSet::new();
+
+            for line in reader.lines() {
+                let line = line?;
+                let barcode = line.trim();
+
+                if !barcode.is_empty() {
+                    whitelist.insert(BString::from(barcode));
+                }
+            }
+
+            Ok(whitelist)
+        })
+        .transpose()?;
+
+    let options = CellOptions {
+        depth: DepthOptions {
+            min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+            min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+        },
+        barcode_tag: *matches.get_one::<Tag>("barcode-tag").unwrap(),
+        umi_tag: *matches.get_one::<Tag>("umi-tag").unwrap(),
+        whitelist,
+    };
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository)?;
+    let counts = compute_cell_counts(&mut reader, refname.as_ref(), reflen, &options)?;
+
+    let stats = counts.stats();
+    log::info!(
+        "Counted {} reads of {} cells; {} duplicates, {} without a barcode and {} not in the whitelist left out.",
+        stats.reads,
+        counts.len(),
+        stats.duplicates,
+        stats.no_barcode,
+        stats.not_whitelisted
+    );
+
+    let prefix = matches.get_one::<String>("output").unwrap();
+
+    for allele in [Allele::A, Allele::C, Allele::G, Allele::T] {
+        let mut writer = BufWriter::new(File::create(format!("{prefix}.{}.txt", allele.as_str()))?);
+        counts.write_sparse_base(&mut writer, allele)?;
+        writer.flush()?;
+    }
+
+    let mut writer = BufWriter::new(File::create(format!("{prefix}.coverage.txt"))?);
+    counts.write_sparse_coverage(&mut writer)?;
+    writer.flush()?;
+
+    let mut writer = BufWriter::new(File::create(format!("{prefix}.depthTable.txt"))?);
+    counts.write_depth_table(&mut writer)?;
+    writer.flush()
+}
+
+// Runs the call subcommand.
+fn run_call(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Calling variants: {}", filename);
+
+    let target_refname = BString::from(matches.get_one::<String>("targetref").unwrap().as_str());
+
+    let repository = read_repository(matches)?;
+    let reference_sequence = repository.get(&target_refname).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {target_refname} is not in the reference FASTA"),
         )
+    })??;
+    let reflen = reference_sequence.len();
+
+    let depth_options = DepthOptions {
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+    };
+
+    let options = CallOptions {
+        min_depth: *matches.get_one::<u32>("min-depth").unwrap(),
+        min_allele_fraction: *matches.get_one::<f64>("min-af").unwrap(),
+        max_p_value: *matches.get_one::<f64>("max-pvalue").unwrap(),
+        overdispersion: *matches.get_one::<f64>("overdispersion").unwrap(),
+        ..Default::default()
+    };
+
+    let mut reader = build_reader(filename, repository)?;
+    let pileup = compute_pileup(&mut reader, target_refname.as_ref(), reflen, &depth_options)?;
+    let variants = call_variants(&pileup, reference_sequence.as_ref(), &options);
+
+    let mut bufwriter = create_output(matches)?;
+    write_vcf(&mut bufwriter, &variants, target_refname.as_ref(), reflen)?;
+    bufwriter.flush()
+}
+
+// Runs the cohort subcommand. Each sample's counts are saved in the work
+// directory as soon as it's done, and samples with saved counts are skipped.
+fn run_cohort(matches: &ArgMatches) -> io::Result<()> {
+    let manifest_filename = matches.get_one::<String>("manifest").unwrap();
+    let samples = read_manifest(BufReader::new(File::open(manifest_filename)?))?;
+    log::info!(
+        "Calling cohort of {} samples: {}",
+        samples.len(),
+        manifest_filename
+    );
+
+    let prefix = matches.get_one::<String>("output").unwrap();
+    let outputs = CohortOutputs::new(prefix)?;
+    let work_dir = matches
+        .get_one::<String>("work-dir")
+        .map_or_else(|| PathBuf::from(format!("{prefix}.work")), PathBuf::from);
+    std::fs::create_dir_all(&work_dir)?;
+
+    let target_refname = BString::from(matches.get_one::<String>("targetref").unwrap().as_str());
+    let refname = matches.get_one::<String>("ref").map_or_else(
+        || target_refname.clone(),
+        |name| BString::from(name.as_str()),
+    );
+
+    let reference_filename = matches.get_one::<String>("reference").unwrap();
+    let repository = read_repository(matches)?;
+    let reference_sequence = repository.get(&target_refname).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {target_refname} is not in the reference FASTA"),
+        )
+    })??;
+    let reflen = reference_sequence.len();
+
+    let depth_options = DepthOptions {
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+    };
+
+    let options = CallOptions {
+        min_depth: *matches.get_one::<u32>("min-depth").unwrap(),
+        min_allele_fraction: *matches.get_one::<f64>("min-af").unwrap(),
+        max_p_value: *matches.get_one::<f64>("max-pvalue").unwrap(),
+        overdispersion: *matches.get_one::<f64>("overdispersion").unwrap(),
+        ..Default::default()
+    };
+
+    let mut cohort = Vec::with_capacity(samples.len());
+
+    for sample in samples {
+        let counts_path = work_dir.join(format!("{}.counts.tsv", sample.id));
+        let source = CountsSource::new(
+            &sample.path,
+            reference_filename,
+            &refname.to_string(),
+            &target_refname.to_string(),
+            depth_options,
+            options,
+        )?;
+
+        let saved = if counts_path.exists() {
+            let saved = SampleCounts::read(BufReader::new(File::open(&counts_path)?), &source)?;
+
+            if saved.is_none() {
+                log::info!(
+                    "Counts of {} are from another input or other options, recounting.",
+                    sample.id
+                );
+            }
+
+            saved
+        } else {
+            None
+        };
+
+        let counts = if let Some(counts) = saved {
+            log::info!("Reusing counts of {}: {}", sample.id, counts_path.display());
+            counts
+        } else {
+            log::info!("Counting {}: {}", sample.id, sample.path.display());
+
+            let mut reader = Builder::default()
+                .set_reference_sequence_repository(repository.clone())
+                .build_from_path(&sample.path)?;
+            let pileup = compute_pileup(&mut reader, refname.as_ref(), reflen, &depth_options)?;
+            let counts = SampleCounts::new(&pileup, reference_sequence.as_ref(), &options);
+
+            // Written under another name first, so an interrupted run never
+            // leaves a partial file to be reused.
+            let partial_path = counts_path.with_extension("tsv.partial");
+            let mut writer = BufWriter::new(File::create(&partial_path)?);
+            counts.write(&mut writer, &source)?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            std::fs::rename(&partial_path, &counts_path)?;
+
+            counts
+        };
+
+        cohort.push((sample.id, counts));
+    }
+
+    let sites = cohort_sites(&cohort);
+    log::info!("{} sites called in any sample.", sites.len());
+
+    outputs.write(target_refname.as_ref(), reflen, &cohort, &sites)
+}
+
+// Runs the deletions subcommand. Writes a VCF if the output ends in .vcf,
+// otherwise a BEDPE.
+fn run_deletions(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Finding deletions: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let options = DeletionOptions {
+        min_len: *matches.get_one::<usize>("min-len").unwrap(),
+        max_len: *matches.get_one::<usize>("max-len").unwrap(),
+        max_distance: *matches.get_one::<usize>("max-distance").unwrap(),
+        min_reads: *matches.get_one::<usize>("min-reads").unwrap(),
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        alias_refnames: matches
+            .get_one::<String>("extended-ref")
+            .map(|name| BString::from(name.as_str()))
+            .into_iter()
+            .collect(),
+    };
+
+    let repository = read_repository(matches)?;
+    let reference_sequence = repository
+        .get(&refname)
+        .transpose()?
+        .map(|sequence| sequence.as_ref().to_vec());
+
+    let mut reader = build_reader(filename, repository)?;
+    let calls = detect_deletions(
+        &mut reader,
+        refname.as_ref(),
+        reflen,
+        reference_sequence.as_deref(),
+        &options,
+    )?;
+
+    let is_vcf = matches.get_one::<String>("output").is_some_and(|output| {
+        Path::new(output)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("vcf"))
+    });
+
+    let mut bufwriter = create_output(matches)?;
+
+    if is_vcf {
+        write_deletions_vcf(
+            &mut bufwriter,
+            &calls,
+            refname.as_ref(),
+            reflen,
+            reference_sequence.as_deref(),
+        )?;
     } else {
-        Ok(())
+        write_bedpe(&mut bufwriter, &calls, refname.as_ref(), reflen)?;
+    }
+
+    bufwriter.flush()
+}
+
+// Runs the consensus subcommand.
+fn run_consensus(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Building consensus: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let depth_options = DepthOptions {
+        min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+        min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+    };
+
+    let options = ConsensusOptions {
+        min_depth: *matches.get_one::<u32>("min-depth").unwrap(),
+        min_allele_fraction: *matches.get_one::<f64>("min-af").unwrap(),
+    };
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository)?;
+    let pileup = compute_pileup(&mut reader, refname.as_ref(), reflen, &depth_options)?;
+    let consensus = build_consensus(&pileup, &options);
+
+    log::info!(
+        "Consensus: {} bp, {} N, {} ambiguous, {} inserted and {} deleted.",
+        consensus.sequence.len(),
+        consensus.low_coverage,
+        consensus.ambiguous,
+        consensus.inserted,
+        consensus.deleted
+    );
+
+    let name = match matches.get_one::<String>("name") {
+        Some(name) => name.clone(),
+        None => Path::new(filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("consensus")),
+    };
+
+    let record = fasta::Record::new(
+        fasta::record::Definition::new(name, None),
+        fasta::record::Sequence::from(consensus.sequence),
+    );
+
+    let mut writer = fasta::io::Writer::new(create_output(matches)?);
+    writer.write_record(&record)?;
+    writer.get_mut().flush()
+}
+
+// Runs the mtcn subcommand.
+fn run_mtcn(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Estimating mtDNA copy number: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let excluded = matches
+        .get_one::<String>("exclude")
+        .map(|bed_filename| read_bed(BufReader::new(File::open(bed_filename)?)))
+        .transpose()?
+        .unwrap_or_default();
+
+    let options = CopyNumberOptions {
+        depth: DepthOptions {
+            min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+            min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+        },
+        numt: matches.get_flag("numt").then(|| NumtOptions {
+            max_edit_distance_difference: *matches.get_one::<i64>("numt-max-nm-diff").unwrap(),
+            max_mismatch_density: *matches.get_one::<f64>("numt-max-mismatch-density").unwrap(),
+            filter: true,
+        }),
+        autosomes: matches
+            .get_many::<String>("autosomes")
+            .into_iter()
+            .flatten()
+            .map(|name| BString::from(name.as_str()))
+            .collect(),
+        windows_per_autosome: *matches.get_one::<usize>("windows").unwrap(),
+        window_len: *matches.get_one::<usize>("window-len").unwrap(),
+        excluded,
+        target_refname: BString::from(matches.get_one::<String>("targetref").unwrap().as_str()),
+    };
+
+    let mut reader = indexed_reader::Builder::default()
+        .set_reference_sequence_repository(read_repository(matches)?)
+        .build_from_path(filename)?;
+
+    let estimate = estimate_copy_number(&mut reader, refname.as_ref(), reflen, &options)?;
+
+    if estimate.windows == 0 {
+        log::warn!("No autosomal windows had any reads; the copy number is NA.");
+    }
+
+    let sample = match matches.get_one::<String>("sample") {
+        Some(sample) => sample.clone(),
+        None => Path::new(filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("sample")),
+    };
+
+    let mut bufwriter = create_output(matches)?;
+    estimate.write(&mut bufwriter, &sample)?;
+    bufwriter.flush()
+}
+
+// Runs the phase subcommand.
+fn run_phase(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Phasing reads: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let sites_filename = matches.get_one::<String>("sites").unwrap();
+    let sites = read_phase_sites(
+        BufReader::new(File::open(sites_filename)?),
+        refname.as_ref(),
+    )?;
+    log::info!("Phasing {} sites from {}", sites.len(), sites_filename);
+
+    let options = PhaseOptions {
+        depth: DepthOptions {
+            min_mapping_quality: *matches.get_one::<u8>("min-mapq").unwrap(),
+            min_base_quality: *matches.get_one::<u8>("min-baseq").unwrap(),
+        },
+        min_reads: *matches.get_one::<usize>("min-reads").unwrap(),
+        min_fraction: *matches.get_one::<f64>("min-fraction").unwrap(),
+        max_mismatch_fraction: *matches.get_one::<f64>("max-mismatch-fraction").unwrap(),
+    };
+
+    let repository = read_repository(matches)?;
+    let mut reader = build_reader(filename, repository)?;
+    let mut bufwriter = create_output(matches)?;
+
+    let phasing = phase_sam(
+        &mut reader,
+        &mut bufwriter,
+        refname.as_ref(),
+        reflen,
+        &sites,
+        &options,
+    )?;
+    bufwriter.flush()?;
+
+    log::info!(
+        "Found {} haplotypes; {} reads couldn't be assigned to one.",
+        phasing.haplotypes.len(),
+        phasing.unassigned
+    );
+
+    let mut writer = BufWriter::new(File::create(
+        matches.get_one::<String>("haplotypes").unwrap(),
+    )?);
+    phasing.write(&mut writer, &sites)?;
+    writer.flush()
+}
+
+// Runs the lift-vcf subcommand.
+fn run_lift_vcf(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("vcf").unwrap();
+    log::info!("Lifting variants: {}", filename);
+
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let options = LiftVcfOptions {
+        refname: BString::from(matches.get_one::<String>("ref").unwrap().as_str()),
+        target_refname: BString::from(matches.get_one::<String>("targetref").unwrap().as_str()),
+        lift: Lift {
+            reflen,
+            offset: *matches.get_one::<usize>("offset").unwrap() % reflen,
+        },
+        policy: match matches.get_one::<String>("depth-policy").unwrap().as_str() {
+            "max" => DepthPolicy::Max,
+            _ => DepthPolicy::Sum,
+        },
+    };
+
+    let reader = open_text(filename)?;
+    let mut bufwriter = create_output(matches)?;
+
+    lift_vcf(reader, &mut bufwriter, &options)?;
+    bufwriter.flush()
+}
+
+// Runs the lift-bed subcommand.
+fn run_lift_bed(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("bed").unwrap();
+    log::info!("Lifting intervals: {}", filename);
+
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let direction = if matches.get_flag("unfold") {
+        LiftDirection::Unfold {
+            extended_len: *matches.get_one::<usize>("extended-len").unwrap(),
+        }
+    } else {
+        LiftDirection::Fold
+    };
+
+    let options = LiftBedOptions {
+        refname: BString::from(matches.get_one::<String>("ref").unwrap().as_str()),
+        target_refname: BString::from(matches.get_one::<String>("targetref").unwrap().as_str()),
+        lift: Lift {
+            reflen,
+            offset: *matches.get_one::<usize>("offset").unwrap() % reflen,
+        },
+        direction,
+    };
+
+    let reader = open_text(filename)?;
+    let mut bufwriter = create_output(matches)?;
+
+    lift_bed(reader, &mut bufwriter, &options)?;
+    bufwriter.flush()
+}
+
+// Runs the paf subcommand.
+fn run_paf(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("paf").unwrap();
+    log::info!("Processing PAF: {}", filename);
+
+    let refname = BString::from(matches.get_one::<String>("ref").unwrap().as_str());
+    let target_refname = BString::from(matches.get_one::<String>("targetref").unwrap().as_str());
+    let reflen = *matches.get_one::<usize>("reflen").unwrap();
+
+    let reader = open_text(filename)?;
+    let mut bufwriter = create_output(matches)?;
+
+    let stats = convert_paf(
+        reader,
+        &mut bufwriter,
+        refname.as_ref(),
+        target_refname.as_ref(),
+        reflen,
+    )?;
+    bufwriter.flush()?;
+
+    log::info!(
+        "{} records unchanged, {} shifted and {} split ({} without a cg:Z CIGAR); {} on other references.",
+        stats.unchanged,
+        stats.shifted,
+        stats.split,
+        stats.split_without_cigar,
+        stats.other
+    );
+
+    Ok(())
+}
+
+// Opens a text file, or stdin for `-`. A file ending in .gz is read as BGZF.
+fn open_text(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    if filename == "-" {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else if filename.ends_with(".gz") {
+        Ok(Box::new(bgzf::Reader::new(File::open(filename)?)))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(filename)?)))
+    }
+}
+
+// Runs the rescue subcommand.
+fn run_rescue(matches: &ArgMatches) -> io::Result<()> {
+    let filename = matches.get_one::<String>("alignmentfile").unwrap();
+    log::info!("Rescuing reads: {}", filename);
+
+    let target_refname = BString::from(matches.get_one::<String>("targetref").unwrap().as_str());
+
+    let repository = read_repository(matches)?;
+    let reference_sequence = repository.get(&target_refname).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {target_refname} is not in the reference FASTA"),
+        )
+    })??;
+
+    let options = RescueOptions {
+        align_clips: !matches.get_flag("join-only"),
+        min_clip_len: *matches.get_one::<usize>("min-clip").unwrap(),
+        max_distance: *matches.get_one::<usize>("max-distance").unwrap(),
+        band: *matches.get_one::<usize>("band").unwrap(),
+        min_score: *matches.get_one::<i32>("min-score").unwrap(),
+        per_base_tags: read_per_base_tags(matches),
+    };
+
+    let mut reader = build_reader(filename, repository)?;
+    let mut bufwriter = create_output(matches)?;
+
+    let stats = rescue_sam(
+        &mut reader,
+        &mut bufwriter,
+        target_refname.as_ref(),
+        reference_sequence.as_ref(),
+        &options,
+    )?;
+
+    if let Some(stats_filename) = matches.get_one::<String>("stats") {
+        write_stats(&stats, stats_filename)?;
     }
+
+    Ok(())
 }
@@ -0,0 +1,403 @@
+//! Rewriting of base modification tags (`MM`/`ML`) for split reads.
+//!
+//! The skip counts in `MM` are relative to the occurrences of a base in the
+//! read *as sequenced*, and `ML` holds one probability per call and
+//! modification code. Truncating SEQ therefore invalidates both tags, so each
+//! piece of a split read gets its own, recomputed, pair.
+
+use bstr::{BString, ByteSlice};
+use noodles::sam::alignment::{
+    record::data::field::Tag,
+    record_buf::data::field::{value::Array, Value},
+    RecordBuf,
+};
+use std::ops::Range;
+
+// The legacy (pre-SAMtags 2021) spelling of MM/ML.
+const LEGACY_BASE_MODIFICATIONS: Tag = Tag::new(b'M', b'm');
+const LEGACY_BASE_MODIFICATION_PROBABILITIES: Tag = Tag::new(b'M', b'l');
+
+const TAG_PAIRS: [(Tag, Tag); 2] = [
+    (
+        Tag::BASE_MODIFICATIONS,
+        Tag::BASE_MODIFICATION_PROBABILITIES,
+    ),
+    (
+        LEGACY_BASE_MODIFICATIONS,
+        LEGACY_BASE_MODIFICATION_PROBABILITIES,
+    ),
+];
+
+// A single `MM` entry, e.g. `C+m?,5,12,0`.
+#[derive(Debug)]
+struct ModificationGroup {
+    base: u8,
+    strand: u8,
+    codes: BString,
+    mode: Option<u8>,
+    // The calls as ordinals into the occurrences of `base` in the read.
+    calls: Vec<usize>,
+}
+
+impl ModificationGroup {
+    // The number of ML values per call. A ChEBI code is a single modification,
+    // otherwise there is one single letter code per modification.
+    fn probabilities_per_call(&self) -> usize {
+        if self.codes.iter().all(u8::is_ascii_digit) {
+            1
+        } else {
+            self.codes.len()
+        }
+    }
+}
+
+/// Returns the reverse complement of a sequence. IUPAC codes other than ACGT
+/// are complemented to `N`.
+pub(crate) fn reverse_complement(sequence: &[u8]) -> Vec<u8> {
+    sequence
+        .iter()
+        .rev()
+        .map(|base| match base.to_ascii_uppercase() {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Rewrites the base modification tags of `read`, a piece of a split record,
+/// so they only describe the bases in `range`.
+///
+/// `sequence` is the full SEQ of the original record and `range` indexes into
+/// it, i.e. both are in reference orientation. For reverse complemented reads
+/// the range is mapped back onto the read as sequenced before counting. If the
+/// tags can't be parsed they are removed from the piece rather than being left
+/// pointing at the wrong bases.
+pub(crate) fn slice_base_modifications(
+    read: &mut RecordBuf,
+    sequence: &[u8],
+    is_reverse_complemented: bool,
+    range: Range<usize>,
+) {
+    // The tags refer to the read in the orientation it was sequenced.
+    let (original_sequence, original_range) = if is_reverse_complemented {
+        (
+            reverse_complement(sequence),
+            sequence.len() - range.end..sequence.len() - range.start,
+        )
+    } else {
+        (sequence.to_vec(), range)
+    };
+
+    for (mm_tag, ml_tag) in TAG_PAIRS {
+        let Some(Value::String(mm)) = read.data().get(&mm_tag) else {
+            continue;
+        };
+
+        let probabilities = match read.data().get(&ml_tag) {
+            Some(Value::Array(Array::UInt8(values))) => Some(values.clone()),
+            _ => None,
+        };
+
+        match slice_tags(
+            mm,
+            probabilities.as_deref(),
+            &original_sequence,
+            &original_range,
+        ) {
+            Some((piece_mm, piece_ml)) => {
+                let data = read.data_mut();
+                data.insert(mm_tag, Value::String(piece_mm));
+
+                if let Some(piece_ml) = piece_ml {
+                    data.insert(ml_tag, Value::Array(Array::UInt8(piece_ml)));
+                }
+            }
+            None => {
+                log::warn!(
+                    "Read: {:?} has invalid {:?}/{:?} tags, removing them from the split read.",
+                    read.name(),
+                    mm_tag,
+                    ml_tag
+                );
+
+                let data = read.data_mut();
+                data.remove(&mm_tag);
+                data.remove(&ml_tag);
+            }
+        }
+    }
+
+    // MN records the SEQ length the MM/ML tags were computed against.
+    if read
+        .data()
+        .get(&Tag::BASE_MODIFICATION_SEQUENCE_LENGTH)
+        .is_some()
+    {
+        let len = i32::try_from(read.sequence().len()).unwrap_or(i32::MAX);
+
+        read.data_mut()
+            .insert(Tag::BASE_MODIFICATION_SEQUENCE_LENGTH, Value::from(len));
+    }
+}
+
+// Slices a pair of MM/ML values to the part of the original read in `range`.
+// Returns None if MM is malformed, or ML has fewer values than MM calls for.
+fn slice_tags(
+    mm: &[u8],
+    ml: Option<&[u8]>,
+    sequence: &[u8],
+    range: &Range<usize>,
+) -> Option<(BString, Option<Vec<u8>>)> {
+    let groups = parse_base_modifications(mm, sequence)?;
+
+    let ml_len: usize = groups
+        .iter()
+        .map(|group| group.calls.len() * group.probabilities_per_call())
+        .sum();
+
+    if ml.is_some_and(|ml| ml.len() < ml_len) {
+        return None;
+    }
+
+    let mut piece_mm = BString::default();
+    let mut piece_ml = Vec::new();
+    let mut ml_offset = 0;
+
+    for group in groups {
+        let occurrences = base_occurrences(sequence, group.base);
+        let width = group.probabilities_per_call();
+
+        // The ordinal of the first occurrence of the base inside the piece.
+        let first_ordinal = occurrences.partition_point(|&pos| pos < range.start);
+
+        piece_mm.push(group.base);
+        piece_mm.push(group.strand);
+        piece_mm.extend_from_slice(&group.codes);
+
+        if let Some(mode) = group.mode {
+            piece_mm.push(mode);
+        }
+
+        let mut previous = None;
+
+        for (i, &ordinal) in group.calls.iter().enumerate() {
+            if !range.contains(&occurrences[ordinal]) {
+                continue;
+            }
+
+            let skip = match previous {
+                Some(prev) => ordinal - prev - 1,
+                None => ordinal - first_ordinal,
+            };
+
+            piece_mm.push(b',');
+            piece_mm.extend_from_slice(skip.to_string().as_bytes());
+            previous = Some(ordinal);
+
+            if let Some(ml) = ml {
+                let start = ml_offset + i * width;
+                piece_ml.extend_from_slice(&ml[start..start + width]);
+            }
+        }
+
+        ml_offset += group.calls.len() * width;
+        piece_mm.push(b';');
+    }
+
+    Some((piece_mm, ml.map(|_| piece_ml)))
+}
+
+// Parses an MM value, resolving the skip counts to ordinals of the base
+// occurrences in `sequence`. Returns None if the tag is malformed or runs past
+// the end of the sequence.
+fn parse_base_modifications(mm: &[u8], sequence: &[u8]) -> Option<Vec<ModificationGroup>> {
+    let mut groups = Vec::new();
+
+    for entry in mm.split_str(";").filter(|entry| !entry.is_empty()) {
+        let mut fields = entry.split_str(",");
+        let header = fields.next()?;
+
+        let (&base, rest) = header.split_first()?;
+        let (&strand, rest) = rest.split_first()?;
+
+        if !matches!(base, b'A' | b'C' | b'G' | b'T' | b'U' | b'N')
+            || !matches!(strand, b'+' | b'-')
+        {
+            return None;
+        }
+
+        let (codes, mode) = match rest.last() {
+            Some(&mode @ (b'.' | b'?')) => (&rest[..rest.len() - 1], Some(mode)),
+            _ => (rest, None),
+        };
+
+        if codes.is_empty() {
+            return None;
+        }
+
+        let occurrence_count = base_occurrences(sequence, base).len();
+        let mut calls = Vec::new();
+        let mut next_ordinal = 0;
+
+        for skip in fields {
+            let skip: usize = skip.to_str().ok()?.trim().parse().ok()?;
+            let ordinal = next_ordinal + skip;
+
+            if ordinal >= occurrence_count {
+                return None;
+            }
+
+            calls.push(ordinal);
+            next_ordinal = ordinal + 1;
+        }
+
+        groups.push(ModificationGroup {
+            base,
+            strand,
+            codes: codes.into(),
+            mode,
+            calls,
+        });
+    }
+
+    Some(groups)
+}
+
+// The positions in `sequence` matching `base`. `N` matches any base.
+fn base_occurrences(sequence: &[u8], base: u8) -> Vec<usize> {
+    sequence
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| {
+            let b = b.to_ascii_uppercase();
+            base == b'N' || b == base || (base == b'U' && b == b'T')
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use noodles::sam::alignment::{
+        record::Flags,
+        record_buf::{Data, Sequence},
+    };
+
+    fn build_read(sequence: &[u8], mm: &str, ml: Vec<u8>, flags: Flags) -> RecordBuf {
+        let data: Data = [
+            (Tag::BASE_MODIFICATIONS, Value::from(mm)),
+            (
+                Tag::BASE_MODIFICATION_PROBABILITIES,
+                Value::Array(Array::UInt8(ml)),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        RecordBuf::builder()
+            .set_name("Read1")
+            .set_flags(flags)
+            .set_sequence(Sequence::from(sequence.to_vec()))
+            .set_data(data)
+            .build()
+    }
+
+    fn get_tags(read: &RecordBuf) -> (Option<&Value>, Option<&Value>) {
+        (
+            read.data().get(&Tag::BASE_MODIFICATIONS),
+            read.data().get(&Tag::BASE_MODIFICATION_PROBABILITIES),
+        )
+    }
+
+    #[test]
+    fn test_slice_forward_read() {
+        // C occurrences at 1, 3, 5, 7, 9; calls at the 2nd, 3rd and 5th C.
+        let sequence = b"ACACACACAC";
+        let flags = Flags::empty();
+
+        let mut left = build_read(sequence, "C+m?,1,0,1;", vec![10, 20, 30], flags);
+        slice_base_modifications(&mut left, sequence, false, 0..6);
+        assert_eq!(
+            get_tags(&left),
+            (
+                Some(&Value::from("C+m?,1,0;")),
+                Some(&Value::Array(Array::UInt8(vec![10, 20])))
+            )
+        );
+
+        let mut right = build_read(sequence, "C+m?,1,0,1;", vec![10, 20, 30], flags);
+        slice_base_modifications(&mut right, sequence, false, 6..10);
+        assert_eq!(
+            get_tags(&right),
+            (
+                Some(&Value::from("C+m?,1;")),
+                Some(&Value::Array(Array::UInt8(vec![30])))
+            )
+        );
+    }
+
+    #[test]
+    fn test_slice_reverse_complemented_read() {
+        // As sequenced the read is GTGTGTGTGT with calls on the first and last C
+        // of the opposite strand, i.e. the G's at 0 and 8.
+        let sequence = b"ACACACACAC";
+        let flags = Flags::REVERSE_COMPLEMENTED;
+
+        // SEQ 0..4 is the last four bases as sequenced, with only the G at 8.
+        let mut left = build_read(sequence, "G-m,0,3;C+h;", vec![1, 2], flags);
+        slice_base_modifications(&mut left, sequence, true, 0..4);
+        assert_eq!(
+            get_tags(&left),
+            (
+                Some(&Value::from("G-m,1;C+h;")),
+                Some(&Value::Array(Array::UInt8(vec![2])))
+            )
+        );
+
+        let mut right = build_read(sequence, "G-m,0,3;C+h;", vec![1, 2], flags);
+        slice_base_modifications(&mut right, sequence, true, 4..10);
+        assert_eq!(
+            get_tags(&right),
+            (
+                Some(&Value::from("G-m,0;C+h;")),
+                Some(&Value::Array(Array::UInt8(vec![1])))
+            )
+        );
+    }
+
+    #[test]
+    fn test_slice_multiple_codes() {
+        let sequence = b"CCCC";
+
+        // Two codes, so two probabilities per call.
+        let mut right = build_read(sequence, "C+mh,0,2;", vec![1, 2, 3, 4], Flags::empty());
+        slice_base_modifications(&mut right, sequence, false, 2..4);
+        assert_eq!(
+            get_tags(&right),
+            (
+                Some(&Value::from("C+mh,1;")),
+                Some(&Value::Array(Array::UInt8(vec![3, 4])))
+            )
+        );
+    }
+
+    #[test]
+    fn test_slice_invalid_tag() {
+        // There are only two C's, so the skip count runs off the end.
+        let sequence = b"ACAC";
+
+        let mut read = build_read(sequence, "C+m,5;", vec![1], Flags::empty());
+        slice_base_modifications(&mut read, sequence, false, 0..2);
+        assert_eq!(get_tags(&read), (None, None));
+
+        // MM calls both C's, but ML only has one probability.
+        let mut read = build_read(sequence, "C+m,0,0;", vec![1], Flags::empty());
+        slice_base_modifications(&mut read, sequence, false, 0..2);
+        assert_eq!(get_tags(&read), (None, None));
+    }
+}
@@ -0,0 +1,411 @@
+//! Mitochondrial DNA copy number from whole genome alignments.
+//!
+//! The depth of the mitochondrial reference is folded onto the circular
+//! reference, as for `depth`, so the reads at the origin of an extended
+//! reference count towards it. The nuclear depth is sampled from evenly spaced
+//! windows of the autosomes through the index, rather than reading the whole
+//! file, and the copy number is the ratio of the two per diploid genome.
+
+use crate::{
+    depth::{aligned_bases, is_counted, mean},
+    numt::is_numt,
+    Depth, DepthOptions, Lift, NumtOptions,
+};
+use bstr::{BStr, BString, ByteSlice};
+use noodles::{
+    core::{Position, Region},
+    sam::{
+        alignment::{Record, RecordBuf},
+        Header,
+    },
+};
+use noodles_util::alignment::io::IndexedReader;
+use std::{
+    io::{self, BufRead, Read, Seek, Write},
+    ops::Range,
+};
+
+/// Options for estimating the copy number.
+#[derive(Clone, Debug)]
+pub struct CopyNumberOptions {
+    /// Filters on the reads and bases counted, on both the mitochondrial
+    /// reference and the autosomes.
+    pub depth: DepthOptions,
+    /// Reads on the mitochondrial reference flagged as likely from NUMTs
+    /// aren't counted.
+    pub numt: Option<NumtOptions>,
+    /// The autosomes to sample. If empty, `1` to `22`, with or without a
+    /// `chr` prefix, as named in the header.
+    pub autosomes: Vec<BString>,
+    /// The number of windows sampled from each autosome.
+    pub windows_per_autosome: usize,
+    /// The length of each window.
+    pub window_len: usize,
+    /// 0-based, half-open regions left out of both depths, e.g. NUMT-prone
+    /// regions of the mitochondrial reference or NUMT insertions in the
+    /// nuclear genome. A window overlapping any of them isn't sampled.
+    /// Regions on the mitochondrial reference may be named after it or the
+    /// linear target reference, and are folded onto the circular reference.
+    pub excluded: Vec<(BString, Range<usize>)>,
+    /// The name of the linear target reference.
+    pub target_refname: BString,
+}
+
+impl Default for CopyNumberOptions {
+    fn default() -> Self {
+        Self {
+            depth: DepthOptions {
+                min_mapping_quality: 20,
+                min_base_quality: 0,
+            },
+            numt: None,
+            autosomes: Vec::new(),
+            windows_per_autosome: 20,
+            window_len: 10_000,
+            excluded: Vec::new(),
+            target_refname: BString::from("chrM"),
+        }
+    }
+}
+
+/// The copy number estimate of a sample.
+#[derive(Clone, Debug, Default)]
+pub struct CopyNumber {
+    /// The mean depth of the mitochondrial reference.
+    pub mt_depth: f64,
+    /// The number of mitochondrial reads left out as likely from NUMTs.
+    pub numt_reads: u64,
+    /// The mean depth of the sampled autosomal windows.
+    pub autosomal_depth: f64,
+    /// The number of windows the autosomal depth is from, including those
+    /// without any reads.
+    pub windows: usize,
+}
+
+impl CopyNumber {
+    /// Returns the number of copies of the mtDNA per diploid nuclear genome, or
+    /// `None` if there's no autosomal depth.
+    pub fn copy_number(&self) -> Option<f64> {
+        (self.autosomal_depth > 0.0).then(|| 2.0 * self.mt_depth / self.autosomal_depth)
+    }
+
+    /// Writes the estimate as a TSV with a header line.
+    pub fn write<W>(&self, writer: &mut W, sample: &str) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let copy_number = self
+            .copy_number()
+            .map_or_else(|| String::from("NA"), |n| format!("{n:.2}"));
+
+        writeln!(
+            writer,
+            "sample\tmt_depth\tautosomal_depth\twindows\tnumt_reads\tmtdna_cn"
+        )?;
+        writeln!(
+            writer,
+            "{sample}\t{:.2}\t{:.2}\t{}\t{}\t{copy_number}",
+            self.mt_depth, self.autosomal_depth, self.windows, self.numt_reads
+        )
+    }
+}
+
+/// Reads the regions of a BED, as 0-based half-open ranges. Header, track and
+/// comment lines are skipped.
+pub fn read_bed<R>(reader: R) -> io::Result<Vec<(BString, Range<usize>)>>
+where
+    R: BufRead,
+{
+    let mut regions = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty()
+            || ["#", "track", "browser"]
+                .iter()
+                .any(|p| line.starts_with(p))
+        {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected a name, start and end", i + 1),
+            )
+        };
+
+        let mut fields = line.split('\t');
+        let name = fields.next().ok_or_else(invalid)?;
+        let mut next_position = || -> io::Result<usize> {
+            fields
+                .next()
+                .and_then(|field| field.trim().parse().ok())
+                .ok_or_else(invalid)
+        };
+        let start = next_position()?;
+        let end = next_position()?;
+
+        regions.push((BString::from(name), start..end));
+    }
+
+    Ok(regions)
+}
+
+/// Estimates the mtDNA copy number of an indexed whole genome alignment.
+///
+/// `refname` is the mitochondrial reference, either the circular one of
+/// length `reflen` or an extended one, whose positions past `reflen` are
+/// folded back onto it.
+pub fn estimate_copy_number<R>(
+    reader: &mut IndexedReader<R>,
+    refname: &BStr,
+    reflen: usize,
+    options: &CopyNumberOptions,
+) -> io::Result<CopyNumber>
+where
+    R: Read + Seek,
+{
+    let header = reader.read_header()?;
+
+    if header.reference_sequences().get(refname).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {refname} is not in the header"),
+        ));
+    }
+
+    let mut estimate = CopyNumber::default();
+
+    // The mitochondrial depth.
+    let mut depth = Depth::new(reflen);
+    let mito_refnames = [refname];
+
+    for result in reader.query(&header, &Region::new(refname.to_vec(), ..))? {
+        let record = result?;
+
+        if let Some(numt_options) = &options.numt {
+            let read = RecordBuf::try_from_alignment_record(&header, &record)?;
+
+            if is_counted(&read, &options.depth)? && is_numt(&read, &mito_refnames, numt_options) {
+                estimate.numt_reads += 1;
+                continue;
+            }
+        }
+
+        depth.add(&record, &options.depth)?;
+    }
+
+    let is_excluded = |name: &BStr, range: &Range<usize>| {
+        options.excluded.iter().any(|(excluded_name, excluded)| {
+            excluded_name.as_bstr() == name
+                && excluded.start < range.end
+                && range.start < excluded.end
+        })
+    };
+
+    let mt_excluded = mt_excluded(refname, reflen, options);
+    let positions: Vec<usize> = (0..depth.len())
+        .filter(|i| !mt_excluded.iter().any(|excluded| excluded.contains(i)))
+        .collect();
+    let bases: u64 = positions.iter().map(|&i| u64::from(depth.get(i))).sum();
+    estimate.mt_depth = mean(bases, positions.len());
+
+    // The autosomal depth, over every window sampled. A window without reads,
+    // e.g. from a deletion, counts as zero like any other base, so the mean
+    // is on the same footing as the mitochondrial one.
+    let mut bases = 0;
+    let mut len = 0;
+
+    for (name, length) in autosomes(&header, &options.autosomes)? {
+        for window in sample_windows(length, options.windows_per_autosome, options.window_len) {
+            if is_excluded(name.as_ref(), &window) {
+                continue;
+            }
+
+            let region = Region::new(
+                name.to_vec(),
+                position(window.start + 1)?..=position(window.end)?,
+            );
+
+            for result in reader.query(&header, &region)? {
+                bases += count_bases(&result?, length, &window, &options.depth)?;
+            }
+
+            len += window.len();
+            estimate.windows += 1;
+        }
+    }
+
+    estimate.autosomal_depth = mean(bases, len);
+
+    Ok(estimate)
+}
+
+// The excluded regions on the mitochondrial reference, named after either
+// `refname` or the target reference, folded onto the circular reference.
+fn mt_excluded(refname: &BStr, reflen: usize, options: &CopyNumberOptions) -> Vec<Range<usize>> {
+    let lift = Lift { reflen, offset: 0 };
+
+    options
+        .excluded
+        .iter()
+        .filter(|(name, _)| name.as_bstr() == refname || *name == options.target_refname)
+        .flat_map(|(_, interval)| lift.fold_interval(interval.clone()))
+        .collect()
+}
+
+fn position(n: usize) -> io::Result<Position> {
+    Position::new(n)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid window position"))
+}
+
+// The names and lengths of the autosomes to sample.
+fn autosomes(header: &Header, names: &[BString]) -> io::Result<Vec<(BString, usize)>> {
+    let reference_sequences = header.reference_sequences();
+
+    if names.is_empty() {
+        return Ok(reference_sequences
+            .iter()
+            .filter(|(name, _)| {
+                let number = name.strip_prefix(b"chr").unwrap_or(name);
+                matches!(
+                    number.to_str().ok().and_then(|n| n.parse::<u8>().ok()),
+                    Some(1..=22)
+                ) && !number.starts_with(b"0")
+            })
+            .map(|(name, reference_sequence)| (name.clone(), reference_sequence.length().get()))
+            .collect());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            reference_sequences
+                .get(name)
+                .map(|reference_sequence| (name.clone(), reference_sequence.length().get()))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("autosome {name} is not in the header"),
+                    )
+                })
+        })
+        .collect()
+}
+
+// Returns `n` windows of `window_len` bases centred on equal parts of a
+// reference of length `length`, as 0-based half-open ranges.
+fn sample_windows(length: usize, n: usize, window_len: usize) -> Vec<Range<usize>> {
+    if n == 0 || window_len == 0 || length < window_len {
+        return Vec::new();
+    }
+
+    (0..n)
+        .map(|k| {
+            let centre = (2 * k + 1) * length / (2 * n);
+            let start = centre
+                .saturating_sub(window_len / 2)
+                .min(length - window_len);
+            start..start + window_len
+        })
+        .collect()
+}
+
+// Counts the aligned bases of a record in the 0-based `window` of a reference
+// of length `length`, with the same filters as the depth.
+fn count_bases(
+    record: &impl Record,
+    length: usize,
+    window: &Range<usize>,
+    options: &DepthOptions,
+) -> io::Result<u64> {
+    if !is_counted(record, options)? {
+        return Ok(0);
+    }
+
+    let bases = aligned_bases(record, length, options.min_base_quality)?
+        .filter(|base| window.contains(&base.position))
+        .count();
+
+    Ok(bases as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::sam::alignment::record::{
+        cigar::{op::Kind, Op},
+        Flags, MappingQuality,
+    };
+
+    #[test]
+    fn test_copy_number() -> io::Result<()> {
+        assert_eq!(
+            sample_windows(100, 2, 10),
+            [20..30, 70..80],
+            "windows are centred on each half"
+        );
+        assert!(sample_windows(5, 2, 10).is_empty());
+
+        let options = DepthOptions {
+            min_mapping_quality: 20,
+            min_base_quality: 0,
+        };
+
+        // 2M1D3M from 8 aligns 8, 9, 11, 12 and 13, 1-based.
+        let record = RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(8).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar(
+                [
+                    Op::new(Kind::Match, 2),
+                    Op::new(Kind::Deletion, 1),
+                    Op::new(Kind::Match, 3),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .build();
+        assert_eq!(count_bases(&record, 100, &(8..12), &options)?, 3);
+
+        let regions = read_bed(&b"track name=numts\nchrM\t5\t10\n# skipped\n1\t0\t100\n"[..])?;
+        assert_eq!(
+            regions,
+            [(BString::from("chrM"), 5..10), (BString::from("1"), 0..100)]
+        );
+
+        // Regions on the extended reference are folded, and the ones on the
+        // linear reference are kept as they are.
+        let copy_number_options = CopyNumberOptions {
+            excluded: vec![
+                (BString::from("chrM_ext"), 95..105),
+                (BString::from("chrM"), 5..10),
+                (BString::from("1"), 0..100),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            mt_excluded(b"chrM_ext".as_bstr(), 100, &copy_number_options),
+            [95..100, 0..5, 5..10]
+        );
+
+        let estimate = CopyNumber {
+            mt_depth: 3000.0,
+            numt_reads: 4,
+            autosomal_depth: 30.0,
+            windows: 40,
+        };
+        let mut tsv = Vec::new();
+        estimate.write(&mut tsv, "s1")?;
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "sample\tmt_depth\tautosomal_depth\twindows\tnumt_reads\tmtdna_cn\n\
+            s1\t3000.00\t30.00\t40\t4\t200.00\n"
+        );
+
+        Ok(())
+    }
+}
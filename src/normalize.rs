@@ -0,0 +1,269 @@
+//! Left-aligns the indels of reads aligned to the extended reference.
+//!
+//! Aligners place an indel in a homopolymer, such as 303-315 or 16184-16193,
+//! anywhere along the run. Moving each indel as far left as it can go makes
+//! the alignments of the same molecule agree. The reference is circular, so
+//! the bases past the end of the linear reference are read from its start,
+//! and an indel is never left touching the point where the conversion splits
+//! the read.
+
+use crate::fold_position;
+use noodles::sam::alignment::{
+    record::{
+        cigar::{op::Kind, Op},
+        data::field::Tag,
+    },
+    RecordBuf,
+};
+
+/// Returns `read` with its indels moved as far left as they are equivalent,
+/// or `None` if none of them move.
+///
+/// `reference_sequence` is the linear reference, of length `reflen`. A
+/// deletion is kept clear of the split at the end of the linear reference,
+/// and an insertion off it, so both pieces of a split read begin and end with
+/// aligned bases. The MD tag no longer matches a moved deletion, so it is
+/// removed.
+pub(crate) fn left_align_indels(
+    read: &RecordBuf,
+    reference_sequence: &[u8],
+    reflen: usize,
+) -> Option<RecordBuf> {
+    if read.flags().is_unmapped() || reference_sequence.len() < reflen {
+        return None;
+    }
+
+    let start = read.alignment_start()?.get() - 1;
+    let sequence = read.sequence().as_ref();
+    let mut ops: Vec<Op> = read.cigar().as_ref().to_vec();
+
+    // The first position of the right piece of a split read.
+    let split = reflen;
+    let base = |position: usize| reference_sequence[fold_position(position, reflen)];
+
+    let mut changed = false;
+    let mut reference_position = start;
+    let mut read_position = 0;
+    let mut i = 0;
+
+    while i < ops.len() {
+        let op = ops[i];
+        let anchor = i.checked_sub(1).map(|j| ops[j]);
+
+        let is_indel = matches!(op.kind(), Kind::Insertion | Kind::Deletion);
+
+        // Keep at least one aligned base in front of the indel. The bases it
+        // rolls over match the reference either side, so a `=` run stays one.
+        if let Some(anchor) = anchor.filter(|anchor| {
+            is_indel
+                && matches!(anchor.kind(), Kind::Match | Kind::SequenceMatch)
+                && anchor.len() > 1
+        }) {
+            let len = op.len();
+            let mut max_shift = 0;
+
+            while max_shift < anchor.len() - 1 {
+                let k = max_shift + 1;
+
+                let is_equivalent = match op.kind() {
+                    Kind::Deletion => base(reference_position - k)
+                        .eq_ignore_ascii_case(&base(reference_position + len - k)),
+                    _ => {
+                        !sequence.is_empty()
+                            && sequence[read_position - k]
+                                .eq_ignore_ascii_case(&sequence[read_position + len - k])
+                    }
+                };
+
+                if !is_equivalent {
+                    break;
+                }
+
+                max_shift = k;
+            }
+
+            // Take the leftmost of the equivalent positions that doesn't
+            // break the split.
+            let shift = (0..=max_shift).rev().find(|&shift| {
+                let position = reference_position - shift;
+
+                match op.kind() {
+                    Kind::Deletion => position + len < split || position > split,
+                    _ => position != split,
+                }
+            });
+
+            if let Some(shift) = shift.filter(|&shift| shift > 0) {
+                ops[i - 1] = Op::new(anchor.kind(), anchor.len() - shift);
+
+                match ops.get(i + 1) {
+                    Some(next) if next.kind() == anchor.kind() => {
+                        ops[i + 1] = Op::new(anchor.kind(), next.len() + shift)
+                    }
+                    _ => ops.insert(i + 1, Op::new(anchor.kind(), shift)),
+                }
+
+                reference_position -= shift;
+                read_position -= shift;
+                changed = true;
+            }
+        }
+
+        if op.kind().consumes_reference() {
+            reference_position += op.len();
+        }
+
+        if op.kind().consumes_read() {
+            read_position += op.len();
+        }
+
+        i += 1;
+    }
+
+    if !changed {
+        return None;
+    }
+
+    let mut aligned = read.clone();
+    *aligned.cigar_mut() = ops.into_iter().collect();
+    aligned.data_mut().remove(&Tag::MISMATCHED_POSITIONS);
+
+    Some(aligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::Flags,
+            record_buf::{data::field::Value, Sequence},
+        },
+    };
+
+    const REF_LEN: usize = 20;
+
+    // A run of five Cs goes around the origin, 18-20 and 1-2.
+    const REFERENCE: &[u8] = b"CCGTAAAAACGTACGTTCCC";
+
+    fn build_read(start: usize, ops: &[(Kind, usize)], sequence: &[u8]) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name("r1")
+            .set_flags(Flags::empty())
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::new(start).unwrap())
+            .set_cigar(ops.iter().map(|&(kind, len)| Op::new(kind, len)).collect())
+            .set_sequence(Sequence::from(sequence.to_vec()))
+            .set_data(
+                [(Tag::MISMATCHED_POSITIONS, Value::from("12"))]
+                    .into_iter()
+                    .collect(),
+            )
+            .build()
+    }
+
+    fn cigar(read: &RecordBuf) -> Vec<(Kind, usize)> {
+        read.cigar()
+            .as_ref()
+            .iter()
+            .map(|op| (op.kind(), op.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_left_align_indels() {
+        // An insertion at the end of the run of As moves to its start.
+        let read = build_read(
+            1,
+            &[(Kind::Match, 7), (Kind::Insertion, 1), (Kind::Match, 5)],
+            b"CCGTAAAAAACGT",
+        );
+        let aligned = left_align_indels(&read, REFERENCE, REF_LEN).unwrap();
+        assert_eq!(
+            cigar(&aligned),
+            [(Kind::Match, 4), (Kind::Insertion, 1), (Kind::Match, 8)]
+        );
+        assert!(aligned.data().get(&Tag::MISMATCHED_POSITIONS).is_none());
+
+        // A deletion of a C after the origin, at 21 on the extended reference,
+        // moves back before the split.
+        let read = build_read(
+            13,
+            &[(Kind::Match, 8), (Kind::Deletion, 1), (Kind::Match, 4)],
+            b"ACGTTCCCCGTA",
+        );
+        let aligned = left_align_indels(&read, REFERENCE, REF_LEN).unwrap();
+        assert_eq!(
+            cigar(&aligned),
+            [(Kind::Match, 5), (Kind::Deletion, 1), (Kind::Match, 7)]
+        );
+
+        // A deletion right at the split moves back clear of it.
+        let read = build_read(
+            18,
+            &[(Kind::Match, 3), (Kind::Deletion, 1), (Kind::Match, 4)],
+            b"CCCCGTA",
+        );
+        let aligned = left_align_indels(&read, REFERENCE, REF_LEN).unwrap();
+        assert_eq!(
+            cigar(&aligned),
+            [(Kind::Match, 1), (Kind::Deletion, 1), (Kind::Match, 6)]
+        );
+
+        // Moving this one would leave it at the split, so it stays put.
+        let read = build_read(
+            19,
+            &[(Kind::Match, 2), (Kind::Deletion, 1), (Kind::Match, 4)],
+            b"CCCGTA",
+        );
+        assert!(left_align_indels(&read, REFERENCE, REF_LEN).is_none());
+
+        // With =/X ops, as from pbmm2 or minimap2 --eqx, the moved bases join
+        // the = run after the indel, or go in front of a mismatch.
+        let read = build_read(
+            1,
+            &[
+                (Kind::SequenceMatch, 7),
+                (Kind::Insertion, 1),
+                (Kind::SequenceMatch, 5),
+            ],
+            b"CCGTAAAAAACGT",
+        );
+        let aligned = left_align_indels(&read, REFERENCE, REF_LEN).unwrap();
+        assert_eq!(
+            cigar(&aligned),
+            [
+                (Kind::SequenceMatch, 4),
+                (Kind::Insertion, 1),
+                (Kind::SequenceMatch, 8)
+            ]
+        );
+
+        let read = build_read(
+            1,
+            &[
+                (Kind::SequenceMatch, 7),
+                (Kind::Insertion, 1),
+                (Kind::SequenceMismatch, 1),
+                (Kind::SequenceMatch, 4),
+            ],
+            b"CCGTAAAAGACGT",
+        );
+        let aligned = left_align_indels(&read, REFERENCE, REF_LEN).unwrap();
+        assert_eq!(
+            cigar(&aligned),
+            [
+                (Kind::SequenceMatch, 4),
+                (Kind::Insertion, 1),
+                (Kind::SequenceMatch, 3),
+                (Kind::SequenceMismatch, 1),
+                (Kind::SequenceMatch, 4)
+            ]
+        );
+
+        // Nothing to move.
+        let read = build_read(1, &[(Kind::Match, 12)], b"CCGTAAAAACGT");
+        assert!(left_align_indels(&read, REFERENCE, REF_LEN).is_none());
+    }
+}
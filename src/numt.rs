@@ -0,0 +1,204 @@
+//! Flagging of reads likely to come from nuclear mitochondrial segments
+//! (NUMTs).
+//!
+//! In a whole genome alignment a read from a NUMT can still land on the
+//! mitochondrial reference. Such reads usually have an alignment to a nuclear
+//! contig, in `SA:Z` or `XA:Z`, about as good as the mitochondrial one, or
+//! more mismatches than real mtDNA would.
+
+use bstr::{BStr, ByteSlice};
+use noodles::sam::alignment::{
+    record::data::field::Tag, record_buf::data::field::Value, RecordBuf,
+};
+
+/// The tag added to flagged reads, as `NU:i:1`.
+pub const NUMT_TAG: Tag = Tag::new(b'N', b'U');
+
+// Alternative hits from BWA (`XA:Z`).
+const ALTERNATIVE_HITS: Tag = Tag::new(b'X', b'A');
+
+/// Thresholds for flagging NUMT reads.
+#[derive(Clone, Copy, Debug)]
+pub struct NumtOptions {
+    /// A nuclear alignment with at most this many more edits than the
+    /// mitochondrial one, once scaled to the same aligned length, flags the
+    /// read.
+    pub max_edit_distance_difference: i64,
+    /// A read with more edits per aligned reference base is flagged.
+    pub max_mismatch_density: f64,
+    /// Leave flagged reads out of the output rather than tagging them.
+    pub filter: bool,
+}
+
+impl Default for NumtOptions {
+    fn default() -> Self {
+        Self {
+            max_edit_distance_difference: 1,
+            max_mismatch_density: 0.02,
+            filter: false,
+        }
+    }
+}
+
+/// Returns true if a read on the mitochondrial reference is likely from a
+/// NUMT.
+///
+/// `mito_refnames` are the names of the mitochondrial reference; an other
+/// alignment to any other reference is nuclear.
+pub(crate) fn is_numt(read: &RecordBuf, mito_refnames: &[&BStr], options: &NumtOptions) -> bool {
+    let edit_distance = read.data().get(&Tag::EDIT_DISTANCE).and_then(Value::as_int);
+    let span = read.cigar().alignment_span();
+
+    if let Some(edit_distance) = edit_distance {
+        if span > 0 && edit_distance as f64 / span as f64 > options.max_mismatch_density {
+            return true;
+        }
+    }
+
+    // A supplementary covers only part of the read, so its edits are scaled
+    // up to the aligned length of the read before they are compared. Without
+    // an edit distance for the read, any nuclear hit counts.
+    let is_similar = |other_edit_distance: Option<i64>, other_span: usize| match (
+        edit_distance,
+        other_edit_distance,
+    ) {
+        (Some(nm), Some(other_nm)) if other_span > 0 && span > 0 => {
+            let scaled_nm = other_nm as f64 * span as f64 / other_span as f64;
+            scaled_nm <= (nm + options.max_edit_distance_difference) as f64
+        }
+        _ => true,
+    };
+
+    let is_nuclear = |refname: &[u8]| !mito_refnames.iter().any(|name| name.as_bytes() == refname);
+
+    // rname,pos,strand,CIGAR,mapQ,NM;
+    if let Some(Value::String(other_alignments)) = read.data().get(&Tag::OTHER_ALIGNMENTS) {
+        for fields in split_entries(other_alignments.as_ref()) {
+            if let [refname, _, _, cigar, _, nm, ..] = fields[..] {
+                if is_nuclear(refname) && is_similar(parse_int(nm), reference_span(cigar)) {
+                    return true;
+                }
+            }
+        }
+    }
+
+    // chr,±pos,CIGAR,NM;
+    if let Some(Value::String(alternative_hits)) = read.data().get(&ALTERNATIVE_HITS) {
+        for fields in split_entries(alternative_hits.as_ref()) {
+            if let [refname, _, cigar, nm, ..] = fields[..] {
+                if is_nuclear(refname) && is_similar(parse_int(nm), reference_span(cigar)) {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
+/// Tags a read as a likely NUMT.
+pub(crate) fn flag(read: &mut RecordBuf) {
+    read.data_mut().insert(NUMT_TAG, Value::from(1u8));
+}
+
+fn split_entries(value: &[u8]) -> impl Iterator<Item = Vec<&[u8]>> {
+    value
+        .split_str(";")
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry.split_str(",").collect())
+}
+
+// Returns the number of reference bases a CIGAR in a tag aligns to.
+fn reference_span(cigar: &[u8]) -> usize {
+    let mut span = 0;
+    let mut len: usize = 0;
+
+    for &c in cigar {
+        if c.is_ascii_digit() {
+            len = len * 10 + usize::from(c - b'0');
+        } else {
+            if matches!(c, b'M' | b'D' | b'N' | b'=' | b'X') {
+                span += len;
+            }
+
+            len = 0;
+        }
+    }
+
+    span
+}
+
+fn parse_int(field: &[u8]) -> Option<i64> {
+    field.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::record::cigar::{op::Kind, Op},
+    };
+
+    fn build_read(fields: Vec<(Tag, Value)>) -> RecordBuf {
+        RecordBuf::builder()
+            .set_alignment_start(Position::MIN)
+            .set_cigar([Op::new(Kind::Match, 100)].into_iter().collect())
+            .set_data(fields.into_iter().collect())
+            .build()
+    }
+
+    #[test]
+    fn test_is_numt() {
+        let mito_refnames: [&BStr; 2] = [b"chrM".into(), b"chrM_ext".into()];
+        let options = NumtOptions::default();
+
+        let nm = |n: u8| (Tag::EDIT_DISTANCE, Value::from(n));
+
+        assert!(!is_numt(&build_read(vec![nm(1)]), &mito_refnames, &options));
+
+        // Too many mismatches.
+        assert!(is_numt(&build_read(vec![nm(3)]), &mito_refnames, &options));
+
+        // A similar alternative hit on a nuclear contig, but not on the
+        // extended reference.
+        let xa = |s: &str| (ALTERNATIVE_HITS, Value::String(s.into()));
+        assert!(is_numt(
+            &build_read(vec![nm(1), xa("chr1,+10000,100M,2;")]),
+            &mito_refnames,
+            &options
+        ));
+        assert!(!is_numt(
+            &build_read(vec![
+                nm(1),
+                xa("chr1,+10000,100M,5;chrM_ext,+17000,100M,1;")
+            ]),
+            &mito_refnames,
+            &options
+        ));
+
+        // A supplementary alignment on a nuclear contig.
+        let sa = (
+            Tag::OTHER_ALIGNMENTS,
+            Value::String("chr5,2000,-,40S60M,60,0;".into()),
+        );
+        assert!(is_numt(
+            &build_read(vec![nm(0), sa]),
+            &mito_refnames,
+            &options
+        ));
+
+        // Two edits in 60 bases are more than three in the 100 of the read.
+        let sa = |s: &str| (Tag::OTHER_ALIGNMENTS, Value::String(s.into()));
+        assert!(!is_numt(
+            &build_read(vec![nm(1), sa("chr5,2000,-,40S60M,60,2;")]),
+            &mito_refnames,
+            &options
+        ));
+        assert!(is_numt(
+            &build_read(vec![nm(1), sa("chr5,2000,-,40S60M,60,1;")]),
+            &mito_refnames,
+            &options
+        ));
+    }
+}
@@ -0,0 +1,431 @@
+//! Conversion of minimap2 PAF aligned to the extended reference.
+//!
+//! A PAF record is cut at the end of the linear reference like a SAM record.
+//! With a `cg:Z` CIGAR the query is cut where the alignment crosses the end;
+//! without one it is cut in proportion to the target, and the match and
+//! alignment lengths are shared out the same way.
+
+use crate::fold_position;
+use bstr::{BStr, ByteSlice};
+use std::io::{self, BufRead, Write};
+
+// The columns of a PAF record.
+const QUERY_START: usize = 2;
+const QUERY_END: usize = 3;
+const STRAND: usize = 4;
+const TARGET_NAME: usize = 5;
+const TARGET_LEN: usize = 6;
+const TARGET_START: usize = 7;
+const TARGET_END: usize = 8;
+const MATCHES: usize = 9;
+const ALIGNMENT_LEN: usize = 10;
+const TAGS: usize = 12;
+
+// Tags describing the whole alignment base by base, which are left out of
+// the pieces of a split record. `cg:Z` is cut instead.
+const DROPPED_TAGS: [&str; 2] = ["cs:Z:", "MD:Z:"];
+
+/// The number of PAF records converted, by how.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PafStats {
+    /// Records on the extended reference within the linear reference.
+    pub unchanged: u64,
+    /// Records past the end of the linear reference, moved to its start.
+    pub shifted: u64,
+    /// Records across the end of the linear reference, cut in two.
+    pub split: u64,
+    /// Records cut in proportion to the target, without a `cg:Z` CIGAR.
+    pub split_without_cigar: u64,
+    /// Records on other references, or unmapped, written unchanged.
+    pub other: u64,
+}
+
+// A piece of a record: its query and target intervals, CIGAR and lengths.
+struct Piece {
+    query: (usize, usize),
+    target: (usize, usize),
+    cigar: Option<String>,
+    matches: usize,
+    alignment_len: usize,
+}
+
+/// Converts PAF records aligned to the extended reference `refname` back to
+/// the linear reference `target_refname`, of length `reflen`.
+///
+/// The pieces of a split record keep the query name, and are told apart by
+/// their query intervals. Their `cs:Z` and `MD:Z` tags are dropped, and the
+/// other tags are kept as they are. A `cg:Z` CIGAR of a split record has to
+/// span its query and target intervals.
+pub fn convert_paf<R, W>(
+    reader: R,
+    writer: &mut W,
+    refname: &BStr,
+    target_refname: &BStr,
+    reflen: usize,
+) -> io::Result<PafStats>
+where
+    R: BufRead,
+    W: Write,
+{
+    let mut stats = PafStats::default();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields: Vec<String> = line.split('\t').map(String::from).collect();
+
+        if fields.len() < TAGS || fields[TARGET_NAME].as_bytes() != refname.as_bytes() {
+            writeln!(writer, "{line}")?;
+            stats.other += 1;
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("PAF line {}: invalid coordinates", i + 1),
+            )
+        };
+
+        let column = |j: usize| fields[j].parse::<usize>().map_err(|_| invalid());
+
+        let query = (column(QUERY_START)?, column(QUERY_END)?);
+        let target = (column(TARGET_START)?, column(TARGET_END)?);
+        let matches = column(MATCHES)?;
+        let alignment_len = column(ALIGNMENT_LEN)?;
+
+        if query.0 > query.1 || target.0 > target.1 {
+            return Err(invalid());
+        }
+
+        fields[TARGET_NAME] = target_refname.to_string();
+        fields[TARGET_LEN] = reflen.to_string();
+
+        if target.1 <= reflen {
+            stats.unchanged += 1;
+            writeln!(writer, "{}", fields.join("\t"))?;
+            continue;
+        }
+
+        if target.0 >= reflen {
+            stats.shifted += 1;
+            let start = fold_position(target.0, reflen);
+            fields[TARGET_START] = start.to_string();
+            fields[TARGET_END] = (start + target.1 - target.0).to_string();
+            writeln!(writer, "{}", fields.join("\t"))?;
+            continue;
+        }
+
+        let cigar = fields[TAGS..]
+            .iter()
+            .find_map(|tag| tag.strip_prefix("cg:Z:"));
+
+        let record = Piece {
+            query,
+            target,
+            cigar: cigar.map(String::from),
+            matches,
+            alignment_len,
+        };
+
+        let is_reverse = fields[STRAND] == "-";
+
+        let (left, right) = match cigar {
+            Some(cigar) => {
+                let invalid_cigar = || {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("PAF line {}: invalid cg:Z CIGAR {cigar}", i + 1),
+                    )
+                };
+
+                let ops = parse_cigar(cigar).ok_or_else(invalid_cigar)?;
+
+                // The CIGAR has to span the query and target intervals, or
+                // the pieces can't be cut from them.
+                let len = |consumes: fn(u8) -> bool| {
+                    ops.iter()
+                        .filter(|&&(kind, _)| consumes(kind))
+                        .map(|&(_, len)| len)
+                        .sum::<usize>()
+                };
+
+                if len(|kind| kind != b'D') != query.1 - query.0
+                    || len(|kind| kind != b'I') != target.1 - target.0
+                {
+                    return Err(invalid_cigar());
+                }
+
+                split_with_cigar(&record, &ops, is_reverse, reflen)
+            }
+            None => {
+                stats.split_without_cigar += 1;
+                split_proportionally(&record, is_reverse, reflen)
+            }
+        };
+
+        stats.split += 1;
+
+        for piece in [left, right] {
+            writeln!(writer, "{}", piece_fields(&fields, &piece).join("\t"))?;
+        }
+    }
+
+    Ok(stats)
+}
+
+// Returns the columns of `fields` for a piece of the record.
+fn piece_fields(fields: &[String], piece: &Piece) -> Vec<String> {
+    let mut fields: Vec<String> = fields
+        .iter()
+        .enumerate()
+        .filter(|(j, field)| {
+            *j < TAGS || !DROPPED_TAGS.iter().any(|prefix| field.starts_with(prefix))
+        })
+        .map(|(_, field)| field.clone())
+        .collect();
+
+    fields[QUERY_START] = piece.query.0.to_string();
+    fields[QUERY_END] = piece.query.1.to_string();
+    fields[TARGET_START] = piece.target.0.to_string();
+    fields[TARGET_END] = piece.target.1.to_string();
+    fields[MATCHES] = piece.matches.to_string();
+    fields[ALIGNMENT_LEN] = piece.alignment_len.to_string();
+
+    if let Some(cigar) = &piece.cigar {
+        for field in fields[TAGS..].iter_mut() {
+            if field.starts_with("cg:Z:") {
+                *field = format!("cg:Z:{cigar}");
+            }
+        }
+    }
+
+    fields
+}
+
+// Parses a CIGAR into its operations, or returns `None` if it is invalid.
+fn parse_cigar(cigar: &str) -> Option<Vec<(u8, usize)>> {
+    let mut ops = Vec::new();
+    let mut len = None;
+
+    for &c in cigar.as_bytes() {
+        if c.is_ascii_digit() {
+            len = Some(len.unwrap_or(0) * 10 + usize::from(c - b'0'));
+        } else if matches!(c, b'M' | b'I' | b'D' | b'=' | b'X') {
+            ops.push((c, len.take()?));
+        } else {
+            return None;
+        }
+    }
+
+    len.is_none().then_some(ops)
+}
+
+// Cuts a record where its CIGAR crosses the end of the linear reference. An
+// insertion at the end stays with the left piece.
+fn split_with_cigar(
+    record: &Piece,
+    ops: &[(u8, usize)],
+    is_reverse: bool,
+    reflen: usize,
+) -> (Piece, Piece) {
+    let left_target_len = reflen - record.target.0;
+
+    let mut left_ops = Vec::new();
+    let mut right_ops = Vec::new();
+    let mut target_len = 0;
+    let mut query_len = 0;
+
+    for &(kind, len) in ops {
+        if !right_ops.is_empty() {
+            right_ops.push((kind, len));
+            continue;
+        }
+
+        let consumes_target = kind != b'I';
+        let consumes_query = kind != b'D';
+
+        if consumes_target && target_len + len > left_target_len {
+            let left_len = left_target_len - target_len;
+
+            if left_len > 0 {
+                left_ops.push((kind, left_len));
+
+                if consumes_query {
+                    query_len += left_len;
+                }
+            }
+
+            right_ops.push((kind, len - left_len));
+            continue;
+        }
+
+        left_ops.push((kind, len));
+
+        if consumes_target {
+            target_len += len;
+        }
+
+        if consumes_query {
+            query_len += len;
+        }
+    }
+
+    let alignment_len = |ops: &[(u8, usize)]| ops.iter().map(|&(_, len)| len).sum::<usize>();
+    let left_alignment_len = alignment_len(&left_ops);
+
+    // With =/X the matches of each piece are known, otherwise they are shared
+    // out by alignment length.
+    let left_matches = if ops.iter().any(|&(kind, _)| kind == b'=') {
+        left_ops
+            .iter()
+            .filter(|&&(kind, _)| kind == b'=')
+            .map(|&(_, len)| len)
+            .sum()
+    } else {
+        share(record.matches, left_alignment_len, alignment_len(ops))
+    };
+
+    let format_cigar = |ops: &[(u8, usize)]| {
+        ops.iter()
+            .map(|&(kind, len)| format!("{len}{}", char::from(kind)))
+            .collect::<String>()
+    };
+
+    let (left_query, right_query) = cut_query(record.query, query_len, is_reverse);
+
+    (
+        Piece {
+            query: left_query,
+            target: (record.target.0, reflen),
+            cigar: Some(format_cigar(&left_ops)),
+            matches: left_matches,
+            alignment_len: left_alignment_len,
+        },
+        Piece {
+            query: right_query,
+            target: (0, record.target.1 - reflen),
+            cigar: Some(format_cigar(&right_ops)),
+            matches: record.matches.saturating_sub(left_matches),
+            alignment_len: alignment_len(&right_ops),
+        },
+    )
+}
+
+// Cuts a record without a CIGAR in proportion to the target on either side of
+// the end of the linear reference.
+fn split_proportionally(record: &Piece, is_reverse: bool, reflen: usize) -> (Piece, Piece) {
+    let left_target_len = reflen - record.target.0;
+    let target_len = record.target.1 - record.target.0;
+
+    let query_len = share(record.query.1 - record.query.0, left_target_len, target_len);
+    let left_matches = share(record.matches, left_target_len, target_len);
+    let left_alignment_len = share(record.alignment_len, left_target_len, target_len);
+
+    let (left_query, right_query) = cut_query(record.query, query_len, is_reverse);
+
+    (
+        Piece {
+            query: left_query,
+            target: (record.target.0, reflen),
+            cigar: None,
+            matches: left_matches,
+            alignment_len: left_alignment_len,
+        },
+        Piece {
+            query: right_query,
+            target: (0, record.target.1 - reflen),
+            cigar: None,
+            matches: record.matches - left_matches,
+            alignment_len: record.alignment_len - left_alignment_len,
+        },
+    )
+}
+
+// Cuts the query interval after `left_len` bases of the alignment. On the
+// reverse strand the alignment runs from the end of the query.
+fn cut_query(
+    query: (usize, usize),
+    left_len: usize,
+    is_reverse: bool,
+) -> ((usize, usize), (usize, usize)) {
+    if is_reverse {
+        ((query.1 - left_len, query.1), (query.0, query.1 - left_len))
+    } else {
+        ((query.0, query.0 + left_len), (query.0 + left_len, query.1))
+    }
+}
+
+// Returns the share of `total` for `part` of `whole`, rounded.
+fn share(total: usize, part: usize, whole: usize) -> usize {
+    if whole == 0 {
+        return 0;
+    }
+
+    (total * part + whole / 2) / whole
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_paf() -> io::Result<()> {
+        const PAF: &str = "\
+r1\t40\t0\t38\t+\tchrM_ext\t130\t90\t127\t35\t39\t60\ttp:A:P\tcg:Z:8=2I5=1X14=1D8=\tcs:Z::8
+r2\t40\t5\t35\t-\tchrM_ext\t130\t95\t125\t24\t30\t60\ttp:A:P
+r3\t20\t0\t20\t+\tchrM_ext\t130\t105\t125\t20\t20\t60\ttp:A:P
+r4\t20\t0\t20\t+\tchrM_ext\t130\t10\t30\t20\t20\t60\ttp:A:P
+r5\t20\t0\t20\t+\tchr1\t1000\t10\t30\t20\t20\t60\ttp:A:P
+";
+
+        let mut output = Vec::new();
+        let stats = convert_paf(
+            PAF.as_bytes(),
+            &mut output,
+            "chrM_ext".into(),
+            "chrM".into(),
+            100,
+        )?;
+
+        // r1 crosses 100 in the middle of the 5=, after 8+2+2 query bases.
+        // r2 is cut a sixth of the way along, from the end of the query.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+r1\t40\t0\t12\t+\tchrM\t100\t90\t100\t10\t12\t60\ttp:A:P\tcg:Z:8=2I2=
+r1\t40\t12\t38\t+\tchrM\t100\t0\t27\t25\t27\t60\ttp:A:P\tcg:Z:3=1X14=1D8=
+r2\t40\t30\t35\t-\tchrM\t100\t95\t100\t4\t5\t60\ttp:A:P
+r2\t40\t5\t30\t-\tchrM\t100\t0\t25\t20\t25\t60\ttp:A:P
+r3\t20\t0\t20\t+\tchrM\t100\t5\t25\t20\t20\t60\ttp:A:P
+r4\t20\t0\t20\t+\tchrM\t100\t10\t30\t20\t20\t60\ttp:A:P
+r5\t20\t0\t20\t+\tchr1\t1000\t10\t30\t20\t20\t60\ttp:A:P
+"
+        );
+
+        assert_eq!(
+            stats,
+            PafStats {
+                unchanged: 1,
+                shifted: 1,
+                split: 2,
+                split_without_cigar: 1,
+                other: 1,
+            }
+        );
+
+        // The CIGAR is longer than the query and target intervals.
+        const MISMATCHED: &str =
+            "r1\t40\t0\t12\t+\tchrM_ext\t130\t90\t102\t12\t12\t60\ttp:A:P\tcg:Z:20=\n";
+        let error = convert_paf(
+            MISMATCHED.as_bytes(),
+            &mut Vec::new(),
+            "chrM_ext".into(),
+            "chrM".into(),
+            100,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Slicing of per-base `B` array tags for split reads.
+//!
+//! Several platforms store one value per base of SEQ in array tags, e.g. the
+//! PacBio kinetics (`fi`, `fp`, `ri`, `rp`) or the ONT move table (`mv`). When
+//! a read is split these arrays have to be cut at the same place as SEQ and
+//! QUAL, taking into account the order in which the values are stored.
+
+use noodles::sam::alignment::{
+    record::data::field::Tag,
+    record_buf::data::field::{value::Array, Value},
+    RecordBuf,
+};
+use std::ops::Range;
+
+// The ONT tag with the number of signal samples trimmed from the start.
+const TRIMMED_SAMPLES: Tag = Tag::new(b't', b's');
+
+/// How the values of a per-base array tag are laid out relative to the read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PerBaseLayout {
+    /// One value per base, in the order the read was sequenced, e.g. PacBio
+    /// forward pass kinetics.
+    Forward,
+    /// One value per base, in the reverse of the order the read was sequenced,
+    /// e.g. PacBio reverse pass kinetics.
+    Reverse,
+    /// An ONT move table: the stride followed by one entry per signal block,
+    /// where a 1 marks the block in which a new base was emitted.
+    MoveTable,
+}
+
+/// A registry of per-base array tags and their layouts.
+#[derive(Clone, Debug)]
+pub struct PerBaseTags(Vec<(Tag, PerBaseLayout)>);
+
+impl Default for PerBaseTags {
+    /// The known per-base array tags.
+    fn default() -> Self {
+        Self(vec![
+            // PacBio HiFi forward/reverse pass IPD and pulse width.
+            (Tag::new(b'f', b'i'), PerBaseLayout::Forward),
+            (Tag::new(b'f', b'p'), PerBaseLayout::Forward),
+            (Tag::new(b'r', b'i'), PerBaseLayout::Reverse),
+            (Tag::new(b'r', b'p'), PerBaseLayout::Reverse),
+            // PacBio subread IPD and pulse width.
+            (Tag::new(b'i', b'p'), PerBaseLayout::Forward),
+            (Tag::new(b'p', b'w'), PerBaseLayout::Forward),
+            // ONT move table.
+            (Tag::new(b'm', b'v'), PerBaseLayout::MoveTable),
+        ])
+    }
+}
+
+impl PerBaseTags {
+    /// Adds a tag to the registry, replacing the layout of a known tag.
+    pub fn insert(&mut self, tag: Tag, layout: PerBaseLayout) {
+        match self.0.iter_mut().find(|(t, _)| *t == tag) {
+            Some(entry) => entry.1 = layout,
+            None => self.0.push((tag, layout)),
+        }
+    }
+
+    /// Returns an iterator over the registered tags and their layouts.
+    pub fn iter(&self) -> impl Iterator<Item = (Tag, PerBaseLayout)> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+/// Parses a user supplied per-base tag of the form `TAG[:forward|reverse]`.
+/// The layout defaults to forward.
+pub fn parse_per_base_tag(s: &str) -> Result<(Tag, PerBaseLayout), String> {
+    let (name, layout) = match s.split_once(':') {
+        Some((name, "forward")) => (name, PerBaseLayout::Forward),
+        Some((name, "reverse")) => (name, PerBaseLayout::Reverse),
+        Some((_, layout)) => {
+            return Err(format!(
+                "invalid layout: {layout}, expected forward or reverse"
+            ))
+        }
+        None => (s, PerBaseLayout::Forward),
+    };
+
+    Ok((parse_tag(name)?, layout))
+}
+
+/// Parses a user supplied tag name, e.g. `CB`.
+pub fn parse_tag(s: &str) -> Result<Tag, String> {
+    match s.as_bytes() {
+        &[a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok(Tag::new(a, b)),
+        _ => Err(format!("invalid tag: {s}")),
+    }
+}
+
+/// Slices the registered per-base array tags of `read`, a piece of a split
+/// record, to the bases in `range`.
+///
+/// `range` indexes into the SEQ of the original record, which is `len` bases
+/// long. Arrays whose length doesn't match SEQ are removed with a warning,
+/// since there is no way to know which values belong to the piece.
+pub(crate) fn slice_per_base_tags(
+    read: &mut RecordBuf,
+    per_base_tags: &PerBaseTags,
+    len: usize,
+    is_reverse_complemented: bool,
+    range: Range<usize>,
+) {
+    // The range in the orientation the read was sequenced.
+    let sequenced_range = if is_reverse_complemented {
+        len - range.end..len - range.start
+    } else {
+        range
+    };
+
+    for (tag, layout) in per_base_tags.iter() {
+        let Some(Value::Array(array)) = read.data().get(&tag) else {
+            continue;
+        };
+
+        // Reverse pass values are stored last base first.
+        let sliced = match layout {
+            PerBaseLayout::Forward => {
+                (array_len(array) == len).then(|| (slice_array(array, sequenced_range.clone()), 0))
+            }
+            PerBaseLayout::Reverse => (array_len(array) == len).then(|| {
+                let reversed_range = len - sequenced_range.end..len - sequenced_range.start;
+                (slice_array(array, reversed_range), 0)
+            }),
+            PerBaseLayout::MoveTable => slice_move_table(array, len, sequenced_range.clone()),
+        };
+
+        match sliced {
+            Some((array, skipped_samples)) => {
+                let data = read.data_mut();
+
+                // The signal of the piece now starts further along.
+                if skipped_samples > 0 {
+                    if let Some(trimmed) = data.get(&TRIMMED_SAMPLES).and_then(Value::as_int) {
+                        let trimmed = i32::try_from(trimmed + skipped_samples).unwrap_or(i32::MAX);
+                        data.insert(TRIMMED_SAMPLES, Value::from(trimmed));
+                    }
+                }
+
+                data.insert(tag, Value::Array(array));
+            }
+            None => {
+                log::warn!(
+                    "Read: {:?} has a {:?} array that doesn't match its sequence, removing it from the split read.",
+                    read.name(),
+                    tag
+                );
+
+                read.data_mut().remove(&tag);
+            }
+        }
+    }
+}
+
+// Slices a move table to the bases in `range`. Also returns the number of
+// signal samples before the first block of the piece.
+fn slice_move_table(array: &Array, len: usize, range: Range<usize>) -> Option<(Array, i64)> {
+    let values = array_values(array);
+    let (&stride, moves) = values.split_first()?;
+
+    // The block index at which each base is emitted.
+    let emissions: Vec<usize> = moves
+        .iter()
+        .enumerate()
+        .filter(|(_, &m)| m == 1)
+        .map(|(i, _)| i)
+        .collect();
+
+    if emissions.len() != len {
+        return None;
+    }
+
+    let start = emissions.get(range.start).copied().unwrap_or(moves.len());
+    let end = emissions.get(range.end).copied().unwrap_or(moves.len());
+
+    let mut sliced = slice_array(array, 0..1);
+    append_array(&mut sliced, &slice_array(array, start + 1..end + 1));
+
+    Some((sliced, stride * start as i64))
+}
+
+fn array_len(array: &Array) -> usize {
+    match array {
+        Array::Int8(values) => values.len(),
+        Array::UInt8(values) => values.len(),
+        Array::Int16(values) => values.len(),
+        Array::UInt16(values) => values.len(),
+        Array::Int32(values) => values.len(),
+        Array::UInt32(values) => values.len(),
+        Array::Float(values) => values.len(),
+    }
+}
+
+// The values of an integer array. Float arrays can't be move tables.
+fn array_values(array: &Array) -> Vec<i64> {
+    match array {
+        Array::Float(_) => Vec::new(),
+        Array::Int8(values) => values.iter().map(|&n| i64::from(n)).collect(),
+        Array::UInt8(values) => values.iter().map(|&n| i64::from(n)).collect(),
+        Array::Int16(values) => values.iter().map(|&n| i64::from(n)).collect(),
+        Array::UInt16(values) => values.iter().map(|&n| i64::from(n)).collect(),
+        Array::Int32(values) => values.iter().map(|&n| i64::from(n)).collect(),
+        Array::UInt32(values) => values.iter().map(|&n| i64::from(n)).collect(),
+    }
+}
+
+// Returns the values in `range` as an array of the same subtype.
+fn slice_array(array: &Array, range: Range<usize>) -> Array {
+    match array {
+        Array::Int8(values) => Array::Int8(values[range].to_vec()),
+        Array::UInt8(values) => Array::UInt8(values[range].to_vec()),
+        Array::Int16(values) => Array::Int16(values[range].to_vec()),
+        Array::UInt16(values) => Array::UInt16(values[range].to_vec()),
+        Array::Int32(values) => Array::Int32(values[range].to_vec()),
+        Array::UInt32(values) => Array::UInt32(values[range].to_vec()),
+        Array::Float(values) => Array::Float(values[range].to_vec()),
+    }
+}
+
+fn append_array(dst: &mut Array, src: &Array) {
+    match (dst, src) {
+        (Array::Int8(a), Array::Int8(b)) => a.extend_from_slice(b),
+        (Array::UInt8(a), Array::UInt8(b)) => a.extend_from_slice(b),
+        (Array::Int16(a), Array::Int16(b)) => a.extend_from_slice(b),
+        (Array::UInt16(a), Array::UInt16(b)) => a.extend_from_slice(b),
+        (Array::Int32(a), Array::Int32(b)) => a.extend_from_slice(b),
+        (Array::UInt32(a), Array::UInt32(b)) => a.extend_from_slice(b),
+        (Array::Float(a), Array::Float(b)) => a.extend_from_slice(b),
+        _ => unreachable!("arrays sliced from the same array have the same subtype"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use noodles::sam::alignment::record_buf::Data;
+
+    fn build_read(fields: Vec<(Tag, Value)>) -> RecordBuf {
+        let data: Data = fields.into_iter().collect();
+        RecordBuf::builder()
+            .set_name("Read1")
+            .set_data(data)
+            .build()
+    }
+
+    fn get_array<'a>(read: &'a RecordBuf, tag: &[u8; 2]) -> Option<&'a Value> {
+        read.data().get(&Tag::new(tag[0], tag[1]))
+    }
+
+    #[test]
+    fn test_slice_kinetics() {
+        let fields = vec![
+            (Tag::new(b'f', b'i'), Value::from(vec![0u8, 1, 2, 3, 4])),
+            (
+                Tag::new(b'r', b'i'),
+                Value::from(vec![40u16, 30, 20, 10, 0]),
+            ),
+        ];
+
+        // Forward read: SEQ 0..2 is the first two bases sequenced.
+        let mut read = build_read(fields.clone());
+        slice_per_base_tags(&mut read, &PerBaseTags::default(), 5, false, 0..2);
+        assert_eq!(get_array(&read, b"fi"), Some(&Value::from(vec![0u8, 1])));
+        assert_eq!(get_array(&read, b"ri"), Some(&Value::from(vec![10u16, 0])));
+
+        // Reverse complemented read: SEQ 0..2 is the last two bases sequenced.
+        let mut read = build_read(fields);
+        slice_per_base_tags(&mut read, &PerBaseTags::default(), 5, true, 0..2);
+        assert_eq!(get_array(&read, b"fi"), Some(&Value::from(vec![3u8, 4])));
+        assert_eq!(get_array(&read, b"ri"), Some(&Value::from(vec![40u16, 30])));
+    }
+
+    #[test]
+    fn test_slice_move_table() {
+        // Stride 5; bases emitted at blocks 0, 2, 3 and 6.
+        let fields = vec![
+            (
+                Tag::new(b'm', b'v'),
+                Value::from(vec![5i8, 1, 0, 1, 1, 0, 0, 1, 0]),
+            ),
+            (TRIMMED_SAMPLES, Value::from(100)),
+        ];
+
+        let mut read = build_read(fields.clone());
+        slice_per_base_tags(&mut read, &PerBaseTags::default(), 4, false, 0..2);
+        assert_eq!(
+            get_array(&read, b"mv"),
+            Some(&Value::from(vec![5i8, 1, 0, 1]))
+        );
+        assert_eq!(get_array(&read, b"ts"), Some(&Value::from(100)));
+
+        let mut read = build_read(fields);
+        slice_per_base_tags(&mut read, &PerBaseTags::default(), 4, false, 2..4);
+        assert_eq!(
+            get_array(&read, b"mv"),
+            Some(&Value::from(vec![5i8, 1, 0, 0, 1, 0]))
+        );
+        assert_eq!(get_array(&read, b"ts"), Some(&Value::from(115)));
+    }
+
+    #[test]
+    fn test_slice_user_tag_and_mismatched_length() {
+        let mut per_base_tags = PerBaseTags::default();
+        let (tag, layout) = parse_per_base_tag("xk:reverse").unwrap();
+        per_base_tags.insert(tag, layout);
+
+        let mut read = build_read(vec![
+            (tag, Value::from(vec![1i32, 2, 3])),
+            (Tag::new(b'f', b'p'), Value::from(vec![1u8, 2])),
+        ]);
+        slice_per_base_tags(&mut read, &per_base_tags, 3, false, 1..3);
+        assert_eq!(get_array(&read, b"xk"), Some(&Value::from(vec![1i32, 2])));
+        assert_eq!(get_array(&read, b"fp"), None);
+    }
+
+    #[test]
+    fn test_parse_per_base_tag() {
+        assert_eq!(
+            parse_per_base_tag("xk"),
+            Ok((Tag::new(b'x', b'k'), PerBaseLayout::Forward))
+        );
+        assert_eq!(
+            parse_per_base_tag("xk:reverse"),
+            Ok((Tag::new(b'x', b'k'), PerBaseLayout::Reverse))
+        );
+        assert!(parse_per_base_tag("xk:sideways").is_err());
+        assert!(parse_per_base_tag("xkz").is_err());
+    }
+}
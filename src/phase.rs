@@ -0,0 +1,757 @@
+//! Phasing of heteroplasmic variants with long reads.
+//!
+//! A HiFi read can cover the whole mitogenome, so the alleles it has at the
+//! heteroplasmic sites are the haplotype of one mtDNA molecule. The pieces of
+//! a read cut at the origin by the conversion, and its supplementaries, are
+//! put back together by name first, so a read crossing the origin isn't phased
+//! as two. The reads are then clustered around the haplotypes their alleles
+//! add up to.
+
+use crate::{
+    depth::{aligned_bases, is_counted, DepthOptions},
+    RIGHT_SUFFIX,
+};
+use bstr::{BStr, BString, ByteSlice};
+use noodles::sam::{
+    alignment::{io::Write, record::data::field::Tag, record_buf::data::field::Value, RecordBuf},
+    header::record::value::map::header::{sort_order, tag},
+    io::Writer,
+    Header,
+};
+use noodles_util::alignment::io::Reader;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Write as StdWrite},
+};
+
+/// The tag added to phased reads, as `HP:i:1` for the most common haplotype.
+pub const HAPLOTYPE_TAG: Tag = Tag::new(b'H', b'P');
+
+// The most rounds of assigning reads and updating the haplotypes.
+const MAX_ITERATIONS: usize = 20;
+
+/// A heteroplasmic site and its alleles, the reference allele first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseSite {
+    /// The 1-based position.
+    pub position: usize,
+    pub alleles: Vec<u8>,
+}
+
+/// Reads the SNVs on `refname` from a VCF. Indels and sites with symbolic
+/// alleles are skipped.
+pub fn read_phase_sites<R>(reader: R, refname: &BStr) -> io::Result<Vec<PhaseSite>>
+where
+    R: BufRead,
+{
+    let mut sites: Vec<PhaseSite> = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+
+        let [chrom, position, _, reference, alternate, ..] = fields[..] else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("VCF line {}: expected at least 5 fields", i + 1),
+            ));
+        };
+
+        if chrom.as_bytes() != refname.as_bytes() {
+            continue;
+        }
+
+        let position: usize = position.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("VCF line {}: invalid position {position}", i + 1),
+            )
+        })?;
+
+        let alleles: Vec<&str> = std::iter::once(reference)
+            .chain(alternate.split(','))
+            .collect();
+
+        if position == 0
+            || !alleles
+                .iter()
+                .all(|allele| matches!(allele.as_bytes(), [b'A' | b'C' | b'G' | b'T']))
+        {
+            continue;
+        }
+
+        let alleles = alleles.iter().map(|allele| allele.as_bytes()[0]).collect();
+
+        // The same position in more than one record is merged.
+        match sites.iter_mut().find(|site| site.position == position) {
+            Some(site) => {
+                for allele in alleles {
+                    if !site.alleles.contains(&allele) {
+                        site.alleles.push(allele);
+                    }
+                }
+            }
+            None => sites.push(PhaseSite { position, alleles }),
+        }
+    }
+
+    sites.sort_by_key(|site| site.position);
+
+    Ok(sites)
+}
+
+/// Options for phasing the reads.
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseOptions {
+    /// Filters on the reads and bases used.
+    pub depth: DepthOptions,
+    /// A haplotype needs at least this many reads seeding it.
+    pub min_reads: usize,
+    /// A haplotype needs at least this fraction of the reads seeding any
+    /// haplotype.
+    pub min_fraction: f64,
+    /// A read is only assigned to a haplotype it differs from at no more than
+    /// this fraction of the sites it covers.
+    pub max_mismatch_fraction: f64,
+}
+
+impl Default for PhaseOptions {
+    fn default() -> Self {
+        Self {
+            depth: DepthOptions {
+                min_mapping_quality: 20,
+                min_base_quality: 20,
+            },
+            min_reads: 3,
+            min_fraction: 0.01,
+            max_mismatch_fraction: 0.2,
+        }
+    }
+}
+
+/// A haplotype, as the index of its allele at each site.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Haplotype {
+    pub alleles: Vec<usize>,
+    /// The number of reads assigned to it.
+    pub reads: usize,
+}
+
+/// The haplotypes found, most common first, and the number of reads with
+/// alleles at any of the sites that couldn't be assigned to one.
+#[derive(Clone, Debug, Default)]
+pub struct Phasing {
+    pub haplotypes: Vec<Haplotype>,
+    pub unassigned: usize,
+}
+
+impl Phasing {
+    /// Writes the haplotypes as a TSV of their number, as in the `HP` tag,
+    /// the number and fraction of the reads assigned to them, and their
+    /// alleles, e.g. `3G,150T`.
+    pub fn write<W>(&self, writer: &mut W, sites: &[PhaseSite]) -> io::Result<()>
+    where
+        W: StdWrite,
+    {
+        let assigned: usize = self
+            .haplotypes
+            .iter()
+            .map(|haplotype| haplotype.reads)
+            .sum();
+
+        writeln!(writer, "haplotype\treads\tfraction\talleles")?;
+
+        for (i, haplotype) in self.haplotypes.iter().enumerate() {
+            let alleles: Vec<String> = sites
+                .iter()
+                .zip(&haplotype.alleles)
+                .map(|(site, &allele)| {
+                    format!("{}{}", site.position, char::from(site.alleles[allele]))
+                })
+                .collect();
+
+            writeln!(
+                writer,
+                "{}\t{}\t{:.4}\t{}",
+                i + 1,
+                haplotype.reads,
+                haplotype.reads as f64 / assigned.max(1) as f64,
+                alleles.join(",")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// A read's alleles by site. `None` is a site whose pieces disagree.
+type Observations = BTreeMap<usize, Option<usize>>;
+
+/// Phases the reads on `refname`, folded onto a circular reference of length
+/// `reflen`, at the heteroplasmic `sites`, and writes every record with the
+/// haplotype of its read in an `HP:i` tag.
+///
+/// Only the records on `refname` are held in memory until the reads are
+/// phased; the others are written as they're read. In a coordinate sorted
+/// file the reads are phased where the records on `refname` end, so the order
+/// is kept. Otherwise the records on `refname` are written last.
+pub fn phase_sam(
+    reader: &mut Reader<Box<dyn BufRead>>,
+    bufwriter: &mut Box<dyn StdWrite>,
+    refname: &BStr,
+    reflen: usize,
+    sites: &[PhaseSite],
+    options: &PhaseOptions,
+) -> io::Result<Phasing> {
+    let header = reader.read_header()?;
+    let reference_sequence_id = header.reference_sequences().get_index_of(refname);
+
+    if reference_sequence_id.is_none() {
+        log::warn!(
+            "Reference: {} is not in the header, no reads will be phased.",
+            refname
+        );
+    }
+
+    let is_coordinate_sorted = header
+        .header()
+        .and_then(|map| map.other_fields().get(&tag::SORT_ORDER))
+        .is_some_and(|order| order == sort_order::COORDINATE);
+
+    let site_indexes: HashMap<usize, usize> = sites
+        .iter()
+        .enumerate()
+        .filter(|(_, site)| site.position <= reflen)
+        .map(|(i, site)| (site.position - 1, i))
+        .collect();
+
+    let mut writer = Writer::new(bufwriter);
+    writer.write_header(&header)?;
+
+    let mut records = Vec::new();
+    let mut reads = Reads::default();
+    let mut phased: Option<(Phasing, HashMap<BString, usize>)> = None;
+
+    for result in reader.records(&header) {
+        let record = RecordBuf::try_from_alignment_record(&header, &result?)?;
+
+        let is_on_refname = reference_sequence_id.is_some()
+            && record.reference_sequence_id() == reference_sequence_id;
+
+        if !is_on_refname {
+            // The records on `refname` are all in, so they can be phased.
+            if is_coordinate_sorted && phased.is_none() && !records.is_empty() {
+                let (phasing, read_haplotypes) = reads.phase(sites, options);
+                write_tagged(&mut writer, &header, records.drain(..), &read_haplotypes)?;
+                phased = Some((phasing, read_haplotypes));
+            }
+
+            writer.write_alignment_record(&header, &record)?;
+            continue;
+        }
+
+        if let Some((_, read_haplotypes)) = &phased {
+            // Out of order, after the reads were phased.
+            write_tagged(&mut writer, &header, [record], read_haplotypes)?;
+            continue;
+        }
+
+        if reflen > 0 && is_counted(&record, &options.depth)? {
+            let observed = observe(&record, sites, &site_indexes, reflen, options);
+            reads.add(read_name(&record), observed);
+        }
+
+        records.push(record);
+    }
+
+    let phasing = match phased {
+        Some((phasing, _)) => phasing,
+        None => {
+            let (phasing, read_haplotypes) = reads.phase(sites, options);
+            write_tagged(&mut writer, &header, records, &read_haplotypes)?;
+            phasing
+        }
+    };
+
+    Ok(phasing)
+}
+
+// Each read's alleles, in the order the reads were seen.
+#[derive(Default)]
+struct Reads {
+    names: Vec<BString>,
+    observations: HashMap<BString, Observations>,
+}
+
+impl Reads {
+    // Adds the alleles of one record of a read.
+    fn add(&mut self, name: BString, observed: Vec<(usize, usize)>) {
+        if !self.observations.contains_key(&name) {
+            self.names.push(name.clone());
+        }
+
+        let read_observations = self.observations.entry(name).or_default();
+
+        for (site, allele) in observed {
+            match read_observations.get(&site) {
+                Some(&seen) if seen != Some(allele) => {
+                    read_observations.insert(site, None);
+                }
+                Some(_) => {}
+                None => {
+                    read_observations.insert(site, Some(allele));
+                }
+            }
+        }
+    }
+
+    // Clusters the reads, returning the phasing and each assigned read's
+    // haplotype.
+    fn phase(
+        &self,
+        sites: &[PhaseSite],
+        options: &PhaseOptions,
+    ) -> (Phasing, HashMap<BString, usize>) {
+        let names: Vec<&BString> = self
+            .names
+            .iter()
+            .filter(|name| self.observations[*name].values().any(Option::is_some))
+            .collect();
+
+        let profiles: Vec<Vec<Option<usize>>> = names
+            .iter()
+            .map(|name| {
+                let read_observations = &self.observations[*name];
+                (0..sites.len())
+                    .map(|site| read_observations.get(&site).copied().flatten())
+                    .collect()
+            })
+            .collect();
+
+        let (haplotypes, assignments) = cluster(&profiles, options);
+
+        let phasing = Phasing {
+            unassigned: assignments.iter().filter(|hp| hp.is_none()).count(),
+            haplotypes,
+        };
+
+        let read_haplotypes = names
+            .into_iter()
+            .zip(assignments)
+            .filter_map(|(name, hp)| Some((name.clone(), hp?)))
+            .collect();
+
+        (phasing, read_haplotypes)
+    }
+}
+
+// Writes records on the phased reference, with the haplotype of their read.
+fn write_tagged<W, I>(
+    writer: &mut Writer<W>,
+    header: &Header,
+    records: I,
+    read_haplotypes: &HashMap<BString, usize>,
+) -> io::Result<()>
+where
+    W: StdWrite,
+    I: IntoIterator<Item = RecordBuf>,
+{
+    for mut record in records {
+        if let Some(&hp) = read_haplotypes.get(&read_name(&record)) {
+            record
+                .data_mut()
+                .insert(HAPLOTYPE_TAG, Value::from((hp + 1) as i32));
+        }
+
+        writer.write_alignment_record(header, &record)?;
+    }
+
+    Ok(())
+}
+
+// The name of a record without the `_right` suffix of a split read.
+fn read_name(record: &RecordBuf) -> BString {
+    let name: &[u8] = record.name().map(|name| name.as_ref()).unwrap_or_default();
+    BString::from(name.strip_suffix(RIGHT_SUFFIX).unwrap_or(name))
+}
+
+// Returns the site and allele of each site the record has one of the alleles
+// at.
+fn observe(
+    record: &RecordBuf,
+    sites: &[PhaseSite],
+    site_indexes: &HashMap<usize, usize>,
+    reflen: usize,
+    options: &PhaseOptions,
+) -> Vec<(usize, usize)> {
+    // A `RecordBuf` has already been parsed, so this can't fail.
+    let Ok(bases) = aligned_bases(record, reflen, options.depth.min_base_quality) else {
+        return Vec::new();
+    };
+
+    bases
+        .filter_map(|base| {
+            let &site = site_indexes.get(&base.position)?;
+            let observed = base.base?.to_ascii_uppercase();
+
+            let allele = sites[site]
+                .alleles
+                .iter()
+                .position(|&allele| allele == observed)?;
+
+            Some((site, allele))
+        })
+        .collect()
+}
+
+// Clusters the reads' alleles around haplotypes, returning the haplotypes,
+// most common first, and the index of each read's haplotype.
+//
+// The haplotypes are seeded from the reads with the most sites first: each
+// distinct combination of alleles either fills in the one seed it shares
+// sites and agrees with, or starts a seed of its own, so reads that each
+// cover only some of the sites still add up to a haplotype. A combination
+// agreeing with more than one seed could be either, and seeds none. Seeds
+// with enough reads become the haplotypes, with the majority allele of all
+// the reads at any site none of their reads cover. Each read is then
+// assigned to the haplotype it has the fewest mismatches with, and each
+// haplotype takes the majority allele of its reads at each site, until
+// nothing changes.
+fn cluster(
+    profiles: &[Vec<Option<usize>>],
+    options: &PhaseOptions,
+) -> (Vec<Haplotype>, Vec<Option<usize>>) {
+    let covered = |profile: &[Option<usize>]| profile.iter().flatten().count();
+
+    let mut distinct: HashMap<&Vec<Option<usize>>, usize> = HashMap::new();
+
+    for profile in profiles {
+        *distinct.entry(profile).or_default() += 1;
+    }
+
+    let mut distinct: Vec<(&Vec<Option<usize>>, usize)> = distinct.into_iter().collect();
+    distinct.sort_by(|(a, a_reads), (b, b_reads)| {
+        covered(b)
+            .cmp(&covered(a))
+            .then(b_reads.cmp(a_reads))
+            .then(a.cmp(b))
+    });
+
+    let mut seeds: Vec<(Vec<Option<usize>>, usize)> = Vec::new();
+
+    for (profile, reads) in distinct {
+        let agreeing: Vec<usize> = seeds
+            .iter()
+            .enumerate()
+            .filter(|(_, (seed, _))| {
+                let mut shared = profile
+                    .iter()
+                    .zip(seed)
+                    .filter(|(allele, seed_allele)| allele.is_some() && seed_allele.is_some())
+                    .peekable();
+
+                shared.peek().is_some() && shared.all(|(allele, seed_allele)| allele == seed_allele)
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        match agreeing[..] {
+            [] => seeds.push((profile.clone(), reads)),
+            [i] => {
+                let (seed, seed_reads) = &mut seeds[i];
+
+                for (seed_allele, allele) in seed.iter_mut().zip(profile) {
+                    *seed_allele = seed_allele.or(*allele);
+                }
+
+                *seed_reads += reads;
+            }
+            _ => {}
+        }
+    }
+
+    let seeded: usize = seeds.iter().map(|(_, reads)| reads).sum();
+    let all: Vec<&Vec<Option<usize>>> = profiles.iter().collect();
+
+    let mut seeds: Vec<(Vec<usize>, usize)> = seeds
+        .into_iter()
+        .filter(|&(_, reads)| {
+            reads >= options.min_reads && reads as f64 >= options.min_fraction * seeded as f64
+        })
+        .map(|(seed, reads)| {
+            let alleles = seed
+                .iter()
+                .enumerate()
+                .map(|(site, allele)| allele.or_else(|| majority(&all, site)).unwrap_or(0))
+                .collect();
+            (alleles, reads)
+        })
+        .collect();
+    seeds.sort_by(|(a, a_reads), (b, b_reads)| b_reads.cmp(a_reads).then(a.cmp(b)));
+
+    let mut haplotypes: Vec<Vec<usize>> = Vec::new();
+
+    for (alleles, _) in seeds {
+        if !haplotypes.contains(&alleles) {
+            haplotypes.push(alleles);
+        }
+    }
+
+    let mut assignments = vec![None; profiles.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        assignments = profiles
+            .iter()
+            .map(|profile| assign(profile, &haplotypes, options))
+            .collect();
+
+        let mut updated: Vec<Vec<usize>> = Vec::new();
+
+        for (hp, haplotype) in haplotypes.iter().enumerate() {
+            let reads: Vec<&Vec<Option<usize>>> = profiles
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assignment)| **assignment == Some(hp))
+                .map(|(profile, _)| profile)
+                .collect();
+
+            if reads.len() < options.min_reads {
+                continue;
+            }
+
+            let alleles: Vec<usize> = haplotype
+                .iter()
+                .enumerate()
+                .map(|(site, &allele)| majority(&reads, site).unwrap_or(allele))
+                .collect();
+
+            if !updated.contains(&alleles) {
+                updated.push(alleles);
+            }
+        }
+
+        if updated == haplotypes {
+            break;
+        }
+
+        haplotypes = updated;
+    }
+
+    assignments = profiles
+        .iter()
+        .map(|profile| assign(profile, &haplotypes, options))
+        .collect();
+
+    let mut counts = vec![0; haplotypes.len()];
+    for hp in assignments.iter().flatten() {
+        counts[*hp] += 1;
+    }
+
+    // Numbered by the number of reads.
+    let mut order: Vec<usize> = (0..haplotypes.len()).collect();
+    order.sort_by(|&a, &b| counts[b].cmp(&counts[a]).then(a.cmp(&b)));
+
+    let mut renumbered = vec![0; haplotypes.len()];
+    for (new, &old) in order.iter().enumerate() {
+        renumbered[old] = new;
+    }
+
+    let haplotypes = order
+        .iter()
+        .map(|&old| Haplotype {
+            alleles: haplotypes[old].clone(),
+            reads: counts[old],
+        })
+        .collect();
+    let assignments = assignments
+        .into_iter()
+        .map(|hp| hp.map(|hp| renumbered[hp]))
+        .collect();
+
+    (haplotypes, assignments)
+}
+
+// The haplotype a read has the fewest mismatches with, if there's only one
+// and the mismatches are few enough.
+fn assign(
+    profile: &[Option<usize>],
+    haplotypes: &[Vec<usize>],
+    options: &PhaseOptions,
+) -> Option<usize> {
+    let covered = profile.iter().flatten().count();
+
+    if covered == 0 {
+        return None;
+    }
+
+    let mismatches: Vec<usize> = haplotypes
+        .iter()
+        .map(|haplotype| {
+            profile
+                .iter()
+                .zip(haplotype)
+                .filter(|(allele, &expected)| allele.is_some_and(|allele| allele != expected))
+                .count()
+        })
+        .collect();
+
+    let best = *mismatches.iter().min()?;
+
+    if mismatches.iter().filter(|&&n| n == best).count() > 1
+        || best as f64 > options.max_mismatch_fraction * covered as f64
+    {
+        return None;
+    }
+
+    mismatches.iter().position(|&n| n == best)
+}
+
+// The most common allele at a site, if there's one.
+fn majority(reads: &[&Vec<Option<usize>>], site: usize) -> Option<usize> {
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+
+    for allele in reads.iter().filter_map(|profile| profile[site]) {
+        *counts.entry(allele).or_default() += 1;
+    }
+
+    let max = *counts.values().max()?;
+    let mut alleles = counts.iter().filter(|(_, &count)| count == max);
+    let (&allele, _) = alleles.next()?;
+
+    alleles.next().is_none().then_some(allele)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase() -> io::Result<()> {
+        let vcf = b"##fileformat=VCFv4.3\n\
+            #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+            chrM\t3\t.\tG\tT\t.\tPASS\t.\n\
+            chrM\t5\t.\tA\tAC\t.\tPASS\t.\n\
+            chrM\t9\t.\tC\tA,G\t.\tPASS\t.\n\
+            chr1\t9\t.\tC\tA\t.\tPASS\t.\n";
+        let sites = read_phase_sites(&vcf[..], b"chrM".into())?;
+        assert_eq!(
+            sites,
+            [
+                PhaseSite {
+                    position: 3,
+                    alleles: b"GT".to_vec()
+                },
+                PhaseSite {
+                    position: 9,
+                    alleles: b"CAG".to_vec()
+                },
+            ]
+        );
+
+        let options = PhaseOptions {
+            min_reads: 2,
+            ..Default::default()
+        };
+
+        // Two haplotypes, one read with an error, one covering one site and
+        // one that fits neither.
+        let profiles = vec![
+            vec![Some(1), Some(1)],
+            vec![Some(1), Some(1)],
+            vec![Some(1), Some(1)],
+            vec![Some(0), Some(0)],
+            vec![Some(0), Some(0)],
+            vec![Some(0), Some(2)],
+            vec![None, Some(1)],
+            vec![Some(0), Some(1)],
+        ];
+
+        let (haplotypes, assignments) = cluster(&profiles, &options);
+        assert_eq!(
+            haplotypes,
+            [
+                Haplotype {
+                    alleles: vec![1, 1],
+                    reads: 4
+                },
+                Haplotype {
+                    alleles: vec![0, 0],
+                    reads: 2
+                },
+            ]
+        );
+        assert_eq!(
+            assignments,
+            [
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(1),
+                None,
+                Some(0),
+                None
+            ]
+        );
+
+        // No read covers every site, but the reads overlap.
+        let profiles = vec![
+            vec![Some(1), Some(1), None],
+            vec![Some(1), Some(1), None],
+            vec![None, Some(1), Some(1)],
+            vec![None, Some(1), Some(1)],
+            vec![Some(0), Some(0), None],
+            vec![Some(0), Some(0), None],
+            vec![None, Some(0), Some(0)],
+            vec![None, Some(0), Some(0)],
+        ];
+
+        let (overlapping, assignments) = cluster(&profiles, &options);
+        assert_eq!(
+            overlapping,
+            [
+                Haplotype {
+                    alleles: vec![0, 0, 0],
+                    reads: 4
+                },
+                Haplotype {
+                    alleles: vec![1, 1, 1],
+                    reads: 4
+                },
+            ]
+        );
+        assert_eq!(
+            assignments,
+            [
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(0)
+            ]
+        );
+
+        let phasing = Phasing {
+            haplotypes,
+            unassigned: 2,
+        };
+        let mut tsv = Vec::new();
+        phasing.write(&mut tsv, &sites)?;
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "haplotype\treads\tfraction\talleles\n\
+            1\t4\t0.6667\t3T,9A\n\
+            2\t2\t0.3333\t3G,9C\n"
+        );
+
+        Ok(())
+    }
+}
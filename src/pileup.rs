@@ -0,0 +1,465 @@
+//! Per-position allele counts on the circular reference, for heteroplasmy.
+//!
+//! Like the depth, positions are folded modulo the reference length, so
+//! alleles next to the `reflen`→1 junction are counted at the right position
+//! whether the reads were converted or not.
+
+use crate::{
+    depth::{aligned_bases, is_counted, mean, DepthOptions},
+    fold_position,
+};
+use bstr::BStr;
+use noodles::sam::alignment::{record::cigar::op::Kind, Record};
+use noodles_util::alignment::io::Reader;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+};
+
+/// An allele counted in the pileup.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Allele {
+    A,
+    C,
+    G,
+    T,
+    /// An insertion after the position.
+    Insertion,
+    /// A deletion of the position.
+    Deletion,
+}
+
+impl Allele {
+    /// All the alleles, in the order they are reported.
+    pub const ALL: [Allele; 6] = [
+        Self::A,
+        Self::C,
+        Self::G,
+        Self::T,
+        Self::Insertion,
+        Self::Deletion,
+    ];
+
+    // The bases, which are the only alleles with qualities.
+    pub(crate) const BASES: [Allele; 4] = [Self::A, Self::C, Self::G, Self::T];
+
+    /// Returns the name used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::C => "C",
+            Self::G => "G",
+            Self::T => "T",
+            Self::Insertion => "ins",
+            Self::Deletion => "del",
+        }
+    }
+
+    pub(crate) fn from_base(base: u8) -> Option<Self> {
+        match base.to_ascii_uppercase() {
+            b'A' => Some(Self::A),
+            b'C' => Some(Self::C),
+            b'G' => Some(Self::G),
+            b'T' => Some(Self::T),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// An insertion or deletion after a position.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Indel {
+    /// The inserted bases.
+    Insertion(Vec<u8>),
+    /// The number of deleted bases.
+    Deletion(usize),
+}
+
+// The counts at one position of one strand.
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+    alleles: [u32; 6],
+    quality_sums: [u64; 4],
+}
+
+/// The forward and reverse strand allele counts of each position of the
+/// reference.
+#[derive(Clone, Debug)]
+pub struct Pileup {
+    forward: Vec<Counts>,
+    reverse: Vec<Counts>,
+    // The forward and reverse counts of each indel, by the 0-based position
+    // before it.
+    indels: BTreeMap<(usize, Indel), (u32, u32)>,
+}
+
+impl Pileup {
+    /// Creates an empty pileup for a reference of length `reflen`.
+    pub fn new(reflen: usize) -> Self {
+        Self {
+            forward: vec![Counts::default(); reflen],
+            reverse: vec![Counts::default(); reflen],
+            indels: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the length of the reference.
+    pub fn len(&self) -> usize {
+        self.forward.len()
+    }
+
+    /// Returns true if the reference is empty.
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty()
+    }
+
+    /// Returns the forward and reverse strand counts of an allele at the
+    /// 0-based position `i`.
+    pub fn get(&self, i: usize, allele: Allele) -> (u32, u32) {
+        (
+            self.forward[i].alleles[allele.index()],
+            self.reverse[i].alleles[allele.index()],
+        )
+    }
+
+    /// Returns the mean quality of an allele, over both strands, at the
+    /// 0-based position `i`. Only bases have qualities; it is 0 for indels.
+    pub fn mean_quality(&self, i: usize, allele: Allele) -> f64 {
+        if !Allele::BASES.contains(&allele) {
+            return 0.0;
+        }
+
+        let (forward, reverse) = self.get(i, allele);
+        let quality_sum = self.forward[i].quality_sums[allele.index()]
+            + self.reverse[i].quality_sums[allele.index()];
+
+        mean(quality_sum, (forward + reverse) as usize)
+    }
+
+    /// Returns the mean quality of all the bases at the 0-based position `i`.
+    pub fn mean_site_quality(&self, i: usize) -> f64 {
+        let (count, quality_sum) = Allele::BASES.iter().fold((0, 0), |(count, sum), allele| {
+            let (forward, reverse) = self.get(i, *allele);
+            (
+                count + (forward + reverse) as usize,
+                sum + self.forward[i].quality_sums[allele.index()]
+                    + self.reverse[i].quality_sums[allele.index()],
+            )
+        });
+
+        mean(quality_sum, count)
+    }
+
+    /// Returns the indels with their forward and reverse strand counts, by the
+    /// 0-based position before them.
+    pub fn indels(&self) -> impl Iterator<Item = (usize, &Indel, (u32, u32))> + '_ {
+        self.indels
+            .iter()
+            .map(|((i, indel), &counts)| (*i, indel, counts))
+    }
+
+    /// Adds the alleles of a record to the pileup.
+    ///
+    /// Records are filtered as for the depth. Bases below the minimum base
+    /// quality, and bases other than A, C, G and T, are skipped. An insertion
+    /// is counted at the position before it.
+    pub fn add(&mut self, record: &impl Record, options: &DepthOptions) -> io::Result<()> {
+        if self.is_empty() || !is_counted(record, options)? {
+            return Ok(());
+        }
+
+        let Some(start) = record.alignment_start().transpose()? else {
+            return Ok(());
+        };
+
+        let sequence: Vec<u8> = record.sequence().iter().collect();
+
+        let is_reverse_complemented = record.flags()?.is_reverse_complemented();
+
+        let counts = if is_reverse_complemented {
+            &mut self.reverse
+        } else {
+            &mut self.forward
+        };
+
+        let reflen = counts.len();
+
+        for base in aligned_bases(record, reflen, options.min_base_quality)? {
+            let Some(allele) = base.base.and_then(Allele::from_base) else {
+                continue;
+            };
+
+            let position = &mut counts[base.position];
+            position.alleles[allele.index()] += 1;
+            position.quality_sums[allele.index()] += u64::from(base.quality_score.unwrap_or(0));
+        }
+
+        // The indels, walking the CIGAR again.
+        let mut ref_pos = usize::from(start) - 1;
+        let mut query_pos = 0;
+
+        for result in record.cigar().iter() {
+            let op = result?;
+
+            match op.kind() {
+                Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                    ref_pos += op.len();
+                    query_pos += op.len();
+                }
+                Kind::Insertion => {
+                    // There's nothing before an insertion at the start.
+                    if ref_pos > usize::from(start) - 1 {
+                        let anchor = fold_position(ref_pos - 1, reflen);
+                        counts[anchor].alleles[Allele::Insertion.index()] += 1;
+
+                        let end = (query_pos + op.len()).min(sequence.len());
+                        let bases = sequence
+                            .get(query_pos..end)
+                            .unwrap_or_default()
+                            .to_ascii_uppercase();
+                        let indel = Indel::Insertion(bases);
+                        add_strand(&mut self.indels, (anchor, indel), is_reverse_complemented);
+                    }
+
+                    query_pos += op.len();
+                }
+                Kind::Deletion => {
+                    for i in 0..op.len() {
+                        counts[fold_position(ref_pos + i, reflen)].alleles
+                            [Allele::Deletion.index()] += 1;
+                    }
+
+                    if ref_pos > usize::from(start) - 1 {
+                        let anchor = fold_position(ref_pos - 1, reflen);
+                        let indel = Indel::Deletion(op.len());
+                        add_strand(&mut self.indels, (anchor, indel), is_reverse_complemented);
+                    }
+
+                    ref_pos += op.len();
+                }
+                Kind::SoftClip => query_pos += op.len(),
+                Kind::Skip => ref_pos += op.len(),
+                Kind::HardClip | Kind::Pad => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the non-zero counts as a tidy TSV, with a row for each position,
+    /// strand and allele.
+    pub fn write_tsv<W>(&self, writer: &mut W, name: &BStr) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "chrom\tpos\tstrand\tallele\tcount")?;
+
+        for i in 0..self.len() {
+            for (strand, counts) in [('+', &self.forward[i]), ('-', &self.reverse[i])] {
+                for allele in Allele::ALL {
+                    let count = counts.alleles[allele.index()];
+
+                    if count > 0 {
+                        writeln!(
+                            writer,
+                            "{name}\t{}\t{strand}\t{}\t{count}",
+                            i + 1,
+                            allele.as_str()
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes an mgatk-style sparse matrix of a base: the position, sample,
+    /// and forward and reverse strand counts and mean qualities, for the
+    /// positions where the base was seen.
+    pub fn write_sparse_base<W>(
+        &self,
+        writer: &mut W,
+        sample: &str,
+        allele: Allele,
+    ) -> io::Result<()>
+    where
+        W: Write,
+    {
+        assert!(Allele::BASES.contains(&allele), "{allele:?} is not a base");
+
+        for i in 0..self.len() {
+            let (forward, reverse) = (&self.forward[i], &self.reverse[i]);
+            let forward_count = forward.alleles[allele.index()];
+            let reverse_count = reverse.alleles[allele.index()];
+
+            if forward_count == 0 && reverse_count == 0 {
+                continue;
+            }
+
+            writeln!(
+                writer,
+                "{},{},{},{:.1},{},{:.1}",
+                i + 1,
+                sample,
+                forward_count,
+                mean(forward.quality_sums[allele.index()], forward_count as usize),
+                reverse_count,
+                mean(reverse.quality_sums[allele.index()], reverse_count as usize)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes an mgatk-style sparse coverage matrix: the position, sample and
+    /// the number of bases counted there.
+    pub fn write_sparse_coverage<W>(&self, writer: &mut W, sample: &str) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for i in 0..self.len() {
+            let coverage: u32 = Allele::BASES
+                .iter()
+                .map(|allele| {
+                    let (forward, reverse) = self.get(i, *allele);
+                    forward + reverse
+                })
+                .sum();
+
+            if coverage > 0 {
+                writeln!(writer, "{},{},{}", i + 1, sample, coverage)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Adds a read on the given strand to the counts of an indel.
+fn add_strand(
+    indels: &mut BTreeMap<(usize, Indel), (u32, u32)>,
+    key: (usize, Indel),
+    is_reverse_complemented: bool,
+) {
+    let (forward, reverse) = indels.entry(key).or_default();
+
+    if is_reverse_complemented {
+        *reverse += 1;
+    } else {
+        *forward += 1;
+    }
+}
+
+/// Computes the pileup of the records on `refname`, folding their positions
+/// onto a circular reference of length `reflen`.
+pub fn compute_pileup<R>(
+    reader: &mut Reader<R>,
+    refname: &BStr,
+    reflen: usize,
+    options: &DepthOptions,
+) -> io::Result<Pileup>
+where
+    R: BufRead,
+{
+    let header = reader.read_header()?;
+
+    let Some(reference_sequence_id) = header.reference_sequences().get_index_of(refname) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("reference {refname} is not in the header"),
+        ));
+    };
+
+    let mut pileup = Pileup::new(reflen);
+
+    for result in reader.records(&header) {
+        let record = result?;
+
+        if record.reference_sequence_id(&header).transpose()? == Some(reference_sequence_id) {
+            pileup.add(&record, options)?;
+        }
+    }
+
+    Ok(pileup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::{
+        core::Position,
+        sam::alignment::{
+            record::{cigar::Op, Flags},
+            RecordBuf,
+        },
+    };
+
+    #[test]
+    fn test_pileup() -> io::Result<()> {
+        let options = DepthOptions {
+            min_mapping_quality: 0,
+            min_base_quality: 20,
+        };
+
+        let mut pileup = Pileup::new(10);
+
+        // Crosses the junction: 9 and 10, an insertion after 10, then 1 is
+        // deleted and 2 and 3 are low quality and N.
+        let record = RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(9).unwrap())
+            .set_cigar(
+                [
+                    Op::new(Kind::Match, 2),
+                    Op::new(Kind::Insertion, 1),
+                    Op::new(Kind::Deletion, 1),
+                    Op::new(Kind::Match, 2),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .set_sequence(b"ACGTN".to_vec().into())
+            .set_quality_scores(vec![30, 40, 30, 10, 30].into())
+            .build();
+        pileup.add(&record, &options)?;
+
+        assert_eq!(pileup.get(8, Allele::A), (1, 0));
+        assert_eq!(pileup.get(9, Allele::C), (1, 0));
+        assert_eq!(pileup.get(9, Allele::Insertion), (1, 0));
+        assert_eq!(pileup.get(0, Allele::Deletion), (1, 0));
+        assert_eq!(pileup.get(1, Allele::T), (0, 0));
+        assert_eq!(pileup.get(2, Allele::A), (0, 0));
+        assert_eq!(
+            pileup.indels().collect::<Vec<_>>(),
+            [
+                (9, &Indel::Insertion(b"G".to_vec()), (1, 0)),
+                (9, &Indel::Deletion(1), (1, 0))
+            ]
+        );
+
+        let mut tsv = Vec::new();
+        pileup.write_tsv(&mut tsv, b"chrM".into())?;
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "chrom\tpos\tstrand\tallele\tcount\n\
+             chrM\t1\t+\tdel\t1\n\
+             chrM\t9\t+\tA\t1\n\
+             chrM\t10\t+\tC\t1\n\
+             chrM\t10\t+\tins\t1\n"
+        );
+
+        let mut sparse = Vec::new();
+        pileup.write_sparse_base(&mut sparse, "sample1", Allele::C)?;
+        assert_eq!(
+            String::from_utf8(sparse).unwrap(),
+            "10,sample1,1,40.0,0,0.0\n"
+        );
+
+        Ok(())
+    }
+}
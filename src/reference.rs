@@ -0,0 +1,50 @@
+//! Reference sequence (FASTA) handling.
+
+use crate::push_ext;
+use noodles::fasta::{self, fai, repository::adapters::IndexedReader};
+use std::{io, path::Path};
+
+/// Builds a reference sequence repository from a FASTA file, e.g. to decode
+/// CRAM records.
+///
+/// The FASTA index, `<src>.fai`, is used if it exists. Otherwise the FASTA is
+/// indexed on the fly, which only works for uncompressed files.
+pub fn build_repository<P>(src: P) -> io::Result<fasta::Repository>
+where
+    P: AsRef<Path>,
+{
+    let src = src.as_ref();
+
+    let index = match fai::read(push_ext(src, "fai")) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::info!("Indexing reference: {}", src.display());
+            fasta::index(src)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let reader = fasta::io::indexed_reader::Builder::default()
+        .set_index(index)
+        .build_from_path(src)?;
+
+    Ok(fasta::Repository::new(IndexedReader::new(reader)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_repository_without_index() -> io::Result<()> {
+        let src = std::env::temp_dir().join(format!("mt_lintocirc_{}_ref.fa", std::process::id()));
+        std::fs::write(&src, b">chrM\nACGTACGT\nACGT\n>chr1\nTTTT\n")?;
+
+        let repository = build_repository(&src)?;
+        let sequence = repository.get(b"chrM").transpose()?.unwrap();
+        assert_eq!(sequence.as_ref(), b"ACGTACGTACGT");
+        assert!(repository.get(b"chr2").and_then(Result::ok).is_none());
+
+        std::fs::remove_file(src)
+    }
+}
@@ -0,0 +1,761 @@
+//! Conversion of just the extended reference in indexed alignment files.
+//!
+//! In a whole genome alignment only the handful of records on the extended
+//! mitochondrial reference need converting. Rather than decoding the entire
+//! file, the index is used to either fetch only those records, or to find where
+//! they are so everything else can be copied through as compressed blocks.
+
+use crate::{
+    convert_header, push_ext, write_converted_records, ConversionStats, ConvertOptions, SideOutputs,
+};
+use bstr::BStr;
+use noodles::{
+    bam::{self, bai},
+    bgzf::{self, VirtualPosition},
+    core::{Position, Region},
+    csi::{self, BinningIndex},
+    sam::{
+        self,
+        alignment::{
+            io::Write as _,
+            record::{Cigar, Data, Flags, MappingQuality, QualityScores, Sequence},
+            Record,
+        },
+    },
+};
+use noodles_util::alignment::io::IndexedReader;
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write as StdWrite},
+    path::Path,
+};
+
+// The BGZF end-of-file marker block.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Converts only the records in `region` of an indexed alignment file, and
+/// optionally the unplaced unmapped records.
+///
+/// The output header only has the reference sequence of the region, renamed
+/// if it is the extended reference, along with the @HD, @RG, @PG and @CO
+/// lines of the input. Mates, and unmapped records, placed on other reference
+/// sequences are written as unplaced. Returns the statistics of the conversion.
+pub fn convert_region<R>(
+    reader: &mut IndexedReader<R>,
+    region: &Region,
+    include_unmapped: bool,
+    bufwriter: &mut Box<dyn StdWrite>,
+    options: &ConvertOptions,
+    outputs: &mut SideOutputs,
+) -> io::Result<ConversionStats>
+where
+    R: Read + Seek,
+{
+    let header = reader.read_header()?;
+    let converted_header = convert_header(&header, options);
+
+    // The converted header keeps the reference sequence IDs of the input.
+    let reference_sequence_id = header.reference_sequences().get_index_of(region.name());
+
+    let mut writer = RegionWriter {
+        inner: sam::io::Writer::new(bufwriter),
+        header: region_header(&converted_header, reference_sequence_id),
+        reference_sequence_id,
+    };
+    writer.write_alignment_header(&converted_header)?;
+
+    let mut stats = ConversionStats::default();
+
+    let query = reader.query(&header, region)?;
+    write_converted_records(
+        &mut writer,
+        &header,
+        &converted_header,
+        query,
+        options,
+        &mut stats,
+        outputs,
+    )?;
+
+    if include_unmapped {
+        match reader {
+            IndexedReader::Sam(reader) => {
+                let query = reader.query_unmapped()?;
+                write_converted_records(
+                    &mut writer,
+                    &header,
+                    &converted_header,
+                    query,
+                    options,
+                    &mut stats,
+                    outputs,
+                )?;
+            }
+            IndexedReader::Bam(reader) => {
+                let query = reader.query_unmapped()?;
+                write_converted_records(
+                    &mut writer,
+                    &header,
+                    &converted_header,
+                    query,
+                    options,
+                    &mut stats,
+                    outputs,
+                )?;
+            }
+            IndexedReader::Cram(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unmapped reads can't be queried from a CRAM file",
+                ))
+            }
+        }
+    }
+
+    writer.finish(&converted_header)?;
+    stats.finish();
+
+    Ok(stats)
+}
+
+// Keeps only the reference sequence with the given ID in the header.
+fn region_header(
+    converted_header: &sam::Header,
+    reference_sequence_id: Option<usize>,
+) -> sam::Header {
+    let mut header = converted_header.clone();
+    let name = reference_sequence_id
+        .and_then(|id| converted_header.reference_sequences().get_index(id))
+        .map(|(name, _)| name.clone());
+
+    header
+        .reference_sequences_mut()
+        .retain(|other, _| Some(other) == name.as_ref());
+
+    header
+}
+
+// Writes the records of a region with the region's header, moving them from
+// the reference sequence IDs of the converted header to the only one left.
+struct RegionWriter<W> {
+    inner: W,
+    header: sam::Header,
+    reference_sequence_id: Option<usize>,
+}
+
+impl<W> sam::alignment::io::Write for RegionWriter<W>
+where
+    W: sam::alignment::io::Write,
+{
+    fn write_alignment_header(&mut self, _: &sam::Header) -> io::Result<()> {
+        self.inner.write_alignment_header(&self.header)
+    }
+
+    fn write_alignment_record(
+        &mut self,
+        header: &sam::Header,
+        record: &dyn Record,
+    ) -> io::Result<()> {
+        let record = RegionRecord {
+            record,
+            header,
+            reference_sequence_id: self.reference_sequence_id,
+        };
+
+        self.inner.write_alignment_record(&self.header, &record)
+    }
+
+    fn finish(&mut self, _: &sam::Header) -> io::Result<()> {
+        self.inner.finish(&self.header)
+    }
+}
+
+// A record of the region, read against the converted header.
+struct RegionRecord<'a> {
+    record: &'a dyn Record,
+    header: &'a sam::Header,
+    reference_sequence_id: Option<usize>,
+}
+
+impl RegionRecord<'_> {
+    fn is_on_region_reference(&self, id: Option<io::Result<usize>>) -> Option<io::Result<bool>> {
+        id.map(|result| result.map(|id| Some(id) == self.reference_sequence_id))
+    }
+}
+
+impl Record for RegionRecord<'_> {
+    fn name(&self) -> Option<&BStr> {
+        self.record.name()
+    }
+
+    fn flags(&self) -> io::Result<Flags> {
+        self.record.flags()
+    }
+
+    fn reference_sequence_id<'r, 'h: 'r>(
+        &'r self,
+        _: &'h sam::Header,
+    ) -> Option<io::Result<usize>> {
+        // Unmapped records placed on other reference sequences are unplaced.
+        match self.is_on_region_reference(self.record.reference_sequence_id(self.header))? {
+            Ok(true) => Some(Ok(0)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn alignment_start(&self) -> Option<io::Result<Position>> {
+        match self.reference_sequence_id(self.header) {
+            Some(Ok(_)) => self.record.alignment_start(),
+            _ => None,
+        }
+    }
+
+    fn mapping_quality(&self) -> Option<io::Result<MappingQuality>> {
+        self.record.mapping_quality()
+    }
+
+    fn cigar(&self) -> Box<dyn Cigar + '_> {
+        self.record.cigar()
+    }
+
+    fn mate_reference_sequence_id<'r, 'h: 'r>(
+        &'r self,
+        _: &'h sam::Header,
+    ) -> Option<io::Result<usize>> {
+        // A mate on a reference sequence that isn't in the output is unavailable.
+        match self.is_on_region_reference(self.record.mate_reference_sequence_id(self.header))? {
+            Ok(true) => Some(Ok(0)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn mate_alignment_start(&self) -> Option<io::Result<Position>> {
+        match self.mate_reference_sequence_id(self.header) {
+            Some(Ok(_)) => self.record.mate_alignment_start(),
+            _ => None,
+        }
+    }
+
+    fn template_length(&self) -> io::Result<i32> {
+        self.record.template_length()
+    }
+
+    fn sequence(&self) -> Box<dyn Sequence + '_> {
+        self.record.sequence()
+    }
+
+    fn quality_scores(&self) -> Box<dyn QualityScores + '_> {
+        self.record.quality_scores()
+    }
+
+    fn data(&self) -> Box<dyn Data + '_> {
+        self.record.data()
+    }
+}
+
+/// Converts the records on the extended reference of an indexed BAM file and
+/// copies everything else through as is.
+///
+/// The BGZF blocks holding only records on the other reference sequences are
+/// copied without being decompressed; only the blocks shared with the extended
+/// reference's records are re-encoded. The output is always BAM.
+///
+/// The returned statistics only count the records on the extended reference,
+/// as the others are never decoded.
+pub fn convert_bam_passthrough<P>(
+    src: P,
+    output: Box<dyn StdWrite>,
+    options: &ConvertOptions,
+    outputs: &mut SideOutputs,
+) -> io::Result<ConversionStats>
+where
+    P: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let index = read_associated_index(src)?;
+
+    let mut reader = bam::io::Reader::new(File::open(src)?);
+    let header = reader.read_header()?;
+    let records_start = reader.get_ref().virtual_position();
+
+    let converted_header = convert_header(&header, options);
+
+    let mut writer = bam::io::Writer::from(bgzf::Writer::new(output));
+    writer.write_header(&converted_header)?;
+    let output = finish_blocks(writer.into_inner())?;
+
+    // The end of the record data, excluding the EOF marker.
+    let records_end = {
+        let file = reader.get_mut().get_mut();
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut marker = [0; BGZF_EOF.len()];
+
+        let data_len = if len >= BGZF_EOF.len() as u64 {
+            file.seek(SeekFrom::Start(len - BGZF_EOF.len() as u64))?;
+            file.read_exact(&mut marker)?;
+            if marker == BGZF_EOF {
+                len - BGZF_EOF.len() as u64
+            } else {
+                len
+            }
+        } else {
+            len
+        };
+
+        VirtualPosition::try_from((data_len, 0))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    };
+
+    let mt_range = header
+        .reference_sequences()
+        .get_index_of(&options.refname)
+        .map(|id| reference_sequence_range(index.as_ref(), id))
+        .transpose()?
+        .flatten();
+
+    let Some((mt_start, mt_end)) = mt_range else {
+        log::warn!(
+            "Reference: {} has no indexed records, copying the input through.",
+            options.refname
+        );
+
+        let output = copy_virtual_range(reader.get_mut(), output, records_start, records_end)?;
+        bgzf::Writer::new(output).finish()?.flush()?;
+
+        let mut stats = ConversionStats::default();
+        stats.finish();
+        return Ok(stats);
+    };
+
+    // Everything up to the extended reference.
+    let output = copy_virtual_range(reader.get_mut(), output, records_start, mt_start)?;
+
+    // The records on the extended reference.
+    reader.get_mut().seek(mt_start)?;
+    let mut writer = bam::io::Writer::from(bgzf::Writer::new(output));
+    let mut record = bam::Record::default();
+    let mut records = Vec::new();
+    let mut stats = ConversionStats::default();
+
+    while reader.get_ref().virtual_position() < mt_end {
+        if reader.read_record(&mut record)? == 0 {
+            break;
+        }
+
+        records.push(Ok(record.clone()));
+
+        // Convert in batches to keep memory bounded.
+        if records.len() >= 1024 {
+            write_converted_records(
+                &mut writer,
+                &header,
+                &converted_header,
+                records.drain(..),
+                options,
+                &mut stats,
+                outputs,
+            )?;
+        }
+    }
+
+    write_converted_records(
+        &mut writer,
+        &header,
+        &converted_header,
+        records,
+        options,
+        &mut stats,
+        outputs,
+    )?;
+    let output = finish_blocks(writer.into_inner())?;
+
+    // Everything after the extended reference, including unmapped reads.
+    let output = copy_virtual_range(reader.get_mut(), output, mt_end, records_end)?;
+    bgzf::Writer::new(output).finish()?.flush()?;
+    stats.finish();
+
+    Ok(stats)
+}
+
+// Returns the start and end virtual positions of the records on a reference
+// sequence, or None if it has no records.
+fn reference_sequence_range(
+    index: &dyn BinningIndex,
+    reference_sequence_id: usize,
+) -> io::Result<Option<(VirtualPosition, VirtualPosition)>> {
+    let Some(reference_sequence) = index.reference_sequences().nth(reference_sequence_id) else {
+        return Ok(None);
+    };
+
+    if let Some(metadata) = reference_sequence.metadata() {
+        return Ok(Some((metadata.start_position(), metadata.end_position())));
+    }
+
+    // Without metadata, fall back to the span of the chunks of the whole sequence.
+    let chunks = index.query(reference_sequence_id, (..).into())?;
+    let start = chunks.iter().map(|chunk| chunk.start()).min();
+    let end = chunks.iter().map(|chunk| chunk.end()).max();
+
+    Ok(start.zip(end))
+}
+
+// Flushes the partially filled block of a BGZF writer and returns the
+// underlying writer, without writing an EOF marker.
+fn finish_blocks<W>(mut writer: bgzf::Writer<W>) -> io::Result<W>
+where
+    W: StdWrite,
+{
+    writer.flush()?;
+    Ok(writer.into_inner())
+}
+
+// Copies the uncompressed data between two virtual positions. Whole blocks
+// are copied as is; only the partial blocks at either end are re-encoded.
+fn copy_virtual_range<R, W>(
+    reader: &mut bgzf::Reader<R>,
+    output: W,
+    start: VirtualPosition,
+    end: VirtualPosition,
+) -> io::Result<W>
+where
+    R: Read + Seek,
+    W: StdWrite,
+{
+    if start >= end {
+        return Ok(output);
+    }
+
+    reader.seek(start)?;
+
+    if start.compressed() == end.compressed() {
+        let mut buf = vec![0; usize::from(end.uncompressed() - start.uncompressed())];
+        reader.read_exact(&mut buf)?;
+
+        let mut writer = bgzf::Writer::new(output);
+        writer.write_all(&buf)?;
+        return finish_blocks(writer);
+    }
+
+    // The remainder of the first block. If the start is at the very end of a
+    // block, filling the buffer moves on to the next one.
+    let next_block = reader.position();
+    let buf = reader.fill_buf()?.to_vec();
+
+    let mut writer = bgzf::Writer::new(output);
+    if reader.virtual_position().compressed() == start.compressed() {
+        writer.write_all(&buf)?;
+    }
+    let mut output = finish_blocks(writer)?;
+
+    // The whole blocks in between.
+    let file = reader.get_mut();
+    file.seek(SeekFrom::Start(next_block))?;
+    io::copy(
+        &mut file.by_ref().take(end.compressed() - next_block),
+        &mut output,
+    )?;
+
+    // The start of the last block.
+    if end.uncompressed() > 0 {
+        reader.seek(VirtualPosition::try_from((end.compressed(), 0)).unwrap())?;
+
+        let mut buf = vec![0; usize::from(end.uncompressed())];
+        reader.read_exact(&mut buf)?;
+
+        let mut writer = bgzf::Writer::new(output);
+        writer.write_all(&buf)?;
+        output = finish_blocks(writer)?;
+    }
+
+    Ok(output)
+}
+
+// Reads the index associated with a BAM file, `<src>.bai` or `<src>.csi`.
+fn read_associated_index(src: &Path) -> io::Result<Box<dyn BinningIndex>> {
+    match bai::read(push_ext(src, "bai")) {
+        Ok(index) => Ok(Box::new(index)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let index = csi::read(push_ext(src, "csi"))?;
+            Ok(Box::new(index))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Outcome, PerBaseTags};
+    use noodles::{
+        core::Position,
+        csi::binning_index::{index::reference_sequence::bin::Chunk, Indexer},
+        sam::{
+            alignment::{
+                record::cigar::{op::Kind, Op},
+                record_buf::{QualityScores, Sequence},
+                RecordBuf,
+            },
+            header::record::value::{
+                map::{ReadGroup, ReferenceSequence},
+                Map,
+            },
+        },
+    };
+    use noodles_util::alignment::io::indexed_reader;
+    use std::num::NonZeroUsize;
+
+    const REF_LEN: usize = 1000;
+
+    fn build_record(name: String, reference_sequence_id: Option<usize>, start: usize) -> RecordBuf {
+        let mut builder = RecordBuf::builder()
+            .set_name(name)
+            .set_sequence(Sequence::from(b"ACGT".repeat(25)))
+            .set_quality_scores(QualityScores::from(vec![30; 100]));
+
+        if let Some(id) = reference_sequence_id {
+            builder = builder
+                .set_flags(sam::alignment::record::Flags::empty())
+                .set_reference_sequence_id(id)
+                .set_alignment_start(Position::new(start).unwrap())
+                .set_cigar([Op::new(Kind::Match, 100)].into_iter().collect());
+        } else {
+            builder = builder.set_flags(sam::alignment::record::Flags::UNMAPPED);
+        }
+
+        builder.build()
+    }
+
+    // Writes a sorted BAM with enough records on either side of the extended
+    // reference to fill several blocks, and its index.
+    fn write_indexed_bam(dst: &Path, header: &sam::Header) -> io::Result<()> {
+        let mut records = Vec::new();
+
+        for i in 0..4000 {
+            records.push(build_record(format!("chr1_{i}"), Some(0), i + 1));
+        }
+
+        // mt_0's mate is on another reference sequence, and mt_2's on the
+        // extended reference.
+        let mut mt_0 = build_record(String::from("mt_0"), Some(1), 100);
+        *mt_0.mate_reference_sequence_id_mut() = Some(2);
+        *mt_0.mate_alignment_start_mut() = Position::new(500);
+        records.push(mt_0);
+
+        records.push(build_record(String::from("mt_1"), Some(1), 950));
+
+        let mut mt_2 = build_record(String::from("mt_2"), Some(1), 1100);
+        *mt_2.mate_reference_sequence_id_mut() = Some(1);
+        *mt_2.mate_alignment_start_mut() = Position::new(100);
+        records.push(mt_2);
+
+        for i in 0..4000 {
+            records.push(build_record(format!("chr2_{i}"), Some(2), i + 1));
+        }
+
+        records.push(build_record(String::from("unmapped"), None, 0));
+
+        let mut writer = bam::io::Writer::new(File::create(dst)?);
+        writer.write_header(header)?;
+        for record in &records {
+            writer.write_alignment_record(header, record)?;
+        }
+        writer.try_finish()?;
+        drop(writer);
+
+        let mut reader = bam::io::Reader::new(File::open(dst)?);
+        reader.read_header()?;
+
+        let mut indexer = Indexer::default();
+        let mut chunk_start = reader.get_ref().virtual_position();
+        let mut record = bam::Record::default();
+
+        while reader.read_record(&mut record)? != 0 {
+            let chunk_end = reader.get_ref().virtual_position();
+
+            let alignment_context = match (
+                record.reference_sequence_id().transpose()?,
+                record.alignment_start().transpose()?,
+                record.alignment_end().transpose()?,
+            ) {
+                (Some(id), Some(start), Some(end)) => Some((id, start, end, true)),
+                _ => None,
+            };
+
+            indexer.add_record(alignment_context, Chunk::new(chunk_start, chunk_end))?;
+            chunk_start = chunk_end;
+        }
+
+        let index: bai::Index = indexer.build(header.reference_sequences().len());
+        bai::write(push_ext(dst, "bai"), &index)
+    }
+
+    #[test]
+    fn test_convert_region() -> io::Result<()> {
+        let len = NonZeroUsize::new(10000).unwrap();
+        let header = sam::Header::builder()
+            .set_header(Default::default())
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chrM_ext", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chr2", Map::<ReferenceSequence>::new(len))
+            .add_read_group("rg0", Map::<ReadGroup>::default())
+            .add_comment("sample comment")
+            .build();
+
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("mt_lintocirc_{}_region.bam", std::process::id()));
+        let dst = dir.join(format!("mt_lintocirc_{}_region.sam", std::process::id()));
+        write_indexed_bam(&src, &header)?;
+
+        let options = ConvertOptions {
+            reflen: REF_LEN,
+            refname: "chrM_ext".into(),
+            target_refname: "chrM".into(),
+            per_base_tags: PerBaseTags::default(),
+            numt: None,
+            left_align: None,
+        };
+
+        let mut reader = indexed_reader::Builder::default().build_from_path(&src)?;
+        let region = "chrM_ext".parse().unwrap();
+        let mut output: Box<dyn StdWrite> = Box::new(File::create(&dst)?);
+        let stats = convert_region(
+            &mut reader,
+            &region,
+            true,
+            &mut output,
+            &options,
+            &mut SideOutputs::default(),
+        )?;
+        drop(output);
+        assert_eq!(stats.records_in(), 4);
+        assert_eq!(stats.outcome_count(Outcome::Split), 1);
+
+        let mut reader = sam::io::Reader::new(io::BufReader::new(File::open(&dst)?));
+        let converted_header = reader.read_header()?;
+
+        // Only the target reference is left, with the other header lines.
+        let names: Vec<_> = converted_header.reference_sequences().keys().collect();
+        assert_eq!(names, [b"chrM".as_ref()]);
+        assert_eq!(
+            converted_header.reference_sequences()[0].length().get(),
+            REF_LEN
+        );
+        assert!(converted_header.header().is_some());
+        assert!(converted_header.read_groups().contains_key(b"rg0".as_ref()));
+        assert_eq!(converted_header.comments(), [b"sample comment".as_ref()]);
+
+        let records: Vec<RecordBuf> = reader
+            .record_bufs(&converted_header)
+            .collect::<io::Result<_>>()?;
+
+        let names: Vec<String> = records
+            .iter()
+            .map(|record| record.name().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["mt_0", "mt_1", "mt_1_right", "mt_2", "unmapped"]);
+
+        for record in &records[..4] {
+            assert_eq!(record.reference_sequence_id(), Some(0));
+        }
+
+        // A mate off the target reference is unavailable.
+        assert_eq!(records[0].mate_reference_sequence_id(), None);
+        assert_eq!(records[0].mate_alignment_start(), None);
+        assert_eq!(records[3].mate_reference_sequence_id(), Some(0));
+        assert_eq!(records[3].mate_alignment_start(), Position::new(100));
+        assert_eq!(records[4].reference_sequence_id(), None);
+
+        std::fs::remove_file(push_ext(&src, "bai"))?;
+        std::fs::remove_file(src)?;
+        std::fs::remove_file(dst)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_bam_passthrough() -> io::Result<()> {
+        let len = NonZeroUsize::new(10000).unwrap();
+        let header = sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chrM_ext", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chr2", Map::<ReferenceSequence>::new(len))
+            .build();
+
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!(
+            "mt_lintocirc_{}_passthrough.bam",
+            std::process::id()
+        ));
+        let dst = dir.join(format!(
+            "mt_lintocirc_{}_passthrough.out.bam",
+            std::process::id()
+        ));
+        write_indexed_bam(&src, &header)?;
+
+        let options = ConvertOptions {
+            reflen: REF_LEN,
+            refname: "chrM_ext".into(),
+            target_refname: "chrM".into(),
+            per_base_tags: PerBaseTags::default(),
+            numt: None,
+            left_align: None,
+        };
+
+        let stats = convert_bam_passthrough(
+            &src,
+            Box::new(File::create(&dst)?),
+            &options,
+            &mut SideOutputs::default(),
+        )?;
+        assert_eq!(stats.records_in(), 3);
+        assert_eq!(stats.records_out, 4);
+        assert_eq!(stats.outcome_count(Outcome::Split), 1);
+
+        let mut reader = bam::io::Reader::new(File::open(&dst)?);
+        let converted_header = reader.read_header()?;
+        assert_eq!(
+            converted_header
+                .reference_sequences()
+                .get_index_of(b"chrM".as_ref()),
+            Some(1)
+        );
+
+        let records: Vec<RecordBuf> = reader
+            .record_bufs(&converted_header)
+            .collect::<io::Result<_>>()?;
+
+        let names: Vec<String> = records
+            .iter()
+            .map(|record| record.name().unwrap().to_string())
+            .collect();
+
+        // Everything else is copied through in order.
+        assert_eq!(records.len(), 4000 + 4 + 4000 + 1);
+        assert_eq!(names[0], "chr1_0");
+        assert_eq!(names[3999], "chr1_3999");
+        assert_eq!(&names[4000..4004], ["mt_0", "mt_1", "mt_1_right", "mt_2"]);
+        assert_eq!(names[4004], "chr2_0");
+        assert_eq!(names[8004], "unmapped");
+        assert_eq!(records[8003].alignment_start(), Position::new(4000));
+
+        // The records on the extended reference are converted.
+        assert_eq!(records[4002].alignment_start(), Some(Position::MIN));
+        assert_eq!(
+            records[4001].sequence().len() + records[4002].sequence().len(),
+            100
+        );
+        assert_eq!(records[4003].alignment_start(), Position::new(100));
+
+        std::fs::remove_file(push_ext(&src, "bai"))?;
+        std::fs::remove_file(src)?;
+        std::fs::remove_file(dst)?;
+
+        Ok(())
+    }
+}
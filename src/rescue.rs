@@ -0,0 +1,651 @@
+//! Rescue of origin-spanning reads aligned to a plain linear reference.
+//!
+//! Without an extended reference, a read spanning the origin is aligned to one
+//! end of the chromosome and soft clipped where it runs off the other. The
+//! clipped bases are aligned to the opposite end with a banded Smith-Waterman
+//! extension, anchored at the clip, and the read is rewritten as it would have
+//! been aligned to the extended reference. It is then split by the same
+//! conversion as extended reference data.
+//!
+//! Reads the aligner already split into a primary and a supplementary at the
+//! two ends are joined up first; see [`crate::join`].
+
+use crate::{convert_read, join, stats::Outcome, ConversionStats, PerBaseTags, SplitType};
+use bstr::BStr;
+use noodles::{
+    core::Position,
+    sam::{
+        alignment::{
+            io::Write,
+            record::cigar::{op::Kind, Op},
+            RecordBuf,
+        },
+        io::Writer,
+    },
+};
+use noodles_util::alignment::io::Reader;
+use std::io::{self, BufRead, Write as StdWrite};
+
+// BWA-MEM scores.
+const MATCH: i32 = 1;
+const MISMATCH: i32 = -4;
+const GAP_OPEN: i32 = 6;
+const GAP_EXTEND: i32 = 1;
+
+/// Options for rescuing the reads around the origin.
+#[derive(Clone, Debug)]
+pub struct RescueOptions {
+    /// Align the soft clips of reads that aren't already split into a
+    /// primary and a supplementary.
+    pub align_clips: bool,
+    /// The shortest soft clip to align.
+    pub min_clip_len: usize,
+    /// How far the aligned end of the read, or of its supplementary, may be
+    /// from the end of the chromosome.
+    pub max_distance: usize,
+    /// The band width of the alignment, the most the clip may be shifted by
+    /// gaps.
+    pub band: usize,
+    /// The lowest alignment score of the clip for it to be rescued.
+    pub min_score: i32,
+    /// The per-base array tags to split alongside the sequence.
+    pub per_base_tags: PerBaseTags,
+}
+
+impl Default for RescueOptions {
+    fn default() -> Self {
+        Self {
+            align_clips: true,
+            min_clip_len: 10,
+            max_distance: 5,
+            band: 10,
+            min_score: 10,
+            per_base_tags: PerBaseTags::default(),
+        }
+    }
+}
+
+/// Rescues the reads spanning the origin of a file aligned to the linear
+/// `reference_sequence`, named `refname`.
+///
+/// Rescued reads are split as the conversion splits reads aligned to the
+/// extended reference, and the supplementaries joined into them are dropped;
+/// all other records are written unchanged. Returns the statistics of the run,
+/// with the rescued reads counted as split.
+pub fn rescue_sam(
+    reader: &mut Reader<Box<dyn BufRead>>,
+    bufwriter: &mut Box<dyn StdWrite>,
+    refname: &BStr,
+    reference_sequence: &[u8],
+    options: &RescueOptions,
+) -> io::Result<ConversionStats> {
+    let header = reader.read_header()?;
+    let reference_sequence = reference_sequence.to_ascii_uppercase();
+    let reflen = reference_sequence.len();
+
+    let mut writer = Writer::new(bufwriter);
+    writer.write_header(&header)?;
+
+    let mt_reference_sequence_id = header.reference_sequences().get_index_of(refname);
+
+    if mt_reference_sequence_id.is_none() {
+        log::warn!(
+            "Reference: {} is not in the header, no reads will be rescued.",
+            refname
+        );
+    }
+
+    let mut stats = ConversionStats::default();
+
+    for result in reader.records(&header) {
+        let record = result?;
+        let flags = record.flags()?;
+        let reference_sequence_id = record.reference_sequence_id(&header).transpose()?;
+
+        if reference_sequence_id.is_none() || reference_sequence_id != mt_reference_sequence_id {
+            let outcome = if reference_sequence_id.is_none() {
+                Outcome::Unplaced
+            } else {
+                Outcome::OtherReference
+            };
+            stats.add(outcome, flags);
+            writer.write_alignment_record(&header, &record)?;
+            continue;
+        }
+
+        let read = RecordBuf::try_from_alignment_record(&header, &record)?;
+
+        if join::is_joined_supplementary(&read, refname, reflen, options.max_distance) {
+            stats.add(Outcome::Joined, flags);
+            continue;
+        }
+
+        let joined = join::join_at_origin(&read, refname, reflen, options.max_distance);
+        let is_joined = joined.is_some();
+
+        let extended = joined.or_else(|| {
+            options
+                .align_clips
+                .then(|| extend_over_origin(&read, &reference_sequence, options))
+                .flatten()
+        });
+
+        let split = extended.and_then(|extended| {
+            match convert_read(&extended, &header, reflen, &options.per_base_tags) {
+                SplitType::Split(mut left_read, mut right_read) => {
+                    if is_joined {
+                        join::clip_join(&mut left_read);
+                        join::clip_join(&mut right_read);
+                    }
+
+                    Some((left_read, right_read))
+                }
+                _ => None,
+            }
+        });
+
+        match split {
+            Some((left_read, right_read)) => {
+                stats.add(Outcome::Split, flags);
+                stats
+                    .left_piece_lengths
+                    .add(left_read.cigar().read_length());
+                stats
+                    .right_piece_lengths
+                    .add(right_read.cigar().read_length());
+
+                writer.write_alignment_record(&header, &left_read)?;
+                writer.write_alignment_record(&header, &right_read)?;
+            }
+            None => {
+                stats.add(Outcome::Unchanged, flags);
+                writer.write_alignment_record(&header, &record)?;
+            }
+        }
+    }
+
+    writer.finish(&header)?;
+    stats.finish();
+
+    Ok(stats)
+}
+
+// Returns the read as aligned to the extended reference, if a soft clip at
+// either end of the chromosome aligns across the origin.
+fn extend_over_origin(
+    read: &RecordBuf,
+    reference_sequence: &[u8],
+    options: &RescueOptions,
+) -> Option<RecordBuf> {
+    if read.flags().is_unmapped() || read.sequence().is_empty() {
+        return None;
+    }
+
+    extend_end(read, reference_sequence, options)
+        .or_else(|| extend_start(read, reference_sequence, options))
+}
+
+// Aligns a trailing soft clip at the end of the chromosome to its start.
+fn extend_end(
+    read: &RecordBuf,
+    reference_sequence: &[u8],
+    options: &RescueOptions,
+) -> Option<RecordBuf> {
+    let reflen = reference_sequence.len();
+    let ops = read.cigar().as_ref();
+
+    // Hard clips are outside the soft clips.
+    let hard_clips = ops
+        .iter()
+        .rev()
+        .take_while(|op| op.kind() == Kind::HardClip)
+        .count();
+    let (ops, hard_clips) = ops.split_at(ops.len() - hard_clips);
+    let (clip, ops) = ops.split_last()?;
+
+    if clip.kind() != Kind::SoftClip || clip.len() < options.min_clip_len {
+        return None;
+    }
+
+    let end = read.alignment_end()?.get();
+
+    if end > reflen || reflen - end > options.max_distance {
+        return None;
+    }
+
+    // The rest of the chromosome, then its start.
+    let sequence = read.sequence().as_ref();
+    let query = sequence[sequence.len() - clip.len()..].to_ascii_uppercase();
+    let start_len = (clip.len() + options.band).min(reflen);
+    let target = [&reference_sequence[end..], &reference_sequence[..start_len]].concat();
+
+    let extension = extend(&query, &target, options.band);
+
+    if extension.score < options.min_score || extension.target_len <= reflen - end {
+        return None;
+    }
+
+    let mut cigar: Vec<Op> = ops.to_vec();
+    for op in extension.ops {
+        push_op(&mut cigar, op);
+    }
+
+    if extension.query_len < clip.len() {
+        cigar.push(Op::new(Kind::SoftClip, clip.len() - extension.query_len));
+    }
+
+    cigar.extend_from_slice(hard_clips);
+
+    let mut extended = read.clone();
+    *extended.cigar_mut() = cigar.into_iter().collect();
+    Some(extended)
+}
+
+// Aligns a leading soft clip at the start of the chromosome to its end. The
+// clip and the reference are reversed, so the extension runs away from the
+// origin.
+fn extend_start(
+    read: &RecordBuf,
+    reference_sequence: &[u8],
+    options: &RescueOptions,
+) -> Option<RecordBuf> {
+    let reflen = reference_sequence.len();
+    let ops = read.cigar().as_ref();
+
+    let hard_clips = ops
+        .iter()
+        .take_while(|op| op.kind() == Kind::HardClip)
+        .count();
+    let (hard_clips, ops) = ops.split_at(hard_clips);
+    let (clip, ops) = ops.split_first()?;
+
+    if clip.kind() != Kind::SoftClip || clip.len() < options.min_clip_len {
+        return None;
+    }
+
+    let start = read.alignment_start()?.get();
+
+    if start - 1 > options.max_distance {
+        return None;
+    }
+
+    // The start of the chromosome, then its end, backwards.
+    let query: Vec<u8> = read.sequence().as_ref()[..clip.len()]
+        .iter()
+        .rev()
+        .map(u8::to_ascii_uppercase)
+        .collect();
+    let end_len = (clip.len() + options.band).min(reflen);
+    let target: Vec<u8> = reference_sequence[..start - 1]
+        .iter()
+        .rev()
+        .chain(reference_sequence[reflen - end_len..].iter().rev())
+        .copied()
+        .collect();
+
+    let extension = extend(&query, &target, options.band);
+
+    // On the extended reference the read starts in the second copy, so the
+    // extension has to reach back into the first one.
+    if extension.score < options.min_score || extension.target_len <= start {
+        return None;
+    }
+
+    let mut cigar: Vec<Op> = hard_clips.to_vec();
+
+    if extension.query_len < clip.len() {
+        cigar.push(Op::new(Kind::SoftClip, clip.len() - extension.query_len));
+    }
+
+    for op in extension.ops.into_iter().rev().chain(ops.iter().copied()) {
+        push_op(&mut cigar, op);
+    }
+
+    let mut extended = read.clone();
+    *extended.alignment_start_mut() = Position::new(reflen + start - extension.target_len);
+    *extended.cigar_mut() = cigar.into_iter().collect();
+    Some(extended)
+}
+
+// An alignment of the start of a query to the start of a target.
+#[derive(Debug, Eq, PartialEq)]
+struct Extension {
+    ops: Vec<Op>,
+    query_len: usize,
+    target_len: usize,
+    score: i32,
+}
+
+// Traceback directions.
+const FROM_DIAGONAL: u8 = 0;
+const FROM_DELETION: u8 = 1;
+const FROM_INSERTION: u8 = 2;
+const DELETION_EXTENDED: u8 = 4;
+const INSERTION_EXTENDED: u8 = 8;
+
+/// Aligns the start of `query` to the start of `target` with affine gaps,
+/// within `band` of the diagonal. The end is free, as in Smith-Waterman, so
+/// the alignment stops where the score is highest and the rest of the query
+/// is left unaligned.
+fn extend(query: &[u8], target: &[u8], band: usize) -> Extension {
+    const NEG_INF: i32 = i32::MIN / 2;
+
+    let m = query.len();
+    let n = target.len().min(m + band);
+    let width = 2 * band + 1;
+
+    // Row i holds columns i - band ..= i + band.
+    let column = |i: usize, j: usize| (j + band).checked_sub(i).filter(|&k| k < width);
+
+    let mut h = vec![NEG_INF; (m + 1) * width];
+    let mut e = vec![NEG_INF; (m + 1) * width];
+    let mut f = vec![NEG_INF; (m + 1) * width];
+    let mut directions = vec![0u8; (m + 1) * width];
+
+    let mut best = (0, 0, 0);
+
+    for i in 0..=m {
+        let first = i.saturating_sub(band);
+        let last = (i + band).min(n);
+
+        for j in first..=last {
+            let k = i * width + column(i, j).unwrap();
+            let mut direction = 0;
+
+            if i == 0 && j == 0 {
+                h[k] = 0;
+                continue;
+            }
+
+            // A deletion, from the cell to the left.
+            if let Some(left) = j.checked_sub(1).and_then(|left| column(i, left)) {
+                let left = i * width + left;
+                let open = h[left] - GAP_OPEN - GAP_EXTEND;
+                let extend = e[left] - GAP_EXTEND;
+
+                if extend > open {
+                    e[k] = extend;
+                    direction |= DELETION_EXTENDED;
+                } else {
+                    e[k] = open;
+                }
+            }
+
+            // An insertion, from the cell above.
+            if let Some(up) = i.checked_sub(1).and_then(|up| column(up, j)) {
+                let up = (i - 1) * width + up;
+                let open = h[up] - GAP_OPEN - GAP_EXTEND;
+                let extend = f[up] - GAP_EXTEND;
+
+                if extend > open {
+                    f[k] = extend;
+                    direction |= INSERTION_EXTENDED;
+                } else {
+                    f[k] = open;
+                }
+            }
+
+            let mut score = e[k];
+            let mut from = FROM_DELETION;
+
+            if f[k] > score {
+                score = f[k];
+                from = FROM_INSERTION;
+            }
+
+            if i > 0 && j > 0 {
+                let diagonal = (i - 1) * width + column(i - 1, j - 1).unwrap();
+                let substitution = if query[i - 1] == target[j - 1] && query[i - 1] != b'N' {
+                    MATCH
+                } else {
+                    MISMATCH
+                };
+
+                if h[diagonal] + substitution >= score {
+                    score = h[diagonal] + substitution;
+                    from = FROM_DIAGONAL;
+                }
+            }
+
+            h[k] = score;
+            directions[k] = direction | from;
+
+            if score > best.0 {
+                best = (score, i, j);
+            }
+        }
+    }
+
+    let (score, query_len, target_len) = best;
+
+    // Trace back from the best cell, in the state the cell was reached in.
+    let mut kinds = Vec::new();
+    let (mut i, mut j) = (query_len, target_len);
+    let mut state = FROM_DIAGONAL;
+
+    while i > 0 || j > 0 {
+        let k = i * width + column(i, j).unwrap();
+
+        state = match state {
+            FROM_DELETION => {
+                kinds.push(Kind::Deletion);
+                j -= 1;
+
+                if directions[k] & DELETION_EXTENDED != 0 {
+                    FROM_DELETION
+                } else {
+                    FROM_DIAGONAL
+                }
+            }
+            FROM_INSERTION => {
+                kinds.push(Kind::Insertion);
+                i -= 1;
+
+                if directions[k] & INSERTION_EXTENDED != 0 {
+                    FROM_INSERTION
+                } else {
+                    FROM_DIAGONAL
+                }
+            }
+            _ => match directions[k] & 3 {
+                FROM_DIAGONAL => {
+                    kinds.push(Kind::Match);
+                    i -= 1;
+                    j -= 1;
+                    FROM_DIAGONAL
+                }
+                from => from,
+            },
+        };
+    }
+
+    let mut ops: Vec<Op> = Vec::new();
+
+    for kind in kinds.into_iter().rev() {
+        push_op(&mut ops, Op::new(kind, 1));
+    }
+
+    Extension {
+        ops,
+        query_len,
+        target_len,
+        score,
+    }
+}
+
+// Appends an op, merging it with the last one if they are the same kind.
+pub(crate) fn push_op(ops: &mut Vec<Op>, op: Op) {
+    match ops.last_mut() {
+        Some(last) if last.kind() == op.kind() => *last = Op::new(op.kind(), last.len() + op.len()),
+        _ => ops.push(op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::{check_record, ValidationReport};
+    use bstr::BString;
+    use noodles::sam::alignment::{record::Flags, record_buf::Sequence};
+    use noodles_util::alignment::io::reader::Builder;
+    use std::{fs, fs::File, io::Cursor};
+
+    const REFERENCE: &[u8] = b"ACGGTCATTGCAAGTCCATGGACTTAGCCATGATCCAGTGCATTACGGATCCAGT";
+
+    #[test]
+    fn test_extend() {
+        // A mismatch, then an unalignable tail.
+        let extension = extend(b"ACGTTCATTGCATTTTTTTT", REFERENCE, 5);
+        assert_eq!(extension.ops, [Op::new(Kind::Match, 12)]);
+        assert_eq!(extension.query_len, 12);
+        assert_eq!(extension.score, 7);
+
+        // A deletion from the query.
+        let extension = extend(b"ACGGTCATTGCGTCCATGGACTTAG", REFERENCE, 5);
+        assert_eq!(
+            extension.ops,
+            [
+                Op::new(Kind::Match, 11),
+                Op::new(Kind::Deletion, 2),
+                Op::new(Kind::Match, 14)
+            ]
+        );
+        assert_eq!(extension.target_len, 27);
+    }
+
+    #[test]
+    fn test_extend_over_origin() {
+        let reflen = REFERENCE.len();
+        let options = RescueOptions::default();
+
+        // The last 12 bases of the chromosome, then the first 12 clipped.
+        let sequence = [&REFERENCE[reflen - 12..], &REFERENCE[..12]].concat();
+        let read = RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::new(reflen - 11).unwrap())
+            .set_cigar(
+                [Op::new(Kind::Match, 12), Op::new(Kind::SoftClip, 12)]
+                    .into_iter()
+                    .collect(),
+            )
+            .set_sequence(Sequence::from(sequence.clone()))
+            .build();
+
+        let extended = extend_over_origin(&read, REFERENCE, &options).unwrap();
+        assert_eq!(extended.alignment_start(), Position::new(reflen - 11));
+        assert_eq!(extended.cigar().as_ref(), [Op::new(Kind::Match, 24)]);
+
+        // The same read aligned at the start, clipped at the other end.
+        let read = RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_alignment_start(Position::MIN)
+            .set_cigar(
+                [Op::new(Kind::SoftClip, 12), Op::new(Kind::Match, 12)]
+                    .into_iter()
+                    .collect(),
+            )
+            .set_sequence(Sequence::from(sequence))
+            .build();
+
+        let extended = extend_over_origin(&read, REFERENCE, &options).unwrap();
+        assert_eq!(extended.alignment_start(), Position::new(reflen - 11));
+        assert_eq!(extended.cigar().as_ref(), [Op::new(Kind::Match, 24)]);
+
+        // Clipped away from the ends.
+        let read = RecordBuf::builder()
+            .set_alignment_start(Position::new(20).unwrap())
+            .set_cigar(
+                [Op::new(Kind::SoftClip, 12), Op::new(Kind::Match, 12)]
+                    .into_iter()
+                    .collect(),
+            )
+            .set_sequence(Sequence::from(REFERENCE[7..31].to_vec()))
+            .build();
+        assert!(extend_over_origin(&read, REFERENCE, &options).is_none());
+    }
+
+    #[test]
+    fn test_rescue_sam_joins() -> io::Result<()> {
+        let reference = std::str::from_utf8(REFERENCE).unwrap();
+        let (end, start) = (&reference[43..], &reference[..12]);
+        let (qual, long_qual) = ("I".repeat(12), "I".repeat(24));
+        let (gapped_end, gapped_start) = (&reference[41..53], &reference[2..14]);
+        let gapped_qual = "I".repeat(26);
+
+        // r1 is joined and split. r2's primary is hard clipped, so it can't
+        // be joined, and its supplementary has to be kept. r3 is joined over
+        // two bases of the reference at each end, and two bases neither
+        // alignment has.
+        let sam = format!(
+            "@HD\tVN:1.6\n@SQ\tSN:chrM\tLN:55\n\
+             r1\t2048\tchrM\t1\t60\t12H12M\t*\t0\t0\t{start}\t{qual}\tSA:Z:chrM,44,+,12M12S,60,0;\n\
+             r2\t2048\tchrM\t1\t60\t12S12M\t*\t0\t0\t{end}{start}\t{long_qual}\tSA:Z:chrM,44,+,12M12H,60,0;\n\
+             r1\t0\tchrM\t44\t60\t12M12S\t*\t0\t0\t{end}{start}\t{long_qual}\tSA:Z:chrM,1,+,12S12M,60,0;\n\
+             r2\t0\tchrM\t44\t60\t12M12H\t*\t0\t0\t{end}\t{qual}\tSA:Z:chrM,1,+,12S12M,60,0;\n\
+             r3\t2048\tchrM\t3\t60\t14H12M\t*\t0\t0\t{gapped_start}\t{qual}\tSA:Z:chrM,42,+,12M14S,60,0;\n\
+             r3\t0\tchrM\t42\t60\t12M14S\t*\t0\t0\t{gapped_end}GG{gapped_start}\t{gapped_qual}\tSA:Z:chrM,3,+,14H12M,60,0;\n"
+        );
+        let mut reader = Builder::default().build_from_reader(Cursor::new(sam.into_bytes()))?;
+
+        let dst =
+            std::env::temp_dir().join(format!("mt_lintocirc_{}_rescue.sam", std::process::id()));
+        let mut writer: Box<dyn StdWrite> = Box::new(File::create(&dst)?);
+
+        let options = RescueOptions {
+            align_clips: false,
+            ..Default::default()
+        };
+
+        let stats = rescue_sam(
+            &mut reader,
+            &mut writer,
+            b"chrM".into(),
+            REFERENCE,
+            &options,
+        )?;
+        drop(writer);
+
+        assert_eq!(stats.outcome_count(Outcome::Joined), 2);
+        assert_eq!(stats.outcome_count(Outcome::Split), 2);
+        assert_eq!(stats.outcome_count(Outcome::Unchanged), 2);
+
+        let output = fs::read(&dst)?;
+        fs::remove_file(&dst)?;
+
+        // The pieces pass the validator: the skipped reference isn't left as
+        // a deletion at the cut.
+        let mut reader = Builder::default().build_from_reader(Cursor::new(output))?;
+        let header = reader.read_header()?;
+        let mut report = ValidationReport::default();
+        let mut records = Vec::new();
+
+        for result in reader.records(&header) {
+            let record = RecordBuf::try_from_alignment_record(&header, &result?)?;
+            let name = BString::from(record.name().unwrap().to_vec());
+            check_record(&header, &record, &name, &mut report);
+            records.push(record);
+        }
+
+        assert!(report.is_valid(), "{:?}", report.violations);
+
+        let names: Vec<&[u8]> = records
+            .iter()
+            .map(|record| record.name().unwrap().as_ref())
+            .collect();
+        assert_eq!(
+            names,
+            [&b"r2"[..], b"r1", b"r1_right", b"r2", b"r3", b"r3_right"]
+        );
+
+        let (left, right) = (&records[4], &records[5]);
+        assert_eq!(left.alignment_start(), Position::new(42));
+        assert_eq!(
+            left.cigar().as_ref(),
+            [Op::new(Kind::Match, 12), Op::new(Kind::SoftClip, 2)]
+        );
+        assert_eq!(right.alignment_start(), Position::new(3));
+        assert_eq!(right.cigar().as_ref(), [Op::new(Kind::Match, 12)]);
+
+        Ok(())
+    }
+}
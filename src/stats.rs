@@ -0,0 +1,351 @@
+//! Conversion statistics.
+//!
+//! Counts what happened to each record, so that a broken extended reference
+//! (e.g. one where nothing is ever split) shows up in the per-sample numbers.
+
+use crate::json;
+use noodles::sam::alignment::record::Flags;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+// The width of the length and overhang histogram bins.
+const BIN_WIDTH: usize = 100;
+
+/// What the conversion did with a record.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Outcome {
+    /// On the extended reference and left as is.
+    Unchanged,
+    /// On the extended reference and moved, but not split.
+    Modified,
+    /// On the extended reference and split in two.
+    Split,
+    /// On another reference, passed through.
+    OtherReference,
+    /// Not placed on any reference, passed through.
+    Unplaced,
+    /// On the extended reference, but left out of the output.
+    Filtered,
+    /// A supplementary alignment joined into its primary's pieces.
+    Joined,
+}
+
+impl Outcome {
+    /// Returns the name used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unchanged => "unchanged",
+            Self::Modified => "modified",
+            Self::Split => "split",
+            Self::OtherReference => "other_reference",
+            Self::Unplaced => "unplaced",
+            Self::Filtered => "filtered",
+            Self::Joined => "joined",
+        }
+    }
+}
+
+/// The class of a record according to its flags.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum FlagClass {
+    Primary,
+    Secondary,
+    Supplementary,
+    Unmapped,
+}
+
+impl FlagClass {
+    /// Returns the class of a record with the given flags.
+    pub fn from_flags(flags: Flags) -> Self {
+        if flags.is_unmapped() {
+            Self::Unmapped
+        } else if flags.is_secondary() {
+            Self::Secondary
+        } else if flags.is_supplementary() {
+            Self::Supplementary
+        } else {
+            Self::Primary
+        }
+    }
+
+    /// Returns the name used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Secondary => "secondary",
+            Self::Supplementary => "supplementary",
+            Self::Unmapped => "unmapped",
+        }
+    }
+}
+
+/// A histogram with fixed width bins.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bin_width: usize,
+    bins: BTreeMap<usize, u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            bin_width: BIN_WIDTH,
+            bins: BTreeMap::new(),
+        }
+    }
+}
+
+impl Histogram {
+    /// Adds a value to the histogram.
+    pub fn add(&mut self, value: usize) {
+        *self.bins.entry(value / self.bin_width).or_default() += 1;
+    }
+
+    /// Returns an iterator over the non-empty bins, as the inclusive range of
+    /// values in the bin and its count.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, u64)> + '_ {
+        self.bins.iter().map(|(&bin, &count)| {
+            let start = bin * self.bin_width;
+            (start, start + self.bin_width - 1, count)
+        })
+    }
+
+    fn to_json(&self) -> String {
+        let bins: Vec<String> = self
+            .iter()
+            .map(|(start, end, count)| {
+                format!("{{\"start\": {start}, \"end\": {end}, \"count\": {count}}}")
+            })
+            .collect();
+
+        format!("[{}]", bins.join(", "))
+    }
+}
+
+/// Statistics of a conversion run.
+#[derive(Clone, Debug)]
+pub struct ConversionStats {
+    /// The number of input records by outcome and flag class.
+    pub counts: BTreeMap<(Outcome, FlagClass), u64>,
+    /// The query lengths of the left pieces of split reads.
+    pub left_piece_lengths: Histogram,
+    /// The query lengths of the right pieces of split reads.
+    pub right_piece_lengths: Histogram,
+    /// How far alignments extend past the end of the linear reference.
+    pub overhangs: Histogram,
+    /// The number of records written.
+    pub records_out: u64,
+    /// The number of records flagged as likely from NUMTs.
+    pub numt_flagged: u64,
+    /// The number of records with indels left-aligned.
+    pub left_aligned: u64,
+    /// The time taken by the conversion.
+    pub elapsed: Duration,
+    started: Instant,
+}
+
+impl Default for ConversionStats {
+    fn default() -> Self {
+        Self {
+            counts: BTreeMap::new(),
+            left_piece_lengths: Histogram::default(),
+            right_piece_lengths: Histogram::default(),
+            overhangs: Histogram::default(),
+            records_out: 0,
+            numt_flagged: 0,
+            left_aligned: 0,
+            elapsed: Duration::ZERO,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl ConversionStats {
+    /// Counts an input record.
+    pub fn add(&mut self, outcome: Outcome, flags: Flags) {
+        *self
+            .counts
+            .entry((outcome, FlagClass::from_flags(flags)))
+            .or_default() += 1;
+
+        self.records_out += match outcome {
+            Outcome::Split => 2,
+            Outcome::Filtered | Outcome::Joined => 0,
+            _ => 1,
+        };
+    }
+
+    /// Returns the number of input records.
+    pub fn records_in(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Returns the number of input records with the given outcome.
+    pub fn outcome_count(&self, outcome: Outcome) -> u64 {
+        self.counts
+            .iter()
+            .filter(|((o, _), _)| *o == outcome)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Stops the clock.
+    pub fn finish(&mut self) {
+        self.elapsed = self.started.elapsed();
+    }
+
+    /// Returns the number of input records processed per second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+
+        if seconds > 0.0 {
+            self.records_in() as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Writes the statistics as JSON.
+    pub fn write_json<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let outcomes: Vec<String> = [
+            Outcome::Unchanged,
+            Outcome::Modified,
+            Outcome::Split,
+            Outcome::OtherReference,
+            Outcome::Unplaced,
+            Outcome::Filtered,
+            Outcome::Joined,
+        ]
+        .iter()
+        .map(|outcome| {
+            format!(
+                "{}: {}",
+                json::quote(outcome.as_str()),
+                self.outcome_count(*outcome)
+            )
+        })
+        .collect();
+
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|((outcome, flag_class), count)| {
+                format!(
+                    "{{\"outcome\": {}, \"flag_class\": {}, \"count\": {}}}",
+                    json::quote(outcome.as_str()),
+                    json::quote(flag_class.as_str()),
+                    count
+                )
+            })
+            .collect();
+
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"records_in\": {},", self.records_in())?;
+        writeln!(writer, "  \"records_out\": {},", self.records_out)?;
+        writeln!(writer, "  \"numt_flagged\": {},", self.numt_flagged)?;
+        writeln!(writer, "  \"left_aligned\": {},", self.left_aligned)?;
+        writeln!(writer, "  \"outcomes\": {{{}}},", outcomes.join(", "))?;
+        writeln!(writer, "  \"counts\": [{}],", counts.join(", "))?;
+        writeln!(
+            writer,
+            "  \"left_piece_lengths\": {},",
+            self.left_piece_lengths.to_json()
+        )?;
+        writeln!(
+            writer,
+            "  \"right_piece_lengths\": {},",
+            self.right_piece_lengths.to_json()
+        )?;
+        writeln!(writer, "  \"overhangs\": {},", self.overhangs.to_json())?;
+        writeln!(
+            writer,
+            "  \"elapsed_seconds\": {:.3},",
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(writer, "  \"records_per_second\": {:.1}", self.throughput())?;
+        writeln!(writer, "}}")
+    }
+
+    /// Writes the statistics as a TSV of `section`, `key` and `value` rows.
+    pub fn write_tsv<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "section\tkey\tvalue")?;
+        writeln!(writer, "records\tin\t{}", self.records_in())?;
+        writeln!(writer, "records\tout\t{}", self.records_out)?;
+        writeln!(writer, "records\tnumt_flagged\t{}", self.numt_flagged)?;
+        writeln!(writer, "records\tleft_aligned\t{}", self.left_aligned)?;
+
+        for ((outcome, flag_class), count) in &self.counts {
+            writeln!(
+                writer,
+                "count\t{}/{}\t{}",
+                outcome.as_str(),
+                flag_class.as_str(),
+                count
+            )?;
+        }
+
+        for (section, histogram) in [
+            ("left_piece_length", &self.left_piece_lengths),
+            ("right_piece_length", &self.right_piece_lengths),
+            ("overhang", &self.overhangs),
+        ] {
+            for (start, end, count) in histogram.iter() {
+                writeln!(writer, "{section}\t{start}-{end}\t{count}")?;
+            }
+        }
+
+        writeln!(
+            writer,
+            "time\telapsed_seconds\t{:.3}",
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(writer, "time\trecords_per_second\t{:.1}", self.throughput())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion_stats() -> io::Result<()> {
+        let mut stats = ConversionStats::default();
+        stats.add(Outcome::Split, Flags::empty());
+        stats.add(Outcome::Split, Flags::SUPPLEMENTARY);
+        stats.add(Outcome::Unchanged, Flags::SECONDARY);
+        stats.add(Outcome::Unplaced, Flags::UNMAPPED);
+        stats.left_piece_lengths.add(150);
+        stats.left_piece_lengths.add(199);
+        stats.finish();
+
+        assert_eq!(stats.records_in(), 4);
+        assert_eq!(stats.records_out, 6);
+        assert_eq!(stats.outcome_count(Outcome::Split), 2);
+        assert_eq!(
+            stats.left_piece_lengths.iter().collect::<Vec<_>>(),
+            [(100, 199, 2)]
+        );
+
+        let mut tsv = Vec::new();
+        stats.write_tsv(&mut tsv)?;
+        let tsv = String::from_utf8(tsv).unwrap();
+        assert!(tsv.contains("count\tsplit/supplementary\t1\n"));
+        assert!(tsv.contains("left_piece_length\t100-199\t2\n"));
+
+        let mut json = Vec::new();
+        stats.write_json(&mut json)?;
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"outcomes\": {\"unchanged\": 1, \"modified\": 0, \"split\": 2"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+//! Validation of converted alignment files.
+//!
+//! Checks the invariants `convert_read` is supposed to uphold, so a converted
+//! file can be checked without an external validator: the CIGAR matches SEQ,
+//! alignments stay within their reference, pieces don't start or end with a
+//! deletion, records aren't duplicated and mate fields aren't left dangling.
+//! Given the original input, the pieces of every read are also checked to add
+//! up to the original sequence.
+
+use crate::{json, RIGHT_SUFFIX};
+use bstr::{BString, ByteSlice};
+use noodles::sam::{
+    alignment::{
+        record::cigar::op::Kind,
+        record_buf::{Cigar, RecordBuf},
+        Record,
+    },
+    Header,
+};
+use noodles_util::alignment::io::Reader;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, Write},
+};
+
+/// A kind of invariant violation.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ViolationKind {
+    /// The CIGAR query length differs from the SEQ length.
+    QueryLengthMismatch,
+    /// The alignment ends past the end of its reference sequence.
+    PastReferenceEnd,
+    /// The alignment starts with a deletion or skip.
+    LeadingDeletion,
+    /// The alignment ends with a deletion or skip.
+    TrailingDeletion,
+    /// Another record has the same name and flags.
+    DuplicateRecord,
+    /// The mate fields are inconsistent with the flags or the header.
+    DanglingMate,
+    /// The pieces of a read don't add up to the original sequence.
+    SequenceMismatch,
+    /// The read isn't in the original input.
+    UnknownRead,
+}
+
+impl ViolationKind {
+    /// Returns the name of the violation used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::QueryLengthMismatch => "query_length_mismatch",
+            Self::PastReferenceEnd => "past_reference_end",
+            Self::LeadingDeletion => "leading_deletion",
+            Self::TrailingDeletion => "trailing_deletion",
+            Self::DuplicateRecord => "duplicate_record",
+            Self::DanglingMate => "dangling_mate",
+            Self::SequenceMismatch => "sequence_mismatch",
+            Self::UnknownRead => "unknown_read",
+        }
+    }
+}
+
+/// A single invariant violation.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    /// The name of the offending read.
+    pub read_name: String,
+    /// What is wrong.
+    pub kind: ViolationKind,
+    /// A human readable description.
+    pub message: String,
+}
+
+/// The result of validating a file.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// The number of records checked.
+    pub record_count: u64,
+    /// The number of violations of each kind.
+    pub counts: BTreeMap<ViolationKind, u64>,
+    /// The first violations found, up to the reporting limit.
+    pub violations: Vec<Violation>,
+    max_reported: usize,
+}
+
+impl ValidationReport {
+    /// Returns whether no violations were found.
+    pub fn is_valid(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Writes the report as JSON.
+    pub fn write_json<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"valid\": {},", self.is_valid())?;
+        writeln!(writer, "  \"records\": {},", self.record_count)?;
+
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", json::quote(kind.as_str()), count))
+            .collect();
+        writeln!(writer, "  \"counts\": {{{}}},", counts.join(", "))?;
+
+        writeln!(writer, "  \"violations\": [")?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            let separator = if i + 1 < self.violations.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(
+                writer,
+                "    {{\"read\": {}, \"kind\": {}, \"message\": {}}}{}",
+                json::quote(&violation.read_name),
+                json::quote(violation.kind.as_str()),
+                json::quote(&violation.message),
+                separator
+            )?;
+        }
+        writeln!(writer, "  ]")?;
+
+        writeln!(writer, "}}")
+    }
+
+    fn add(&mut self, read_name: &BString, kind: ViolationKind, message: String) {
+        *self.counts.entry(kind).or_default() += 1;
+
+        if self.violations.len() < self.max_reported {
+            self.violations.push(Violation {
+                read_name: read_name.to_string(),
+                kind,
+                message,
+            });
+        }
+    }
+}
+
+// The sequence of a read in the original input, by name and flags.
+type ReadKey = (BString, u16);
+
+// The sequences of the left and right pieces of a read.
+type Pieces = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+// A fingerprint of a sequence: its hash and length.
+type SequenceDigest = (u64, usize);
+
+/// Validates a converted alignment file.
+///
+/// If `original` is given, the reads of the converted file are checked to
+/// match it: the SEQ of a read's pieces, concatenated, must be the SEQ of the
+/// original read, whatever order the pieces come in. A read is checked as soon
+/// as it is seen, unless it could be one piece of a split read, a record
+/// reaching the end of its reference or a `_right` piece, which is held
+/// until its other piece turns up. Duplicates are found by a digest of each
+/// record's name and flags. At most `max_reported` violations are listed in
+/// the report, but all are counted.
+pub fn validate<R, S>(
+    reader: &mut Reader<R>,
+    original: Option<&mut Reader<S>>,
+    max_reported: usize,
+) -> io::Result<ValidationReport>
+where
+    R: BufRead,
+    S: BufRead,
+{
+    let original_sequences = original.map(read_original_sequences).transpose()?;
+
+    let header = reader.read_header()?;
+
+    let mut report = ValidationReport {
+        max_reported,
+        ..Default::default()
+    };
+
+    let mut seen: HashSet<u64> = HashSet::new();
+
+    // The sequences of the pieces of split reads waiting for their other
+    // piece, by the name of the read. On sorted output the pieces are far
+    // apart.
+    let mut pieces: BTreeMap<ReadKey, Pieces> = BTreeMap::new();
+
+    for result in reader.records(&header) {
+        let record = RecordBuf::try_from_alignment_record(&header, &result?)?;
+        report.record_count += 1;
+
+        let name = record
+            .name()
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|| BString::from("*"));
+
+        check_record(&header, &record, &name, &mut report);
+
+        let key = (name, record.flags().bits());
+
+        if !seen.insert(digest(&key)) {
+            report.add(
+                &key.0,
+                ViolationKind::DuplicateRecord,
+                format!("another record has flags {}", key.1),
+            );
+        }
+
+        let Some(original_sequences) = &original_sequences else {
+            continue;
+        };
+
+        let sequence: Vec<u8> = record.sequence().as_ref().to_vec();
+
+        let (key, is_right) = match key.0.strip_suffix(RIGHT_SUFFIX) {
+            Some(name) => ((BString::from(name), key.1), true),
+            None => (key, false),
+        };
+
+        let Some((left, right)) = pieces.get_mut(&key) else {
+            if is_right || reaches_reference_end(&header, &record) {
+                let piece = Some(sequence);
+                let waiting = if is_right {
+                    (None, piece)
+                } else {
+                    (piece, None)
+                };
+                pieces.insert(key, waiting);
+            } else {
+                check_sequence(original_sequences, &key, &sequence, &mut report);
+            }
+
+            continue;
+        };
+
+        let (piece, other) = if is_right {
+            (right, left)
+        } else {
+            (left, right)
+        };
+
+        // A duplicate is checked on its own.
+        if piece.is_some() {
+            check_sequence(original_sequences, &key, &sequence, &mut report);
+            continue;
+        }
+
+        *piece = Some(sequence);
+
+        if other.is_some() {
+            let (left, right) = pieces.remove(&key).unwrap_or_default();
+            let sequence = [left.unwrap_or_default(), right.unwrap_or_default()].concat();
+            check_sequence(original_sequences, &key, &sequence, &mut report);
+        }
+    }
+
+    if let Some(original_sequences) = &original_sequences {
+        for (key, (left, right)) in pieces {
+            let sequence = [left.unwrap_or_default(), right.unwrap_or_default()].concat();
+            check_sequence(original_sequences, &key, &sequence, &mut report);
+        }
+    }
+
+    Ok(report)
+}
+
+// Returns true if the record is aligned up to the end of its reference, or
+// past it, as the left piece of a split read is.
+fn reaches_reference_end(header: &Header, record: &RecordBuf) -> bool {
+    let reference_length = record
+        .reference_sequence_id()
+        .and_then(|id| header.reference_sequences().get_index(id))
+        .map(|(_, reference_sequence)| reference_sequence.length().get());
+
+    !record.flags().is_unmapped()
+        && record
+            .alignment_end()
+            .zip(reference_length)
+            .is_some_and(|(end, length)| end.get() >= length)
+}
+
+// Checks the invariants of a single record.
+pub(crate) fn check_record(
+    header: &Header,
+    record: &RecordBuf,
+    name: &BString,
+    report: &mut ValidationReport,
+) {
+    let cigar = record.cigar();
+
+    if !cigar.as_ref().is_empty() && !record.sequence().is_empty() {
+        let read_length = cigar.read_length();
+
+        if read_length != record.sequence().len() {
+            report.add(
+                name,
+                ViolationKind::QueryLengthMismatch,
+                format!(
+                    "CIGAR query length {} != SEQ length {}",
+                    read_length,
+                    record.sequence().len()
+                ),
+            );
+        }
+    }
+
+    if !record.flags().is_unmapped() {
+        let reference_length = record
+            .reference_sequence_id()
+            .and_then(|id| header.reference_sequences().get_index(id))
+            .map(|(_, reference_sequence)| reference_sequence.length().get());
+
+        if let (Some(start), Some(length)) = (record.alignment_start(), reference_length) {
+            let end = start.get() + cigar.alignment_span().max(1) - 1;
+
+            if end > length {
+                report.add(
+                    name,
+                    ViolationKind::PastReferenceEnd,
+                    format!(
+                        "POS {} + reference span {} is past LN {}",
+                        start,
+                        cigar.alignment_span(),
+                        length
+                    ),
+                );
+            }
+        }
+
+        if let Some(kind) = first_aligned_kind(cigar, false) {
+            report.add(
+                name,
+                ViolationKind::LeadingDeletion,
+                format!("alignment starts with {kind:?}"),
+            );
+        }
+
+        if let Some(kind) = first_aligned_kind(cigar, true) {
+            report.add(
+                name,
+                ViolationKind::TrailingDeletion,
+                format!("alignment ends with {kind:?}"),
+            );
+        }
+    }
+
+    if let Some(message) = check_mate(header, record) {
+        report.add(name, ViolationKind::DanglingMate, message);
+    }
+}
+
+// Returns the kind of the first (or last) op that isn't a clip, if it is a
+// deletion or skip.
+fn first_aligned_kind(cigar: &Cigar, from_end: bool) -> Option<Kind> {
+    let is_clip = |kind: &Kind| matches!(kind, Kind::SoftClip | Kind::HardClip | Kind::Pad);
+    let kinds = cigar.as_ref().iter().map(|op| op.kind());
+
+    let kind = if from_end {
+        kinds.rev().find(|kind| !is_clip(kind))
+    } else {
+        kinds.clone().find(|kind| !is_clip(kind))
+    };
+
+    kind.filter(|kind| matches!(kind, Kind::Deletion | Kind::Skip))
+}
+
+// Checks the mate fields agree with the flags and the header.
+fn check_mate(header: &Header, record: &RecordBuf) -> Option<String> {
+    let flags = record.flags();
+    let mate_reference_sequence_id = record.mate_reference_sequence_id();
+    let mate_alignment_start = record.mate_alignment_start();
+
+    if !flags.is_segmented() {
+        if mate_reference_sequence_id.is_some() || mate_alignment_start.is_some() {
+            return Some(String::from("mate fields set on an unpaired read"));
+        }
+
+        return None;
+    }
+
+    if flags.is_mate_unmapped() {
+        return None;
+    }
+
+    let (Some(id), Some(start)) = (mate_reference_sequence_id, mate_alignment_start) else {
+        return Some(String::from("mapped mate without RNEXT/PNEXT"));
+    };
+
+    match header.reference_sequences().get_index(id) {
+        Some((_, reference_sequence)) if start.get() > reference_sequence.length().get() => Some(
+            format!("PNEXT {} is past LN {}", start, reference_sequence.length()),
+        ),
+        Some(_) => None,
+        None => Some(format!("RNEXT {id} is not in the header")),
+    }
+}
+
+// Checks the (concatenated) sequence of a read against the original.
+fn check_sequence(
+    original_sequences: &HashMap<ReadKey, SequenceDigest>,
+    key: &ReadKey,
+    sequence: &[u8],
+    report: &mut ValidationReport,
+) {
+    match original_sequences.get(key) {
+        Some(&(hash, len)) => {
+            if len != sequence.len() || hash != digest(&sequence) {
+                report.add(
+                    &key.0,
+                    ViolationKind::SequenceMismatch,
+                    format!(
+                        "pieces have {} bases, the original has {}{}",
+                        sequence.len(),
+                        len,
+                        if len == sequence.len() {
+                            " but they differ"
+                        } else {
+                            ""
+                        }
+                    ),
+                );
+            }
+        }
+        None => report.add(
+            &key.0,
+            ViolationKind::UnknownRead,
+            format!("no read with flags {} in the original", key.1),
+        ),
+    }
+}
+
+// Reads the sequence digests of all the reads in the original input.
+fn read_original_sequences<S>(
+    reader: &mut Reader<S>,
+) -> io::Result<HashMap<ReadKey, SequenceDigest>>
+where
+    S: BufRead,
+{
+    let header = reader.read_header()?;
+    let mut sequences = HashMap::new();
+
+    for result in reader.records(&header) {
+        let record = result?;
+
+        let name = record.name().map(|name| name.as_bstr().to_owned());
+        let flags = record.flags()?.bits();
+        let sequence: Vec<u8> = record.sequence().iter().collect();
+
+        sequences.insert(
+            (name.unwrap_or_else(|| BString::from("*")), flags),
+            (digest(&sequence.as_slice()), sequence.len()),
+        );
+    }
+
+    Ok(sequences)
+}
+
+fn digest<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use noodles_util::alignment::io::reader::Builder;
+    use std::io::Cursor;
+
+    const HEADER: &str = "@HD\tVN:1.6\n@SQ\tSN:chrM\tLN:100\n";
+
+    fn build_reader(records: &str) -> io::Result<Reader<Box<dyn BufRead>>> {
+        let data = format!("{HEADER}{records}").into_bytes();
+        Builder::default().build_from_reader(Cursor::new(data))
+    }
+
+    #[test]
+    fn test_validate_consistent_split() -> io::Result<()> {
+        let mut original = build_reader("r1\t0\tchrM\t95\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\n")?;
+        let mut converted = build_reader(
+            "r1\t0\tchrM\t95\t60\t6M\t*\t0\t0\tACGTAC\t*\n\
+             r1_right\t0\tchrM\t1\t60\t4M\t*\t0\t0\tGTAC\t*\n",
+        )?;
+
+        let report = validate(&mut converted, Some(&mut original), 10)?;
+        assert!(report.is_valid(), "{:?}", report.violations);
+        assert_eq!(report.record_count, 2);
+
+        // Sorted by coordinate, the right piece comes first.
+        let mut original = build_reader("r1\t0\tchrM\t95\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\n")?;
+        let mut converted = build_reader(
+            "r1_right\t0\tchrM\t1\t60\t4M\t*\t0\t0\tGTAC\t*\n\
+             r1\t0\tchrM\t95\t60\t6M\t*\t0\t0\tACGTAC\t*\n",
+        )?;
+
+        let report = validate(&mut converted, Some(&mut original), 10)?;
+        assert!(report.is_valid(), "{:?}", report.violations);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_violations() -> io::Result<()> {
+        let mut original = build_reader(
+            "r1\t0\tchrM\t95\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\n\
+             r2\t0\tchrM\t1\t60\t4M\t*\t0\t0\tACGT\t*\n",
+        )?;
+        let mut converted = build_reader(
+            "r1\t0\tchrM\t95\t60\t8M\t*\t0\t0\tACGTAC\t*\n\
+             r1_right\t0\tchrM\t1\t60\t2D4M\t*\t0\t0\tGTAA\t*\n\
+             r2\t0\tchrM\t1\t60\t4M\tchrM\t5\t0\tACGT\t*\n\
+             r2\t0\tchrM\t1\t60\t4M\t*\t0\t0\tACGT\t*\n\
+             r3\t0\tchrM\t1\t60\t4M\t*\t0\t0\tACGT\t*\n",
+        )?;
+
+        let report = validate(&mut converted, Some(&mut original), 1)?;
+        assert!(!report.is_valid());
+        assert_eq!(report.violations.len(), 1);
+
+        let count = |kind| report.counts.get(&kind).copied().unwrap_or_default();
+        assert_eq!(count(ViolationKind::QueryLengthMismatch), 1);
+        assert_eq!(count(ViolationKind::PastReferenceEnd), 1);
+        assert_eq!(count(ViolationKind::LeadingDeletion), 1);
+        assert_eq!(count(ViolationKind::DanglingMate), 1);
+        assert_eq!(count(ViolationKind::DuplicateRecord), 1);
+        assert_eq!(count(ViolationKind::SequenceMismatch), 1);
+        assert_eq!(count(ViolationKind::UnknownRead), 1);
+
+        let mut json = Vec::new();
+        report.write_json(&mut json)?;
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains("\"past_reference_end\": 1"));
+
+        Ok(())
+    }
+}